@@ -161,7 +161,7 @@ export async function approveRevoke(): Promise<void> {
   await testToken.revoke(initialOwner, initialOwnerTokenAccount, delegate);
   delegateAccountInfo = await testToken.accountInfo(delegate);
   assert(delegateAccountInfo.amount.toNumber() == 0);
-  assert(delegateAccountInfo.originalAmount.toNumber() == 0);
+  assert(delegateAccountInfo.originalAmount.toNumber() == 456);
   if (delegateAccountInfo.source === null) {
     throw new Error('source should not be null');
   } else {
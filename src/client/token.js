@@ -67,13 +67,45 @@ type TokenInfo = {|
   decimals: number,
 |};
 
+/**
+ * Version byte that follows the tag of every serialized account and instruction
+ *
+ * @private
+ */
+const LAYOUT_VERSION = 1;
+
+/**
+ * Fields of a serialized token, shared by the token's account data and the
+ * NewToken instruction.  Integers are little-endian and each optional key is a
+ * presence byte followed by room for the key
+ *
+ * @private
+ */
+const tokenFields = [
+  Layout.uint64('supply'),
+  BufferLayout.nu64('decimals'),
+  Layout.uint64('maxTransfer'),
+  Layout.uint64('totalMinted'),
+  Layout.uint64('totalBurned'),
+  Layout.uint64('pegRatio'),
+  BufferLayout.u8('rounding'),
+  Layout.uint64('maxSupply'),
+  Layout.publicKey('mintAuthority'),
+  BufferLayout.u8('freezeAuthorityOption'),
+  Layout.publicKey('freezeAuthority'),
+  BufferLayout.u8('paused'),
+  BufferLayout.u16('transferFeeBasisPoints'),
+  Layout.publicKey('feeCollector'),
+  BufferLayout.u8('nonTransferable'),
+];
+
 /**
  * @private
  */
 const TokenInfoLayout = BufferLayout.struct([
   BufferLayout.u8('state'),
-  Layout.uint64('supply'),
-  BufferLayout.u8('decimals'),
+  BufferLayout.u8('version'),
+  ...tokenFields,
 ]);
 
 /**
@@ -109,6 +141,12 @@ type TokenAccountInfo = {|
    * If `source` is null, originalAmount is zero
    */
   originalAmount: TokenAmount,
+
+  /**
+   * Last slot in which this delegate account may spend its allowance, 0 if it
+   * never expires.  If `source` is null, expirySlot is zero
+   */
+  expirySlot: number,
 |};
 
 /**
@@ -116,12 +154,21 @@ type TokenAccountInfo = {|
  */
 const TokenAccountInfoLayout = BufferLayout.struct([
   BufferLayout.u8('state'),
+  BufferLayout.u8('version'),
   Layout.publicKey('token'),
   Layout.publicKey('owner'),
   Layout.uint64('amount'),
-  BufferLayout.nu64('sourceOption'),
+  BufferLayout.u8('sourceOption'),
   Layout.publicKey('source'),
   Layout.uint64('originalAmount'),
+  BufferLayout.nu64('expirySlot'),
+  BufferLayout.u8('confidential'),
+  BufferLayout.blob(32, 'commitment'),
+  BufferLayout.blob(4 * 32, 'whitelist'),
+  BufferLayout.u8('accountState'),
+  BufferLayout.u8('transferAuthorityOption'),
+  Layout.publicKey('transferAuthority'),
+  BufferLayout.u8('ownerImmutable'),
 ]);
 
 type TokenAndPublicKey = [Token, PublicKey]; // This type exists to workaround an esdoc parse error
@@ -207,22 +254,34 @@ export class Token {
 
     const dataLayout = BufferLayout.struct([
       BufferLayout.u8('instruction'),
-      Layout.uint64('supply'),
-      BufferLayout.nu64('decimals'),
+      BufferLayout.u8('version'),
+      ...tokenFields,
     ]);
 
-    let data = Buffer.alloc(1024);
-    {
-      const encodeLength = dataLayout.encode(
-        {
-          instruction: 0, // NewToken instruction
-          supply: supply.toBuffer(),
-          decimals,
-        },
-        data,
-      );
-      data = data.slice(0, encodeLength);
-    }
+    // The program recomputes the totals; every optional feature starts disabled
+    const data = Buffer.alloc(dataLayout.span);
+    dataLayout.encode(
+      {
+        instruction: 0, // NewToken instruction
+        version: LAYOUT_VERSION,
+        supply: supply.toBuffer(),
+        decimals,
+        maxTransfer: new TokenAmount(0).toBuffer(),
+        totalMinted: new TokenAmount(0).toBuffer(),
+        totalBurned: new TokenAmount(0).toBuffer(),
+        pegRatio: new TokenAmount(0).toBuffer(),
+        rounding: 0,
+        maxSupply: new TokenAmount(0).toBuffer(),
+        mintAuthority: Buffer.alloc(32),
+        freezeAuthorityOption: 0,
+        freezeAuthority: Buffer.alloc(32),
+        paused: 0,
+        transferFeeBasisPoints: 0,
+        feeCollector: Buffer.alloc(32),
+        nonTransferable: 0,
+      },
+      data,
+    );
 
     const balanceNeeded = await Token.getMinBalanceRentForExemptToken(
       connection,
@@ -233,7 +292,7 @@ export class Token {
       fromPubkey: owner.publicKey,
       newAccountPubkey: tokenAccount.publicKey,
       lamports: balanceNeeded,
-      space: 1 + data.length,
+      space: TokenInfoLayout.span,
       programId,
     });
     await sendAndConfirmTransaction(
@@ -280,12 +339,16 @@ export class Token {
     const tokenAccount = new Account();
     let transaction;
 
-    const dataLayout = BufferLayout.struct([BufferLayout.u8('instruction')]);
+    const dataLayout = BufferLayout.struct([
+      BufferLayout.u8('instruction'),
+      BufferLayout.u8('version'),
+    ]);
 
     const data = Buffer.alloc(dataLayout.span);
     dataLayout.encode(
       {
         instruction: 1, // NewTokenAccount instruction
+        version: LAYOUT_VERSION,
       },
       data,
     );
@@ -349,7 +412,7 @@ export class Token {
     const data = Buffer.from(accountInfo.data);
 
     const tokenInfo = TokenInfoLayout.decode(data);
-    if (tokenInfo.state !== 1) {
+    if (tokenInfo.state !== 1 || tokenInfo.version !== LAYOUT_VERSION) {
       throw new Error(`Invalid token account data`);
     }
     tokenInfo.supply = TokenAmount.fromBuffer(tokenInfo.supply);
@@ -370,7 +433,10 @@ export class Token {
     const data = Buffer.from(accountInfo.data);
     const tokenAccountInfo = TokenAccountInfoLayout.decode(data);
 
-    if (tokenAccountInfo.state !== 2) {
+    if (
+      tokenAccountInfo.state !== 2 ||
+      tokenAccountInfo.version !== LAYOUT_VERSION
+    ) {
       throw new Error(`Invalid token account data`);
     }
     tokenAccountInfo.token = new PublicKey(tokenAccountInfo.token);
@@ -379,6 +445,7 @@ export class Token {
     if (tokenAccountInfo.sourceOption === 0) {
       tokenAccountInfo.source = null;
       tokenAccountInfo.originalAmount = new TokenAmount();
+      tokenAccountInfo.expirySlot = 0;
     } else {
       tokenAccountInfo.source = new PublicKey(tokenAccountInfo.source);
       tokenAccountInfo.originalAmount = TokenAmount.fromBuffer(
@@ -456,12 +523,19 @@ export class Token {
    * @param account Public key of the token account
    * @param delegate Token account to revoke authorization from
    */
-  revoke(
+  async revoke(
     owner: Account,
     account: PublicKey,
     delegate: PublicKey,
   ): Promise<void> {
-    return this.approve(owner, account, delegate, 0);
+    await sendAndConfirmTransaction(
+      'revoke',
+      this.connection,
+      new Transaction().add(
+        this.revokeInstruction(owner.publicKey, account, delegate),
+      ),
+      owner,
+    );
   }
 
   /**
@@ -507,6 +581,7 @@ export class Token {
 
     const dataLayout = BufferLayout.struct([
       BufferLayout.u8('instruction'),
+      BufferLayout.u8('version'),
       Layout.uint64('amount'),
     ]);
 
@@ -514,6 +589,7 @@ export class Token {
     dataLayout.encode(
       {
         instruction: 2, // Transfer instruction
+        version: LAYOUT_VERSION,
         amount: new TokenAmount(amount).toBuffer(),
       },
       data,
@@ -523,6 +599,7 @@ export class Token {
       {pubkey: owner, isSigner: true, isWritable: false},
       {pubkey: source, isSigner: false, isWritable: true},
       {pubkey: destination, isSigner: false, isWritable: true},
+      {pubkey: this.token, isSigner: false, isWritable: false},
     ];
     if (accountInfo.source) {
       keys.push({
@@ -554,6 +631,7 @@ export class Token {
   ): TransactionInstruction {
     const dataLayout = BufferLayout.struct([
       BufferLayout.u8('instruction'),
+      BufferLayout.u8('version'),
       Layout.uint64('amount'),
     ]);
 
@@ -561,6 +639,7 @@ export class Token {
     dataLayout.encode(
       {
         instruction: 3, // Approve instruction
+        version: LAYOUT_VERSION,
         amount: new TokenAmount(amount).toBuffer(),
       },
       data,
@@ -571,6 +650,7 @@ export class Token {
         {pubkey: owner, isSigner: true, isWritable: false},
         {pubkey: account, isSigner: false, isWritable: true},
         {pubkey: delegate, isSigner: false, isWritable: true},
+        {pubkey: this.token, isSigner: false, isWritable: false},
       ],
       programId: this.programId,
       data,
@@ -589,7 +669,29 @@ export class Token {
     account: PublicKey,
     delegate: PublicKey,
   ): TransactionInstruction {
-    return this.approveInstruction(owner, account, delegate, 0);
+    const dataLayout = BufferLayout.struct([
+      BufferLayout.u8('instruction'),
+      BufferLayout.u8('version'),
+    ]);
+
+    const data = Buffer.alloc(dataLayout.span);
+    dataLayout.encode(
+      {
+        instruction: 32, // Revoke instruction
+        version: LAYOUT_VERSION,
+      },
+      data,
+    );
+
+    return new TransactionInstruction({
+      keys: [
+        {pubkey: owner, isSigner: true, isWritable: false},
+        {pubkey: account, isSigner: false, isWritable: false},
+        {pubkey: delegate, isSigner: false, isWritable: true},
+      ],
+      programId: this.programId,
+      data,
+    });
   }
 
   /**
@@ -604,12 +706,16 @@ export class Token {
     account: PublicKey,
     newOwner: PublicKey,
   ): TransactionInstruction {
-    const dataLayout = BufferLayout.struct([BufferLayout.u8('instruction')]);
+    const dataLayout = BufferLayout.struct([
+      BufferLayout.u8('instruction'),
+      BufferLayout.u8('version'),
+    ]);
 
     const data = Buffer.alloc(dataLayout.span);
     dataLayout.encode(
       {
         instruction: 4, // SetOwner instruction
+        version: LAYOUT_VERSION,
       },
       data,
     );
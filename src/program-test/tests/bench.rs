@@ -1,9 +1,16 @@
 use byteorder::{LittleEndian, WriteBytesExt};
 use solana_bpf_loader_program::{create_vm, deserialize_parameters, serialize_parameters};
-use solana_bpf_token::state::{Command, State, Token, TokenAccount};
+use solana_bpf_token::{
+    client::{
+        APPROVE_COMPUTE_UNITS, NEW_TOKEN_ACCOUNT_COMPUTE_UNITS, NEW_TOKEN_COMPUTE_UNITS,
+        TRANSFER_COMPUTE_UNITS,
+    },
+    state::{Command, State, Token, TokenAccount},
+};
 use solana_sdk::{
     account::{Account, KeyedAccount},
     instruction::InstructionError,
+    program_error::ProgramError,
     pubkey::Pubkey,
 };
 use std::{cell::RefCell, fs::File, io::Read, mem::size_of, path::PathBuf};
@@ -12,23 +19,38 @@ use std::{cell::RefCell, fs::File, io::Read, mem::size_of, path::PathBuf};
 pub fn serde() {
     assert_eq!(State::deserialize(&[0]), Ok(State::default()));
 
-    let mut data = vec![0; 256];
+    let mut data = vec![0; size_of::<State>()];
 
     let account = State::Account(TokenAccount {
         token: Pubkey::new(&[1; 32]),
         owner: Pubkey::new(&[2; 32]),
         amount: 123,
         delegate: None,
+        ..TokenAccount::default()
     });
     account.serialize(&mut data).unwrap();
     assert_eq!(State::deserialize(&data), Ok(account));
 
+    let mut data = vec![0; size_of::<State>()];
+
     let account = State::Token(Token {
         supply: 12345,
         decimals: 2,
+        ..Token::default()
     });
     account.serialize(&mut data).unwrap();
     assert_eq!(State::deserialize(&data), Ok(account));
+
+    let command = Command::Burn(123);
+    let data = command.serialize_to_vec().unwrap();
+    assert_eq!(Command::deserialize(&data), Ok(command));
+
+    let mut data = vec![0; size_of::<Command>()];
+    command.serialize(&mut data).unwrap();
+    assert_eq!(
+        Command::deserialize(&data),
+        Err(ProgramError::InvalidInstructionData)
+    );
 }
 
 fn load_program(name: &str) -> Vec<u8> {
@@ -77,7 +99,6 @@ fn bench() {
     solana_logger::setup();
 
     let program_id = pubkey_new_rand!();
-    let mut instruction_data = vec![0u8; size_of::<Command>()];
     let mint_key = pubkey_new_rand!();
     let mut mint_account = Account::new_ref(0, size_of::<State>(), &program_id);
     let owner_key = pubkey_new_rand!();
@@ -87,7 +108,7 @@ fn bench() {
 
     // Create mint account
     let instruction = Command::NewTokenAccount;
-    instruction.serialize(&mut instruction_data).unwrap();
+    let instruction_data = instruction.serialize_to_vec().unwrap();
     let parameter_accounts = vec![
         KeyedAccount::new(&mint_key, true, &mut mint_account),
         KeyedAccount::new(&owner_key, false, &mut owner_account),
@@ -99,7 +120,7 @@ fn bench() {
 
     // Create new account
     let instruction = Command::NewTokenAccount;
-    instruction.serialize(&mut instruction_data).unwrap();
+    let instruction_data = instruction.serialize_to_vec().unwrap();
     let payee_key = pubkey_new_rand!();
     let mut payee_account = Account::new_ref(0, size_of::<State>(), &program_id);
     let parameter_accounts = vec![
@@ -114,8 +135,9 @@ fn bench() {
     let instruction = Command::NewToken(Token {
         supply: 1000,
         decimals: 2,
+        ..Token::default()
     });
-    instruction.serialize(&mut instruction_data).unwrap();
+    let instruction_data = instruction.serialize_to_vec().unwrap();
     let parameter_accounts = vec![
         KeyedAccount::new(&token_key, true, &mut token_account),
         KeyedAccount::new(&mint_key, false, &mut mint_account),
@@ -126,19 +148,47 @@ fn bench() {
 
     // Transfer
     let instruction = Command::Transfer(100);
-    instruction.serialize(&mut instruction_data).unwrap();
+    let instruction_data = instruction.serialize_to_vec().unwrap();
     let parameter_accounts = vec![
         KeyedAccount::new(&owner_key, true, &mut owner_account),
         KeyedAccount::new(&mint_key, false, &mut mint_account),
         KeyedAccount::new(&payee_key, false, &mut payee_account),
+        KeyedAccount::new(&token_key, false, &mut token_account),
     ];
     let (result, transfer_count) =
         run_program(&program_id, &parameter_accounts[..], &instruction_data).unwrap();
     assert!(result == 0);
 
-    const BASELINE_NEWTOKENACCOUNT_COUNT: u64 = 1500; // last known 1317
-    const BASELINE_NEWTOKEN_COUNT: u64 = 1500; // last known 1340
-    const BASELINE_TRANSFER_COUNT: u64 = 2500; // last known 2161
+    // Create delegate account
+    let instruction = Command::NewTokenAccount;
+    let instruction_data = instruction.serialize_to_vec().unwrap();
+    let delegate_key = pubkey_new_rand!();
+    let mut delegate_account = Account::new_ref(0, size_of::<State>(), &program_id);
+    let parameter_accounts = vec![
+        KeyedAccount::new(&delegate_key, true, &mut delegate_account),
+        KeyedAccount::new(&owner_key, false, &mut owner_account),
+        KeyedAccount::new(&token_key, false, &mut token_account),
+        KeyedAccount::new(&mint_key, false, &mut mint_account),
+    ];
+    let (result, _) = run_program(&program_id, &parameter_accounts[..], &instruction_data).unwrap();
+    assert!(result == 0);
+
+    // Approve
+    let instruction = Command::Approve(100);
+    let instruction_data = instruction.serialize_to_vec().unwrap();
+    let parameter_accounts = vec![
+        KeyedAccount::new(&owner_key, true, &mut owner_account),
+        KeyedAccount::new(&mint_key, false, &mut mint_account),
+        KeyedAccount::new(&delegate_key, false, &mut delegate_account),
+    ];
+    let (result, approve_count) =
+        run_program(&program_id, &parameter_accounts[..], &instruction_data).unwrap();
+    assert!(result == 0);
+
+    const BASELINE_NEWTOKENACCOUNT_COUNT: u64 = NEW_TOKEN_ACCOUNT_COMPUTE_UNITS as u64;
+    const BASELINE_NEWTOKEN_COUNT: u64 = NEW_TOKEN_COMPUTE_UNITS as u64;
+    const BASELINE_TRANSFER_COUNT: u64 = TRANSFER_COMPUTE_UNITS as u64;
+    const BASELINE_APPROVE_COUNT: u64 = APPROVE_COMPUTE_UNITS as u64;
 
     println!("BPF instructions executed");
     println!(
@@ -153,8 +203,13 @@ fn bench() {
         "  Transfer       : {:?} ({:?})",
         transfer_count, BASELINE_TRANSFER_COUNT
     );
+    println!(
+        "  Approve        : {:?} ({:?})",
+        approve_count, BASELINE_APPROVE_COUNT
+    );
 
     assert!(newtokenaccount_count <= BASELINE_NEWTOKENACCOUNT_COUNT);
     assert!(newtoken_count <= BASELINE_NEWTOKEN_COUNT);
     assert!(transfer_count <= BASELINE_TRANSFER_COUNT);
+    assert!(approve_count <= BASELINE_APPROVE_COUNT);
 }
@@ -0,0 +1,135 @@
+use crate::{error::TokenError, state::Token};
+use solana_sdk::program_error::ProgramError;
+
+/// Formats a raw `amount` as a decimal string with `decimals` fractional digits, dropping
+/// trailing zeros and the point if nothing remains after it, e.g. 1200 at 2 decimals is "12"
+pub fn amount_to_ui_string(amount: u64, decimals: u64) -> String {
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let digits = format!("{:0>width$}", amount, width = decimals + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        integer.to_string()
+    } else {
+        format!("{}.{}", integer, fraction)
+    }
+}
+
+/// Parses a decimal string into a raw amount with `decimals` fractional digits, the inverse of
+/// `amount_to_ui_string`.  Fails with `InvalidArgument` for malformed input, more significant
+/// fractional digits than `decimals` or `decimals` above `Token::MAX_DECIMALS`, and with
+/// `Overflow` if the amount does not fit a u64
+pub fn ui_string_to_amount(ui: &str, decimals: u64) -> Result<u64, ProgramError> {
+    if decimals > Token::MAX_DECIMALS {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (integer, fraction) = match ui.find('.') {
+        Some(point) => (&ui[..point], &ui[point + 1..]),
+        None => (ui, ""),
+    };
+    if integer.is_empty() && fraction.is_empty()
+        || !integer.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() as u64 > decimals {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let scale = 10u64.pow(decimals as u32);
+    let fraction_scale = 10u64.pow((decimals - fraction.len() as u64) as u32);
+    let parse = |digits: &str| -> Result<u64, ProgramError> {
+        digits.bytes().try_fold(0u64, |value, b| {
+            value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add(u64::from(b - b'0')))
+                .ok_or_else(|| TokenError::Overflow.into())
+        })
+    };
+    // At most `decimals` digits, so the scaled fraction stays below `scale`
+    let fraction = parse(fraction)? * fraction_scale;
+    parse(integer)?
+        .checked_mul(scale)
+        .and_then(|value| value.checked_add(fraction))
+        .ok_or_else(|| TokenError::Overflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_to_ui_string() {
+        assert_eq!(amount_to_ui_string(1234, 2), "12.34");
+        assert_eq!(amount_to_ui_string(1200, 2), "12");
+        assert_eq!(amount_to_ui_string(1230, 2), "12.3");
+        assert_eq!(amount_to_ui_string(5, 3), "0.005");
+        assert_eq!(amount_to_ui_string(0, 2), "0");
+        assert_eq!(amount_to_ui_string(1234, 0), "1234");
+        // The strings below are written for 18 decimals, not whatever the cap is
+        assert_eq!(amount_to_ui_string(u64::MAX, 18), "18.446744073709551615");
+        assert_eq!(amount_to_ui_string(u64::MAX, 19), "1.8446744073709551615");
+    }
+
+    #[test]
+    fn test_ui_string_to_amount() {
+        assert_eq!(ui_string_to_amount("12.34", 2), Ok(1234));
+        assert_eq!(ui_string_to_amount("12", 2), Ok(1200));
+        assert_eq!(ui_string_to_amount("12.", 2), Ok(1200));
+        assert_eq!(ui_string_to_amount(".5", 2), Ok(50));
+        assert_eq!(ui_string_to_amount("12.3400", 2), Ok(1234));
+        assert_eq!(ui_string_to_amount("1234", 0), Ok(1234));
+
+        // over-precise or malformed
+        assert_eq!(
+            ui_string_to_amount("12.345", 2),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            ui_string_to_amount("1.5", 0),
+            Err(ProgramError::InvalidArgument)
+        );
+        for ui in ["", ".", "1.2.3", "-1", "+1", "1,5", " 1"].iter() {
+            assert_eq!(
+                ui_string_to_amount(ui, 2),
+                Err(ProgramError::InvalidArgument)
+            );
+        }
+        assert_eq!(
+            ui_string_to_amount("1", Token::MAX_DECIMALS + 1),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // beyond u64
+        assert_eq!(
+            ui_string_to_amount("18.446744073709551616", 18),
+            Err(TokenError::Overflow.into())
+        );
+        assert_eq!(
+            ui_string_to_amount("18446744073709551616", 0),
+            Err(TokenError::Overflow.into())
+        );
+    }
+
+    #[test]
+    fn test_ui_round_trip() {
+        for &(amount, decimals) in [
+            (1234, 2),
+            (1200, 2),
+            (0, 5),
+            (1, Token::MAX_DECIMALS),
+            (u64::MAX, 0),
+            (u64::MAX, Token::MAX_DECIMALS),
+        ]
+        .iter()
+        {
+            let ui = amount_to_ui_string(amount, decimals);
+            assert_eq!(ui_string_to_amount(&ui, decimals), Ok(amount));
+        }
+    }
+}
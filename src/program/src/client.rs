@@ -0,0 +1,639 @@
+use crate::{
+    error::TokenError,
+    state::{Command, State, Token},
+};
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+};
+use std::{convert::TryFrom, fmt::Debug};
+
+/// Upper bound on BPF instructions executed by `NewTokenAccount`, also the bench baseline
+pub const NEW_TOKEN_ACCOUNT_COMPUTE_UNITS: u32 = 1500; // last known 1317
+/// Upper bound on BPF instructions executed by `NewToken`, also the bench baseline
+pub const NEW_TOKEN_COMPUTE_UNITS: u32 = 1500; // last known 1340
+/// Upper bound on BPF instructions executed by `Transfer`, also the bench baseline
+pub const TRANSFER_COMPUTE_UNITS: u32 = 2500; // last known 2161
+/// Upper bound on BPF instructions executed by `Approve`, also the bench baseline
+pub const APPROVE_COMPUTE_UNITS: u32 = 2000;
+
+impl Command {
+    /// Conservative compute budget for this command when its instruction passes `num_accounts`
+    /// accounts
+    ///
+    /// Benched commands use their baseline, the others are estimated from the benched command
+    /// they most resemble, multiplied by how many times they repeat its work.  Commands that
+    /// visit each of a variable number of accounts, such as `Distribute`, charge a `Transfer`
+    /// for each account past their fixed keys, and at least one.  Commands without a benched
+    /// counterpart are charged a `Transfer`
+    pub fn estimated_compute_units(&self, num_accounts: usize) -> u32 {
+        let repeated = |fixed_accounts: usize| {
+            let count = num_accounts.saturating_sub(fixed_accounts).max(1);
+            TRANSFER_COMPUTE_UNITS.saturating_mul(u32::try_from(count).unwrap_or(u32::MAX))
+        };
+        match self {
+            Command::NewTokenAccount
+            | Command::NewTokenAccountIdempotent
+            | Command::NewAssociatedTokenAccount
+            | Command::NewTokenAccountWithImmutableOwner => NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            Command::NewToken(_) | Command::InitMint { .. } => NEW_TOKEN_COMPUTE_UNITS,
+            Command::Transfer(_)
+            | Command::TransferChecked { .. }
+            | Command::TransferWithMemo { .. } => TRANSFER_COMPUTE_UNITS,
+            Command::Approve(_)
+            | Command::ApproveIfUnused(_)
+            | Command::ApproveChecked { .. }
+            | Command::ApproveWithExpiry { .. } => APPROVE_COMPUTE_UNITS,
+            Command::NewTokenDistributed(_, amounts) => {
+                NEW_TOKEN_COMPUTE_UNITS.saturating_mul(amounts.len().max(1) as u32)
+            }
+            Command::NewAccountAndApprove { .. } | Command::MintToNew(_) => {
+                2 * NEW_TOKEN_ACCOUNT_COMPUTE_UNITS
+            }
+            Command::Swap { .. } => 2 * TRANSFER_COMPUTE_UNITS,
+            Command::ApproveAndTransfer { .. } => APPROVE_COMPUTE_UNITS + TRANSFER_COMPUTE_UNITS,
+            Command::BatchTransfer(amounts) => {
+                TRANSFER_COMPUTE_UNITS.saturating_mul(amounts.len().max(1) as u32)
+            }
+            Command::BatchBalance => repeated(1),
+            Command::Distribute(_) => repeated(3),
+            Command::RotateOwner | Command::FreezeOwner(_) => {
+                TRANSFER_COMPUTE_UNITS.saturating_add(repeated(3))
+            }
+            // The invariant accounts start after key 3 or, with a delegate, key 4
+            Command::TransferWithInvariant { .. } => {
+                TRANSFER_COMPUTE_UNITS.saturating_add(repeated(4))
+            }
+            Command::SetOwner
+            | Command::AssertAccountOwner
+            | Command::TransferIfBelow { .. }
+            | Command::MoveAccount
+            | Command::TransferAndCloseIfEmpty { .. }
+            | Command::CloseAndTransfer
+            | Command::CheckTransfer { .. }
+            | Command::CheckPeg
+            | Command::SetConfidential { .. }
+            | Command::SetWhitelist
+            | Command::NewNonce(_)
+            | Command::ConsumeNonce(_)
+            | Command::TransferIfPrice { .. }
+            | Command::TransferIfHolds { .. }
+            | Command::ProgramInfo
+            | Command::GetAccount
+            | Command::GetAccountInfo
+            | Command::GetAuthorities
+            | Command::SyncMirror
+            | Command::Burn(_)
+            | Command::MintTo(_)
+            | Command::CloseAccount
+            | Command::Revoke
+            | Command::NewMultisig(_)
+            | Command::Freeze
+            | Command::Thaw
+            | Command::SetTransferAuthority
+            | Command::SetPause(_) => TRANSFER_COMPUTE_UNITS,
+        }
+    }
+
+    /// Decodes instruction data pasted as hex, e.g. from an explorer.  Surrounding whitespace
+    /// is ignored and either case is accepted.  Malformed hex fails with `InvalidInstruction`,
+    /// like instruction data that doesn't decode
+    pub fn from_hex(s: &str) -> Result<Command, ProgramError> {
+        let input = s
+            .trim()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .filter(|pair| pair.len() == 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or(TokenError::InvalidInstruction)
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+        Command::deserialize(&input)
+    }
+
+    /// Encodes the command's instruction data as lowercase hex, the inverse of `from_hex`
+    pub fn to_hex(&self) -> String {
+        self.serialize_to_vec()
+            .expect("buffer sized by serialized_len")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl State {
+    /// Describes the fields that differ between two serialized states, one entry per field.
+    /// Returns an empty list if they are equal
+    pub fn diff(before: &[u8], after: &[u8]) -> Vec<String> {
+        let mut changes = vec![];
+        match (State::deserialize(before), State::deserialize(after)) {
+            (Ok(State::Token(before)), Ok(State::Token(after))) => {
+                push_change(&mut changes, "supply", &before.supply, &after.supply);
+                push_change(&mut changes, "decimals", &before.decimals, &after.decimals);
+                push_change(
+                    &mut changes,
+                    "max_transfer",
+                    &before.max_transfer,
+                    &after.max_transfer,
+                );
+                push_change(
+                    &mut changes,
+                    "total_minted",
+                    &before.total_minted,
+                    &after.total_minted,
+                );
+                push_change(
+                    &mut changes,
+                    "total_burned",
+                    &before.total_burned,
+                    &after.total_burned,
+                );
+                push_change(
+                    &mut changes,
+                    "peg_ratio",
+                    &before.peg_ratio,
+                    &after.peg_ratio,
+                );
+                push_change(&mut changes, "rounding", &before.rounding, &after.rounding);
+                push_change(
+                    &mut changes,
+                    "max_supply",
+                    &before.max_supply,
+                    &after.max_supply,
+                );
+                push_change(
+                    &mut changes,
+                    "mint_authority",
+                    &before.mint_authority,
+                    &after.mint_authority,
+                );
+                push_change(
+                    &mut changes,
+                    "freeze_authority",
+                    &before.freeze_authority,
+                    &after.freeze_authority,
+                );
+                push_change(&mut changes, "paused", &before.paused, &after.paused);
+                push_change(
+                    &mut changes,
+                    "transfer_fee_basis_points",
+                    &before.transfer_fee_basis_points,
+                    &after.transfer_fee_basis_points,
+                );
+                push_change(
+                    &mut changes,
+                    "fee_collector",
+                    &before.fee_collector,
+                    &after.fee_collector,
+                );
+                push_change(
+                    &mut changes,
+                    "non_transferable",
+                    &before.non_transferable,
+                    &after.non_transferable,
+                );
+            }
+            (Ok(State::Account(before)), Ok(State::Account(after))) => {
+                push_change(&mut changes, "token", &before.token, &after.token);
+                push_change(&mut changes, "owner", &before.owner, &after.owner);
+                push_change(&mut changes, "amount", &before.amount, &after.amount);
+                push_change(&mut changes, "delegate", &before.delegate, &after.delegate);
+                push_change(
+                    &mut changes,
+                    "confidential",
+                    &before.confidential,
+                    &after.confidential,
+                );
+                push_change(
+                    &mut changes,
+                    "commitment",
+                    &before.commitment,
+                    &after.commitment,
+                );
+                push_change(
+                    &mut changes,
+                    "whitelist",
+                    &before.whitelist,
+                    &after.whitelist,
+                );
+                push_change(&mut changes, "state", &before.state, &after.state);
+                push_change(
+                    &mut changes,
+                    "transfer_authority",
+                    &before.transfer_authority,
+                    &after.transfer_authority,
+                );
+                push_change(
+                    &mut changes,
+                    "owner_immutable",
+                    &before.owner_immutable,
+                    &after.owner_immutable,
+                );
+            }
+            (before, after) => push_change(&mut changes, "state", &before, &after),
+        }
+        changes
+    }
+}
+
+fn push_change<T: PartialEq + Debug>(
+    changes: &mut Vec<String>,
+    field: &str,
+    before: &T,
+    after: &T,
+) {
+    if before != after {
+        changes.push(format!("{}: {:?} -> {:?}", field, before, after));
+    }
+}
+
+/// Builds the ordered instructions that allocate a token and a token account, initialize the
+/// account, and create the token with its supply credited to that account
+///
+/// The token and account keys must be fresh keypairs that sign the transaction along with the
+/// payer.  `lamports` is funded into each of the two new accounts.
+pub fn setup_new_token(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    token_key: &Pubkey,
+    account_key: &Pubkey,
+    owner: &Pubkey,
+    lamports: u64,
+    token: Token,
+) -> Result<Vec<Instruction>, ProgramError> {
+    let new_account_data = Command::NewTokenAccount.serialize_to_vec()?;
+    let new_token_data = Command::NewToken(token).serialize_to_vec()?;
+
+    Ok(vec![
+        system_instruction::create_account(
+            payer,
+            token_key,
+            lamports,
+            State::TOKEN_LEN as u64,
+            program_id,
+        ),
+        system_instruction::create_account(
+            payer,
+            account_key,
+            lamports,
+            State::ACCOUNT_LEN as u64,
+            program_id,
+        ),
+        Instruction {
+            program_id: *program_id,
+            accounts: vec![
+                AccountMeta::new(*account_key, true),
+                AccountMeta::new_readonly(*owner, false),
+                AccountMeta::new_readonly(*token_key, false),
+            ],
+            data: new_account_data,
+        },
+        Instruction {
+            program_id: *program_id,
+            accounts: vec![
+                AccountMeta::new(*token_key, true),
+                AccountMeta::new(*account_key, false),
+            ],
+            data: new_token_data,
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TokenAccount;
+    use solana_sdk::system_program;
+    use std::mem::size_of;
+
+    #[test]
+    fn test_diff() {
+        let account = TokenAccount {
+            token: Pubkey::new(&[1; 32]),
+            owner: Pubkey::new(&[2; 32]),
+            amount: 100,
+            delegate: None,
+            ..TokenAccount::default()
+        };
+        let mut before = vec![0u8; size_of::<State>()];
+        State::Account(account).serialize(&mut before).unwrap();
+
+        // identical accounts
+        assert!(State::diff(&before, &before).is_empty());
+
+        // amount only
+        let mut after = vec![0u8; size_of::<State>()];
+        State::Account(TokenAccount {
+            amount: 40,
+            ..account
+        })
+        .serialize(&mut after)
+        .unwrap();
+        assert_eq!(
+            State::diff(&before, &after),
+            vec!["amount: 100 -> 40".to_string()]
+        );
+
+        // different kinds of state
+        let unallocated = vec![0u8; size_of::<State>()];
+        let changes = State::diff(&before, &unallocated);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with("state: Ok(Account("));
+        assert!(changes[0].ends_with("-> Ok(Unallocated)"));
+    }
+
+    #[test]
+    fn test_hex() {
+        let command = Command::Transfer(100);
+        let hex = command.to_hex();
+        assert_eq!(hex, "02016400000000000000");
+        assert_eq!(Ok(command.clone()), Command::from_hex(&hex));
+        assert_eq!(Ok(command), Command::from_hex(" 02016400000000000000\n"));
+        assert_eq!(
+            Ok(Command::Transfer(0xab)),
+            Command::from_hex("0201AB00000000000000")
+        );
+
+        // malformed hex, then well-formed hex that isn't a command
+        for hex in ["030", "0x0301", "03 01", "zz", "03é1"].iter() {
+            assert_eq!(
+                Err(TokenError::InvalidInstruction.into()),
+                Command::from_hex(hex)
+            );
+        }
+        assert_eq!(
+            Err(TokenError::InvalidInstruction.into()),
+            Command::from_hex("ff01")
+        );
+    }
+
+    #[test]
+    fn test_estimated_compute_units() {
+        let cases = vec![
+            (Command::NewTokenAccount, 3, NEW_TOKEN_ACCOUNT_COMPUTE_UNITS),
+            (
+                Command::NewTokenAccountIdempotent,
+                3,
+                NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewAssociatedTokenAccount,
+                3,
+                NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewTokenAccountWithImmutableOwner,
+                3,
+                NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewToken(Token::default()),
+                2,
+                NEW_TOKEN_COMPUTE_UNITS,
+            ),
+            (
+                Command::InitMint { decimals: 2 },
+                1,
+                NEW_TOKEN_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewTokenDistributed(Token::default(), vec![1, 2, 3]),
+                4,
+                3 * NEW_TOKEN_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewTokenDistributed(Token::default(), vec![]),
+                1,
+                NEW_TOKEN_COMPUTE_UNITS,
+            ),
+            (
+                Command::NewAccountAndApprove { allowance: 1 },
+                5,
+                2 * NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            ),
+            (
+                Command::MintToNew(1),
+                4,
+                2 * NEW_TOKEN_ACCOUNT_COMPUTE_UNITS,
+            ),
+            (Command::Transfer(1), 4, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::TransferChecked {
+                    amount: 1,
+                    decimals: 2,
+                },
+                4,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (
+                Command::TransferWithMemo {
+                    amount: 1,
+                    memo: [0; 32],
+                },
+                4,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::Approve(1), 4, APPROVE_COMPUTE_UNITS),
+            (Command::ApproveIfUnused(1), 4, APPROVE_COMPUTE_UNITS),
+            (
+                Command::ApproveChecked {
+                    amount: 1,
+                    decimals: 2,
+                },
+                4,
+                APPROVE_COMPUTE_UNITS,
+            ),
+            (
+                Command::ApproveWithExpiry {
+                    amount: 1,
+                    expiry_slot: 1,
+                },
+                4,
+                APPROVE_COMPUTE_UNITS,
+            ),
+            (
+                Command::Swap {
+                    amount_a: 1,
+                    amount_b: 1,
+                },
+                7,
+                2 * TRANSFER_COMPUTE_UNITS,
+            ),
+            (
+                Command::ApproveAndTransfer {
+                    approve: 1,
+                    transfer: 1,
+                },
+                5,
+                APPROVE_COMPUTE_UNITS + TRANSFER_COMPUTE_UNITS,
+            ),
+            (
+                Command::BatchTransfer(vec![1, 2]),
+                6,
+                2 * TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::BatchTransfer(vec![]), 3, TRANSFER_COMPUTE_UNITS),
+            // one Transfer per account past the fixed keys, and at least one
+            (Command::BatchBalance, 1, TRANSFER_COMPUTE_UNITS),
+            (Command::BatchBalance, 11, 10 * TRANSFER_COMPUTE_UNITS),
+            (Command::Distribute(1), 3, TRANSFER_COMPUTE_UNITS),
+            (Command::Distribute(1), 8, 5 * TRANSFER_COMPUTE_UNITS),
+            (Command::RotateOwner, 3, 2 * TRANSFER_COMPUTE_UNITS),
+            (Command::RotateOwner, 6, 4 * TRANSFER_COMPUTE_UNITS),
+            (
+                Command::FreezeOwner(Pubkey::new(&[1; 32])),
+                6,
+                4 * TRANSFER_COMPUTE_UNITS,
+            ),
+            (
+                Command::TransferWithInvariant {
+                    amount: 1,
+                    expected_total: 1,
+                },
+                6,
+                3 * TRANSFER_COMPUTE_UNITS,
+            ),
+            // without a benched counterpart, or a fixed number of accounts
+            (Command::SetOwner, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::AssertAccountOwner, 2, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::TransferIfBelow { target: 1 },
+                4,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::MoveAccount, 3, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::TransferAndCloseIfEmpty { amount: 1 },
+                5,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::CloseAndTransfer, 5, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::CheckTransfer { amount: 1 },
+                4,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::CheckPeg, 4, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::SetConfidential {
+                    commitment: [0; 32],
+                },
+                2,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::SetWhitelist, 6, TRANSFER_COMPUTE_UNITS),
+            (Command::NewNonce(1), 1, TRANSFER_COMPUTE_UNITS),
+            (Command::ConsumeNonce(1), 1, TRANSFER_COMPUTE_UNITS),
+            (
+                Command::TransferIfPrice {
+                    amount: 1,
+                    min_price: 1,
+                },
+                5,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (
+                Command::TransferIfHolds {
+                    amount: 1,
+                    min_holding: 1,
+                },
+                4,
+                TRANSFER_COMPUTE_UNITS,
+            ),
+            (Command::ProgramInfo, 1, TRANSFER_COMPUTE_UNITS),
+            (Command::GetAccount, 2, TRANSFER_COMPUTE_UNITS),
+            (Command::GetAccountInfo, 1, TRANSFER_COMPUTE_UNITS),
+            (Command::GetAuthorities, 2, TRANSFER_COMPUTE_UNITS),
+            (Command::SyncMirror, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::Burn(1), 3, TRANSFER_COMPUTE_UNITS),
+            (Command::MintTo(1), 2, TRANSFER_COMPUTE_UNITS),
+            (Command::CloseAccount, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::Revoke, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::NewMultisig(2), 3, TRANSFER_COMPUTE_UNITS),
+            (Command::Freeze, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::Thaw, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::SetTransferAuthority, 3, TRANSFER_COMPUTE_UNITS),
+            (Command::SetPause(true), 2, TRANSFER_COMPUTE_UNITS),
+        ];
+        for (command, num_accounts, expected) in cases.iter() {
+            assert_eq!(
+                command.estimated_compute_units(*num_accounts),
+                *expected,
+                "{:?}",
+                command
+            );
+        }
+
+        // a huge account list saturates instead of overflowing
+        assert_eq!(
+            Command::BatchBalance.estimated_compute_units(usize::MAX),
+            u32::MAX
+        );
+    }
+
+    #[test]
+    fn test_setup_new_token() {
+        let program_id = Pubkey::new(&[1; 32]);
+        let payer = Pubkey::new(&[2; 32]);
+        let token_key = Pubkey::new(&[3; 32]);
+        let account_key = Pubkey::new(&[4; 32]);
+        let owner = Pubkey::new(&[5; 32]);
+        let token = Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        };
+
+        let instructions = setup_new_token(
+            &program_id,
+            &payer,
+            &token_key,
+            &account_key,
+            &owner,
+            42,
+            token,
+        )
+        .unwrap();
+        assert_eq!(instructions.len(), 4);
+
+        assert_eq!(instructions[0].program_id, system_program::id());
+        assert_eq!(instructions[0].accounts[1].pubkey, token_key);
+        assert!(instructions[0].accounts[1].is_signer);
+        assert_eq!(instructions[1].program_id, system_program::id());
+        assert_eq!(instructions[1].accounts[1].pubkey, account_key);
+        assert!(instructions[1].accounts[1].is_signer);
+
+        assert_eq!(instructions[2].program_id, program_id);
+        assert_eq!(
+            Command::deserialize(&instructions[2].data),
+            Ok(Command::NewTokenAccount)
+        );
+        assert_eq!(
+            instructions[2]
+                .accounts
+                .iter()
+                .map(|meta| (meta.pubkey, meta.is_signer))
+                .collect::<Vec<_>>(),
+            vec![(account_key, true), (owner, false), (token_key, false)]
+        );
+
+        assert_eq!(instructions[3].program_id, program_id);
+        assert_eq!(
+            Command::deserialize(&instructions[3].data),
+            Ok(Command::NewToken(token))
+        );
+        assert_eq!(
+            instructions[3]
+                .accounts
+                .iter()
+                .map(|meta| (meta.pubkey, meta.is_signer))
+                .collect::<Vec<_>>(),
+            vec![(token_key, true), (account_key, false)]
+        );
+    }
+}
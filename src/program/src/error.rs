@@ -17,6 +17,42 @@ pub enum TokenError {
     NotDelegate,
     #[error("no owner")]
     NoOwner,
+    #[error("transfer too large")]
+    TransferTooLarge,
+    #[error("supply mismatch")]
+    SupplyMismatch,
+    #[error("illegal state transition")]
+    IllegalStateTransition,
+    #[error("not whitelisted")]
+    NotWhitelisted,
+    #[error("nonce consumed")]
+    NonceConsumed,
+    #[error("price condition not met")]
+    PriceConditionNotMet,
+    #[error("holding condition not met")]
+    HoldingConditionNotMet,
+    #[error("invariant violated")]
+    InvariantViolated,
+    #[error("supply cap exceeded")]
+    SupplyCapExceeded,
+    #[error("not an account")]
+    NotAnAccount,
+    #[error("delegate busy")]
+    DelegateBusy,
+    #[error("overflow")]
+    Overflow,
+    #[error("account frozen")]
+    AccountFrozen,
+    #[error("invalid instruction")]
+    InvalidInstruction,
+    #[error("mint paused")]
+    MintPaused,
+    #[error("owner immutable")]
+    OwnerImmutable,
+    #[error("non-transferable")]
+    NonTransferable,
+    #[error("delegate expired")]
+    DelegateExpired,
 }
 
 impl From<TokenError> for ProgramError {
@@ -41,6 +77,24 @@ impl PrintProgramError for TokenError {
             TokenError::TokenMismatch => info!("Error: token mismatch"),
             TokenError::NotDelegate => info!("Error: not a delegate"),
             TokenError::NoOwner => info!("Error: no owner"),
+            TokenError::TransferTooLarge => info!("Error: transfer too large"),
+            TokenError::SupplyMismatch => info!("Error: supply mismatch"),
+            TokenError::IllegalStateTransition => info!("Error: illegal state transition"),
+            TokenError::NotWhitelisted => info!("Error: not whitelisted"),
+            TokenError::NonceConsumed => info!("Error: nonce consumed"),
+            TokenError::PriceConditionNotMet => info!("Error: price condition not met"),
+            TokenError::HoldingConditionNotMet => info!("Error: holding condition not met"),
+            TokenError::InvariantViolated => info!("Error: invariant violated"),
+            TokenError::SupplyCapExceeded => info!("Error: supply cap exceeded"),
+            TokenError::NotAnAccount => info!("Error: not an account"),
+            TokenError::DelegateBusy => info!("Error: delegate busy"),
+            TokenError::Overflow => info!("Error: overflow"),
+            TokenError::AccountFrozen => info!("Error: account frozen"),
+            TokenError::InvalidInstruction => info!("Error: invalid instruction"),
+            TokenError::MintPaused => info!("Error: mint paused"),
+            TokenError::OwnerImmutable => info!("Error: owner immutable"),
+            TokenError::NonTransferable => info!("Error: non-transferable"),
+            TokenError::DelegateExpired => info!("Error: delegate expired"),
         }
     }
 }
@@ -59,6 +113,46 @@ mod test {
         error.print::<TokenError>();
     }
 
+    #[test]
+    fn test_display() {
+        // Matches what `print` logs after its "Error: " prefix
+        let expected = [
+            (TokenError::InsufficientFunds, "insufficient funds"),
+            (TokenError::TokenMismatch, "token mismatch"),
+            (TokenError::NotDelegate, "not a delegate"),
+            (TokenError::NoOwner, "no owner"),
+            (TokenError::TransferTooLarge, "transfer too large"),
+            (TokenError::SupplyMismatch, "supply mismatch"),
+            (
+                TokenError::IllegalStateTransition,
+                "illegal state transition",
+            ),
+            (TokenError::NotWhitelisted, "not whitelisted"),
+            (TokenError::NonceConsumed, "nonce consumed"),
+            (TokenError::PriceConditionNotMet, "price condition not met"),
+            (
+                TokenError::HoldingConditionNotMet,
+                "holding condition not met",
+            ),
+            (TokenError::InvariantViolated, "invariant violated"),
+            (TokenError::SupplyCapExceeded, "supply cap exceeded"),
+            (TokenError::NotAnAccount, "not an account"),
+            (TokenError::DelegateBusy, "delegate busy"),
+            (TokenError::Overflow, "overflow"),
+            (TokenError::AccountFrozen, "account frozen"),
+            (TokenError::InvalidInstruction, "invalid instruction"),
+            (TokenError::MintPaused, "mint paused"),
+            (TokenError::OwnerImmutable, "owner immutable"),
+            (TokenError::NonTransferable, "non-transferable"),
+            (TokenError::DelegateExpired, "delegate expired"),
+        ];
+        for (i, (error, message)) in expected.iter().enumerate() {
+            assert_eq!(TokenError::from_u32(i as u32).as_ref(), Some(error));
+            assert_eq!(format!("{}", error), *message);
+        }
+        assert_eq!(TokenError::from_u32(expected.len() as u32), None);
+    }
+
     #[test]
     #[should_panic(expected = "CustomError(1)")]
     fn test_error_unwrap() {
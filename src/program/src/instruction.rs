@@ -0,0 +1,172 @@
+use crate::state::{Command, Token};
+
+/// Serializes `command`, which can't fail since `serialize_to_vec` sizes the buffer to fit
+fn pack(command: Command) -> Vec<u8> {
+    command
+        .serialize_to_vec()
+        .expect("buffer sized by serialized_len")
+}
+
+/// Instruction data for `NewToken` with the given `supply` and `decimals`
+pub fn new_token(supply: u64, decimals: u64) -> Vec<u8> {
+    pack(Command::NewToken(Token {
+        supply,
+        decimals,
+        ..Token::default()
+    }))
+}
+
+/// Instruction data for `InitMint`
+pub fn init_mint(decimals: u64) -> Vec<u8> {
+    pack(Command::InitMint { decimals })
+}
+
+/// Instruction data for `NewTokenAccount`
+pub fn new_token_account() -> Vec<u8> {
+    pack(Command::NewTokenAccount)
+}
+
+/// Instruction data for `NewTokenAccountIdempotent`
+pub fn new_token_account_idempotent() -> Vec<u8> {
+    pack(Command::NewTokenAccountIdempotent)
+}
+
+/// Instruction data for `NewTokenAccountWithImmutableOwner`
+pub fn new_token_account_with_immutable_owner() -> Vec<u8> {
+    pack(Command::NewTokenAccountWithImmutableOwner)
+}
+
+/// Instruction data for `NewAssociatedTokenAccount`
+pub fn new_associated_token_account() -> Vec<u8> {
+    pack(Command::NewAssociatedTokenAccount)
+}
+
+/// Instruction data for `Transfer`
+pub fn transfer(amount: u64) -> Vec<u8> {
+    pack(Command::Transfer(amount))
+}
+
+/// Instruction data for `TransferChecked`
+pub fn transfer_checked(amount: u64, decimals: u64) -> Vec<u8> {
+    pack(Command::TransferChecked { amount, decimals })
+}
+
+/// Instruction data for `TransferWithMemo`
+pub fn transfer_with_memo(amount: u64, memo: [u8; 32]) -> Vec<u8> {
+    pack(Command::TransferWithMemo { amount, memo })
+}
+
+/// Instruction data for `Approve`
+pub fn approve(amount: u64) -> Vec<u8> {
+    pack(Command::Approve(amount))
+}
+
+/// Instruction data for `ApproveChecked`
+pub fn approve_checked(amount: u64, decimals: u64) -> Vec<u8> {
+    pack(Command::ApproveChecked { amount, decimals })
+}
+
+/// Instruction data for `ApproveWithExpiry`
+pub fn approve_with_expiry(amount: u64, expiry_slot: u64) -> Vec<u8> {
+    pack(Command::ApproveWithExpiry {
+        amount,
+        expiry_slot,
+    })
+}
+
+/// Instruction data for `ApproveAndTransfer`
+pub fn approve_and_transfer(approve: u64, transfer: u64) -> Vec<u8> {
+    pack(Command::ApproveAndTransfer { approve, transfer })
+}
+
+/// Instruction data for `Revoke`
+pub fn revoke() -> Vec<u8> {
+    pack(Command::Revoke)
+}
+
+/// Instruction data for `SetOwner`
+pub fn set_owner() -> Vec<u8> {
+    pack(Command::SetOwner)
+}
+
+/// Instruction data for `SetPause`
+pub fn set_pause(paused: bool) -> Vec<u8> {
+    pack(Command::SetPause(paused))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builders() {
+        let cases = [
+            (
+                new_token(1000, 2),
+                Command::NewToken(Token {
+                    supply: 1000,
+                    decimals: 2,
+                    ..Token::default()
+                }),
+            ),
+            (init_mint(2), Command::InitMint { decimals: 2 }),
+            (new_token_account(), Command::NewTokenAccount),
+            (
+                new_token_account_idempotent(),
+                Command::NewTokenAccountIdempotent,
+            ),
+            (
+                new_token_account_with_immutable_owner(),
+                Command::NewTokenAccountWithImmutableOwner,
+            ),
+            (
+                new_associated_token_account(),
+                Command::NewAssociatedTokenAccount,
+            ),
+            (transfer(100), Command::Transfer(100)),
+            (
+                transfer_checked(100, 2),
+                Command::TransferChecked {
+                    amount: 100,
+                    decimals: 2,
+                },
+            ),
+            (
+                transfer_with_memo(100, [7; 32]),
+                Command::TransferWithMemo {
+                    amount: 100,
+                    memo: [7; 32],
+                },
+            ),
+            (approve(100), Command::Approve(100)),
+            (
+                approve_checked(100, 2),
+                Command::ApproveChecked {
+                    amount: 100,
+                    decimals: 2,
+                },
+            ),
+            (
+                approve_with_expiry(100, 20),
+                Command::ApproveWithExpiry {
+                    amount: 100,
+                    expiry_slot: 20,
+                },
+            ),
+            (
+                approve_and_transfer(100, 40),
+                Command::ApproveAndTransfer {
+                    approve: 100,
+                    transfer: 40,
+                },
+            ),
+            (revoke(), Command::Revoke),
+            (set_owner(), Command::SetOwner),
+            (set_pause(true), Command::SetPause(true)),
+        ];
+        for (data, command) in cases.iter() {
+            assert_eq!(data.len(), command.serialized_len());
+            assert_eq!(Ok(command.clone()), Command::deserialize(data));
+        }
+    }
+}
@@ -1,3 +1,7 @@
+pub mod amount;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod error;
+pub mod instruction;
 pub mod processor;
 pub mod state;
@@ -5,6 +5,9 @@ use solana_sdk::{
 };
 
 entrypoint!(process_instruction);
+/// The entrypoint returns errors to the runtime as `u64::from(ProgramError)`, so a `TokenError`
+/// arrives as its discriminant and `SUCCESS` stays the only 0.  `InsufficientFunds`, whose
+/// discriminant is 0, is reported by the SDK as `CUSTOM_ZERO` instead
 fn process_instruction<'a>(
     program_id: &Pubkey,
     accounts: &'a [AccountInfo<'a>],
@@ -1,10 +1,100 @@
 use crate::error::TokenError;
 use solana_sdk::{
     account_info::AccountInfo, entrypoint::ProgramResult, info, program_error::ProgramError,
-    program_utils::next_account_info, pubkey::Pubkey,
+    program_utils::next_account_info, pubkey::Pubkey, sysvar,
 };
 use std::mem::size_of;
 
+/// Reads the little-endian u64 at `offset`
+fn read_u64(input: &[u8], offset: usize) -> Result<u64, ProgramError> {
+    let bytes = input
+        .get(offset..offset + size_of::<u64>())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    let mut value = [0u8; size_of::<u64>()];
+    value.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+/// Writes `value` as a little-endian u64 at `offset`
+fn write_u64(output: &mut [u8], offset: usize, value: u64) -> ProgramResult {
+    output
+        .get_mut(offset..offset + size_of::<u64>())
+        .ok_or(ProgramError::InvalidAccountData)?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Reads the little-endian u16 at `offset`
+fn read_u16(input: &[u8], offset: usize) -> Result<u16, ProgramError> {
+    let bytes = input
+        .get(offset..offset + size_of::<u16>())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Writes `value` as a little-endian u16 at `offset`
+fn write_u16(output: &mut [u8], offset: usize, value: u16) -> ProgramResult {
+    output
+        .get_mut(offset..offset + size_of::<u16>())
+        .ok_or(ProgramError::InvalidAccountData)?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Reads the `Pubkey` at `offset`
+fn read_pubkey(input: &[u8], offset: usize) -> Result<Pubkey, ProgramError> {
+    input
+        .get(offset..offset + size_of::<Pubkey>())
+        .map(Pubkey::new)
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Writes `key` at `offset`
+fn write_pubkey(output: &mut [u8], offset: usize, key: &Pubkey) -> ProgramResult {
+    output
+        .get_mut(offset..offset + size_of::<Pubkey>())
+        .ok_or(ProgramError::InvalidAccountData)?
+        .copy_from_slice(key.as_ref());
+    Ok(())
+}
+
+/// Like `next_account_info`, but logs the position and `name` of a missing account, as
+/// "Error: missing account" followed by the index and then the name
+fn next_account_info_at<'a, 'b, I: Iterator<Item = &'a AccountInfo<'b>>>(
+    iter: &mut I,
+    index: usize,
+    name: &str,
+) -> Result<I::Item, ProgramError> {
+    let account_info = next_account_info(iter);
+    if account_info.is_err() {
+        info!("Error: missing account");
+        info!(index, 0, 0, 0, 0);
+        info!(name);
+    }
+    account_info
+}
+
+/// Publishes `data` as the instruction's result for an invoking program.  The runtime has no
+/// return data syscall yet, so on-chain it is logged with `sol_log_slice`, the closest output
+/// it offers; off-chain there is nothing to return to and it is a no-op
+#[cfg(target_arch = "bpf")]
+fn set_return_data(data: &[u8]) {
+    solana_sdk::log::sol_log_slice(data);
+}
+#[cfg(not(target_arch = "bpf"))]
+fn set_return_data(_data: &[u8]) {}
+
+/// Address of the associated token account of `owner` for `token`, as created by the system
+/// program's `CreateAccountWithSeed` with `owner` as the base.  Seeds are capped at 32 bytes,
+/// so the seed is the hex encoding of the token's first 16 bytes
+pub fn associated_address(owner: &Pubkey, token: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    let seed: String = token.as_ref()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Pubkey::create_with_seed(owner, &seed, program_id).expect("seed within MAX_SEED_LEN")
+}
+
 /// Represents a unique token type that all like token accounts must be
 /// associated with
 #[repr(C)]
@@ -14,6 +104,282 @@ pub struct Token {
     pub supply: u64,
     /// Number of base 10 digits to the right of the decimal place in the total supply
     pub decimals: u64,
+    /// Largest amount a single transfer may move, 0 if unlimited
+    pub max_transfer: u64,
+    /// Total tokens ever minted, including the initial supply
+    pub total_minted: u64,
+    /// Total tokens ever burned
+    pub total_burned: u64,
+    /// Reserve token units required to back each token for `CheckPeg`, 0 if the token is not
+    /// pegged
+    pub peg_ratio: u64,
+    /// `RoundingMode` discriminant applied to fractional amounts such as fees
+    pub rounding: u8,
+    /// Largest `supply` minting may reach, 0 if unlimited
+    pub max_supply: u64,
+    /// Account that must sign to mint, set by `NewToken`.  Tokens created before it existed
+    /// hold `Pubkey::default()`, in which case the token account itself is the authority
+    pub mint_authority: Pubkey,
+    /// Account that must sign to freeze or thaw token accounts, None if they can't be frozen
+    pub freeze_authority: Option<Pubkey>,
+    /// Set by the mint authority through `SetPause` to block every transfer and approval
+    pub paused: bool,
+    /// Share of each transfer withheld for `fee_collector`, in hundredths of a percent
+    pub transfer_fee_basis_points: u16,
+    /// Token account credited with transfer fees, unused if `transfer_fee_basis_points` is 0
+    pub fee_collector: Pubkey,
+    /// Set at creation to keep tokens in the account they are minted to, where they can still
+    /// be burned
+    pub non_transferable: bool,
+}
+impl Token {
+    /// Largest `decimals` a token may have.  One whole token, `10^decimals`, must fit in a
+    /// u64, and UI amount math is only practical up to 18
+    pub const MAX_DECIMALS: u64 = 18;
+
+    /// Verifies that `decimals` is within `MAX_DECIMALS` and that `supply` rounded up to whole
+    /// tokens still fits in a u64, so every whole-token amount up to the supply has a raw
+    /// amount.  `u64::MAX` with 18 decimals fails, it is 18.446744073709551615 tokens but 19
+    /// tokens can't be represented
+    pub fn check_decimals(&self) -> ProgramResult {
+        if self.decimals > Self::MAX_DECIMALS {
+            info!("Error: too many decimals");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let one = 10u64.pow(self.decimals as u32);
+        let fraction = self.supply % one;
+        if fraction != 0 && self.supply.checked_add(one - fraction).is_none() {
+            info!("Error: supply too large for its decimals");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Largest `transfer_fee_basis_points`, withholding the whole amount
+    pub const MAX_TRANSFER_FEE_BASIS_POINTS: u16 = 10_000;
+
+    /// Verifies that `transfer_fee_basis_points` is within `MAX_TRANSFER_FEE_BASIS_POINTS`
+    pub fn check_transfer_fee(&self) -> ProgramResult {
+        if self.transfer_fee_basis_points > Self::MAX_TRANSFER_FEE_BASIS_POINTS {
+            info!("Error: transfer fee above 100%");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Portion of a transfer of `amount` withheld for `fee_collector`, that is
+    /// `amount * transfer_fee_basis_points / 10000` rounded in the token's rounding mode
+    pub fn transfer_fee(&self, amount: u64) -> Result<u64, ProgramError> {
+        self.check_transfer_fee()?;
+        self.rounding_mode()?
+            .mul_div(
+                amount,
+                u64::from(self.transfer_fee_basis_points),
+                u64::from(Self::MAX_TRANSFER_FEE_BASIS_POINTS),
+            )
+            .ok_or_else(|| TokenError::Overflow.into())
+    }
+
+    /// Decodes the token's `rounding` byte
+    pub fn rounding_mode(&self) -> Result<RoundingMode, ProgramError> {
+        match self.rounding {
+            0 => Ok(RoundingMode::Down),
+            1 => Ok(RoundingMode::Up),
+            2 => Ok(RoundingMode::HalfUp),
+            _ => {
+                info!("Error: invalid rounding mode");
+                Err(ProgramError::InvalidArgument)
+            }
+        }
+    }
+
+    /// Verifies that `supply` is within `max_supply`
+    pub fn check_max_supply(&self) -> ProgramResult {
+        if self.max_supply != 0 && self.supply > self.max_supply {
+            info!("Error: supply exceeds the maximum");
+            return Err(TokenError::SupplyCapExceeded.into());
+        }
+        Ok(())
+    }
+
+    /// Adds `amount` newly minted tokens to `supply` and `total_minted`, failing on overflow or
+    /// if `max_supply` would be exceeded
+    pub fn mint(&mut self, amount: u64) -> ProgramResult {
+        self.supply = self
+            .supply
+            .checked_add(amount)
+            .ok_or(TokenError::Overflow)?;
+        self.total_minted = self
+            .total_minted
+            .checked_add(amount)
+            .ok_or(TokenError::Overflow)?;
+        self.check_max_supply()?;
+        self.check_supply()
+    }
+
+    /// Verifies that `supply` equals `total_minted - total_burned`
+    pub fn check_supply(&self) -> ProgramResult {
+        match self.total_minted.checked_sub(self.total_burned) {
+            Some(net) if net == self.supply => Ok(()),
+            _ => {
+                info!("Error: supply does not match minted less burned");
+                Err(TokenError::SupplyMismatch.into())
+            }
+        }
+    }
+
+    /// Size of the original `Token` layout, which held only `supply` and `decimals`
+    pub const LEGACY_LEN: usize = 2 * size_of::<u64>();
+
+    // Field offsets of the serialized layout, integers are little-endian
+    const DECIMALS_OFFSET: usize = size_of::<u64>();
+    const MAX_TRANSFER_OFFSET: usize = Self::DECIMALS_OFFSET + size_of::<u64>();
+    const TOTAL_MINTED_OFFSET: usize = Self::MAX_TRANSFER_OFFSET + size_of::<u64>();
+    const TOTAL_BURNED_OFFSET: usize = Self::TOTAL_MINTED_OFFSET + size_of::<u64>();
+    const PEG_RATIO_OFFSET: usize = Self::TOTAL_BURNED_OFFSET + size_of::<u64>();
+    const ROUNDING_OFFSET: usize = Self::PEG_RATIO_OFFSET + size_of::<u64>();
+    const MAX_SUPPLY_OFFSET: usize = Self::ROUNDING_OFFSET + size_of::<u8>();
+    const MINT_AUTHORITY_OFFSET: usize = Self::MAX_SUPPLY_OFFSET + size_of::<u64>();
+    // A presence byte followed by room for the key, zeroed if absent
+    const FREEZE_AUTHORITY_OFFSET: usize = Self::MINT_AUTHORITY_OFFSET + size_of::<Pubkey>();
+    const PAUSED_OFFSET: usize =
+        Self::FREEZE_AUTHORITY_OFFSET + size_of::<u8>() + size_of::<Pubkey>();
+    const TRANSFER_FEE_BASIS_POINTS_OFFSET: usize = Self::PAUSED_OFFSET + size_of::<u8>();
+    const FEE_COLLECTOR_OFFSET: usize = Self::TRANSFER_FEE_BASIS_POINTS_OFFSET + size_of::<u16>();
+    const NON_TRANSFERABLE_OFFSET: usize = Self::FEE_COLLECTOR_OFFSET + size_of::<Pubkey>();
+
+    /// Size of a serialized `Token`
+    pub const LEN: usize = Self::NON_TRANSFERABLE_OFFSET + size_of::<u8>();
+
+    /// Decodes a `Token` from the current layout
+    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() < Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let freeze_authority = match input[Self::FREEZE_AUTHORITY_OFFSET] {
+            0 => None,
+            1 => Some(read_pubkey(
+                input,
+                Self::FREEZE_AUTHORITY_OFFSET + size_of::<u8>(),
+            )?),
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let paused = match input[Self::PAUSED_OFFSET] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let non_transferable = match input[Self::NON_TRANSFERABLE_OFFSET] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(Token {
+            supply: read_u64(input, 0)?,
+            decimals: read_u64(input, Self::DECIMALS_OFFSET)?,
+            max_transfer: read_u64(input, Self::MAX_TRANSFER_OFFSET)?,
+            total_minted: read_u64(input, Self::TOTAL_MINTED_OFFSET)?,
+            total_burned: read_u64(input, Self::TOTAL_BURNED_OFFSET)?,
+            peg_ratio: read_u64(input, Self::PEG_RATIO_OFFSET)?,
+            rounding: input[Self::ROUNDING_OFFSET],
+            max_supply: read_u64(input, Self::MAX_SUPPLY_OFFSET)?,
+            mint_authority: read_pubkey(input, Self::MINT_AUTHORITY_OFFSET)?,
+            freeze_authority,
+            paused,
+            transfer_fee_basis_points: read_u16(input, Self::TRANSFER_FEE_BASIS_POINTS_OFFSET)?,
+            fee_collector: read_pubkey(input, Self::FEE_COLLECTOR_OFFSET)?,
+            non_transferable,
+        })
+    }
+
+    /// Decodes the original layout, which held only `supply` and `decimals`, ignoring whatever
+    /// follows them.  The newer fields are filled with values consistent with the legacy supply
+    pub fn deserialize_legacy(input: &[u8]) -> Result<Self, ProgramError> {
+        let supply = read_u64(input, 0)?;
+        Ok(Token {
+            supply,
+            decimals: read_u64(input, Self::DECIMALS_OFFSET)?,
+            total_minted: supply,
+            ..Token::default()
+        })
+    }
+
+    /// Encodes the token into the first `Token::LEN` bytes of `output`
+    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
+        if output.len() < Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        write_u64(output, 0, self.supply)?;
+        write_u64(output, Self::DECIMALS_OFFSET, self.decimals)?;
+        write_u64(output, Self::MAX_TRANSFER_OFFSET, self.max_transfer)?;
+        write_u64(output, Self::TOTAL_MINTED_OFFSET, self.total_minted)?;
+        write_u64(output, Self::TOTAL_BURNED_OFFSET, self.total_burned)?;
+        write_u64(output, Self::PEG_RATIO_OFFSET, self.peg_ratio)?;
+        output[Self::ROUNDING_OFFSET] = self.rounding;
+        write_u64(output, Self::MAX_SUPPLY_OFFSET, self.max_supply)?;
+        write_pubkey(output, Self::MINT_AUTHORITY_OFFSET, &self.mint_authority)?;
+        let (present, freeze_authority) = match self.freeze_authority {
+            Some(freeze_authority) => (1, freeze_authority),
+            None => (0, Pubkey::default()),
+        };
+        output[Self::FREEZE_AUTHORITY_OFFSET] = present;
+        write_pubkey(
+            output,
+            Self::FREEZE_AUTHORITY_OFFSET + size_of::<u8>(),
+            &freeze_authority,
+        )?;
+        output[Self::PAUSED_OFFSET] = self.paused as u8;
+        write_u16(
+            output,
+            Self::TRANSFER_FEE_BASIS_POINTS_OFFSET,
+            self.transfer_fee_basis_points,
+        )?;
+        write_pubkey(output, Self::FEE_COLLECTOR_OFFSET, &self.fee_collector)?;
+        output[Self::NON_TRANSFERABLE_OFFSET] = self.non_transferable as u8;
+        Ok(())
+    }
+}
+
+/// How a fractional result is rounded to a whole token amount
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+    /// Toward zero
+    Down = 0,
+    /// Away from zero whenever there is a remainder
+    Up = 1,
+    /// To the nearest whole amount, halves away from zero
+    HalfUp = 2,
+}
+impl Default for RoundingMode {
+    fn default() -> Self {
+        RoundingMode::Down
+    }
+}
+impl RoundingMode {
+    /// Computes `amount * numerator / denominator` with integer math, rounding the result in
+    /// this mode.  Returns None if `denominator` is 0 or the result doesn't fit in a u64
+    pub fn mul_div(self, amount: u64, numerator: u64, denominator: u64) -> Option<u64> {
+        if denominator == 0 {
+            return None;
+        }
+        let product = u128::from(amount) * u128::from(numerator);
+        let denominator = u128::from(denominator);
+        let quotient = product / denominator;
+        let remainder = product % denominator;
+        let rounded = match self {
+            RoundingMode::Down => quotient,
+            RoundingMode::Up if remainder > 0 => quotient + 1,
+            RoundingMode::Up => quotient,
+            RoundingMode::HalfUp if remainder * 2 >= denominator => quotient + 1,
+            RoundingMode::HalfUp => quotient,
+        };
+        if rounded > u128::from(u64::MAX) {
+            None
+        } else {
+            Some(rounded as u64)
+        }
+    }
 }
 
 /// Delegation details
@@ -24,8 +390,51 @@ pub struct TokenAccountDelegate {
     pub source: Pubkey,
     /// The original amount that this delegate account was authorized to spend up to
     pub original_amount: u64,
+    /// Last slot in which the delegate may spend its allowance, 0 if it never expires
+    pub expiry_slot: u64,
+}
+impl TokenAccountDelegate {
+    const ORIGINAL_AMOUNT_OFFSET: usize = size_of::<Pubkey>();
+    const EXPIRY_SLOT_OFFSET: usize = Self::ORIGINAL_AMOUNT_OFFSET + size_of::<u64>();
+
+    /// Size of a serialized `TokenAccountDelegate`
+    pub const LEN: usize = Self::EXPIRY_SLOT_OFFSET + size_of::<u64>();
+
+    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
+        Ok(TokenAccountDelegate {
+            source: read_pubkey(input, 0)?,
+            original_amount: read_u64(input, Self::ORIGINAL_AMOUNT_OFFSET)?,
+            expiry_slot: read_u64(input, Self::EXPIRY_SLOT_OFFSET)?,
+        })
+    }
+
+    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
+        write_pubkey(output, 0, &self.source)?;
+        write_u64(output, Self::ORIGINAL_AMOUNT_OFFSET, self.original_amount)?;
+        write_u64(output, Self::EXPIRY_SLOT_OFFSET, self.expiry_slot)
+    }
+}
+
+/// Lifecycle of a token account
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccountState {
+    /// Not yet set up, `NewTokenAccount` may still initialize it
+    Uninitialized = 0,
+    /// Usable
+    Initialized = 1,
+    /// Transfers into and out of the account are blocked by the token's freeze authority
+    Frozen = 2,
+}
+impl Default for AccountState {
+    fn default() -> Self {
+        AccountState::Uninitialized
+    }
 }
 
+/// Maximum number of destinations in a token account's whitelist
+pub const MAX_WHITELIST: usize = 4;
+
 /// Account that holds or may delegate tokens
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
@@ -40,11 +449,210 @@ pub struct TokenAccount {
     /// If `delegate` is Option<_>, `amount` represents the remaining allowance
     /// of tokens that may be transferred from the `source` account.
     pub delegate: Option<TokenAccountDelegate>,
+    /// Whether the balance is published as `commitment` rather than read from `amount`
+    pub confidential: bool,
+    /// Commitment to the balance, meaningful only if `confidential` is set
+    pub commitment: [u8; 32],
+    /// Destinations this account may transfer to, unused slots are `Pubkey::default()`.
+    /// If every slot is unused, transfers are unrestricted
+    pub whitelist: [Pubkey; MAX_WHITELIST],
+    /// Whether the account is initialized, and if so whether it is frozen
+    pub state: AccountState,
+    /// Signer other than `owner` that may transfer out of this account, but can't change its
+    /// owner or other settings
+    pub transfer_authority: Option<Pubkey>,
+    /// Set at creation by `NewTokenAccountWithImmutableOwner`, after which `owner` can never
+    /// change
+    pub owner_immutable: bool,
+}
+impl TokenAccount {
+    // Field offsets of the serialized layout.  `delegate` and `transfer_authority` are a
+    // presence byte followed by room for the value, zeroed if absent
+    const OWNER_OFFSET: usize = size_of::<Pubkey>();
+    const AMOUNT_OFFSET: usize = Self::OWNER_OFFSET + size_of::<Pubkey>();
+    const DELEGATE_OFFSET: usize = Self::AMOUNT_OFFSET + size_of::<u64>();
+    const CONFIDENTIAL_OFFSET: usize =
+        Self::DELEGATE_OFFSET + size_of::<u8>() + TokenAccountDelegate::LEN;
+    const COMMITMENT_OFFSET: usize = Self::CONFIDENTIAL_OFFSET + size_of::<u8>();
+    const WHITELIST_OFFSET: usize = Self::COMMITMENT_OFFSET + size_of::<[u8; 32]>();
+    const STATE_OFFSET: usize = Self::WHITELIST_OFFSET + MAX_WHITELIST * size_of::<Pubkey>();
+    const TRANSFER_AUTHORITY_OFFSET: usize = Self::STATE_OFFSET + size_of::<u8>();
+    const OWNER_IMMUTABLE_OFFSET: usize =
+        Self::TRANSFER_AUTHORITY_OFFSET + size_of::<u8>() + size_of::<Pubkey>();
+
+    /// Size of a serialized `TokenAccount`
+    pub const LEN: usize = Self::OWNER_IMMUTABLE_OFFSET + size_of::<u8>();
+
+    // The original layout ended after `delegate`, a presence byte padded to 8 bytes followed by
+    // the delegate's `source` and `original_amount`
+    const LEGACY_DELEGATE_VALUE_OFFSET: usize = Self::DELEGATE_OFFSET + size_of::<u64>();
+
+    /// Size of the original `TokenAccount` layout
+    pub const LEGACY_LEN: usize =
+        Self::LEGACY_DELEGATE_VALUE_OFFSET + size_of::<Pubkey>() + size_of::<u64>();
+
+    /// Decodes the original layout, ignoring whatever follows it.  The newer fields take the
+    /// values of a newly created account
+    pub fn deserialize_legacy(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() < Self::LEGACY_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let delegate = match input[Self::DELEGATE_OFFSET] {
+            0 => None,
+            1 => Some(TokenAccountDelegate {
+                source: read_pubkey(input, Self::LEGACY_DELEGATE_VALUE_OFFSET)?,
+                original_amount: read_u64(
+                    input,
+                    Self::LEGACY_DELEGATE_VALUE_OFFSET + size_of::<Pubkey>(),
+                )?,
+                expiry_slot: 0,
+            }),
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(TokenAccount {
+            token: read_pubkey(input, 0)?,
+            owner: read_pubkey(input, Self::OWNER_OFFSET)?,
+            amount: read_u64(input, Self::AMOUNT_OFFSET)?,
+            delegate,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        })
+    }
+
+    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() < Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let delegate = match input[Self::DELEGATE_OFFSET] {
+            0 => None,
+            1 => Some(TokenAccountDelegate::deserialize(
+                &input[Self::DELEGATE_OFFSET + size_of::<u8>()..],
+            )?),
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let mut commitment = [0u8; 32];
+        commitment.copy_from_slice(
+            &input[Self::COMMITMENT_OFFSET..Self::COMMITMENT_OFFSET + size_of::<[u8; 32]>()],
+        );
+        let mut whitelist = [Pubkey::default(); MAX_WHITELIST];
+        for (i, key) in whitelist.iter_mut().enumerate() {
+            *key = read_pubkey(input, Self::WHITELIST_OFFSET + i * size_of::<Pubkey>())?;
+        }
+        let state = match input[Self::STATE_OFFSET] {
+            0 => AccountState::Uninitialized,
+            1 => AccountState::Initialized,
+            2 => AccountState::Frozen,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let transfer_authority = match input[Self::TRANSFER_AUTHORITY_OFFSET] {
+            0 => None,
+            1 => Some(read_pubkey(
+                input,
+                Self::TRANSFER_AUTHORITY_OFFSET + size_of::<u8>(),
+            )?),
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let owner_immutable = match input[Self::OWNER_IMMUTABLE_OFFSET] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(TokenAccount {
+            token: read_pubkey(input, 0)?,
+            owner: read_pubkey(input, Self::OWNER_OFFSET)?,
+            amount: read_u64(input, Self::AMOUNT_OFFSET)?,
+            delegate,
+            confidential: input[Self::CONFIDENTIAL_OFFSET] != 0,
+            commitment,
+            whitelist,
+            state,
+            transfer_authority,
+            owner_immutable,
+        })
+    }
+
+    /// Encodes the account into the first `TokenAccount::LEN` bytes of `output`
+    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
+        if output.len() < Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        write_pubkey(output, 0, &self.token)?;
+        write_pubkey(output, Self::OWNER_OFFSET, &self.owner)?;
+        write_u64(output, Self::AMOUNT_OFFSET, self.amount)?;
+        let delegate_output = &mut output[Self::DELEGATE_OFFSET..Self::CONFIDENTIAL_OFFSET];
+        match &self.delegate {
+            Some(delegate) => {
+                delegate_output[0] = 1;
+                delegate.serialize(&mut delegate_output[size_of::<u8>()..])?;
+            }
+            None => {
+                for byte in delegate_output.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+        output[Self::CONFIDENTIAL_OFFSET] = self.confidential as u8;
+        output[Self::COMMITMENT_OFFSET..Self::COMMITMENT_OFFSET + size_of::<[u8; 32]>()]
+            .copy_from_slice(&self.commitment);
+        for (i, key) in self.whitelist.iter().enumerate() {
+            write_pubkey(
+                output,
+                Self::WHITELIST_OFFSET + i * size_of::<Pubkey>(),
+                key,
+            )?;
+        }
+        output[Self::STATE_OFFSET] = self.state as u8;
+        let (present, transfer_authority) = match self.transfer_authority {
+            Some(transfer_authority) => (1, transfer_authority),
+            None => (0, Pubkey::default()),
+        };
+        output[Self::TRANSFER_AUTHORITY_OFFSET] = present;
+        write_pubkey(
+            output,
+            Self::TRANSFER_AUTHORITY_OFFSET + size_of::<u8>(),
+            &transfer_authority,
+        )?;
+        output[Self::OWNER_IMMUTABLE_OFFSET] = self.owner_immutable as u8;
+        Ok(())
+    }
+
+    /// Fails with `OwnerImmutable` if the account's owner can't change
+    pub fn check_owner_mutable(&self) -> ProgramResult {
+        if self.owner_immutable {
+            info!("Error: account owner is immutable");
+            return Err(TokenError::OwnerImmutable.into());
+        }
+        Ok(())
+    }
+
+    /// Whether the token's freeze authority has blocked transfers into and out of the account
+    pub fn is_frozen(&self) -> bool {
+        self.state == AccountState::Frozen
+    }
+
+    /// Whether this account's whitelist permits transfers to `dest`
+    pub fn allows_destination(&self, dest: &Pubkey) -> bool {
+        let unused = Pubkey::default();
+        self.whitelist.iter().all(|key| *key == unused) || self.whitelist.contains(dest)
+    }
+
+    /// Returns the balance commitment if the account is confidential
+    pub fn commitment(&self) -> Option<[u8; 32]> {
+        if self.confidential {
+            Some(self.commitment)
+        } else {
+            None
+        }
+    }
 }
 
+/// Maximum number of signers a multisig account can list
+pub const MAX_SIGNERS: usize = 11;
+
 /// Possible states to accounts owned by the token program
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)] // Mirrors the account data layout, variants can't be boxed
 pub enum State {
     /// Unallocated
     Unallocated,
@@ -52,8 +660,24 @@ pub enum State {
     Token(Token),
     /// Token account
     Account(TokenAccount),
-    /// Invalid state
+    /// Invalid state, an in-memory marker only.  No state transitions into it, so account
+    /// data should never hold its tag and `deserialize` rejects it
     Invalid,
+    /// One-time authorization, `consumed` once `ConsumeNonce` has presented `value`
+    Nonce { value: u64, consumed: bool },
+    /// Copy of a token account's `amount`, as of the `slot` `SyncMirror` last ran
+    Mirror {
+        source: Pubkey,
+        amount: u64,
+        slot: u64,
+    },
+    /// M-of-N authority usable as a token account's owner.  Owner checks on the transfer path
+    /// and in `SetOwner` accept it when the next `m` accounts after the command's own are
+    /// distinct `signers` that signed.  Unused signer slots are `Pubkey::default()`
+    Multisig {
+        m: u8,
+        signers: [Pubkey; MAX_SIGNERS],
+    },
 }
 impl Default for State {
     fn default() -> Self {
@@ -65,440 +689,12334 @@ impl Default for State {
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command {
+    /// Creates a token.  `Token::mint_authority` is ignored and set from the accounts instead,
+    /// `Token::freeze_authority` is kept as given
+    ///
     /// key 0 - New token
-    /// key 1 - Token account to hold tokens
+    /// key 1 - Token account to hold tokens, which must be empty
+    /// key 2 - Mint authority, which must sign (optional, defaults to key 0)
     NewToken(Token),
     /// key 0 - New token account
     /// key 1 - Owner of the account
     /// key 2 - Token this account is associated with
-    /// key 3 - Source account that this account is a delegate for (optional)
+    /// key 3 - Source account that this account is a delegate for (optional), which must
+    /// differ from key 0 and, if already an account, hold key 2's tokens
     NewTokenAccount,
-    /// key 0 - Owner of the source account
+    /// Accounts are identified only by position.  Key 0 must sign as key 1's owner or transfer
+    /// authority, so a list with the source and destination swapped fails unless both share
+    /// that signer.  A multisig owner signs through `m` of its signers instead, passed right
+    /// after key 3, so every later key below shifts up by `m`.  A zero `amount` fails with
+    /// `InvalidArgument`, and `u64::MAX` moves key 1's whole balance, or all of a delegate's
+    /// remaining allowance.  If the token charges a transfer fee, key 2 receives `amount` less
+    /// the fee and the token's `fee_collector`, passed last, receives the fee.  A delegate with
+    /// an `expiry_slot` needs the clock sysvar right after its source account, and fails with
+    /// `DelegateExpired` once the slot is past it.  On success the new balances of key 1 and
+    /// key 2 are returned through `set_return_data`
+    ///
+    /// key 0 - Owner or transfer authority of the source account
     /// key 1 - Source/Delegate token account
-    /// key 2 - Destination account
-    /// key 3 - Source account if key 1 is a delegate (optional)
+    /// key 2 - Destination account, which must differ from key 1
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    /// key 5 - Clock sysvar if key 1 is a delegate with an expiry slot (optional)
+    /// key 4, 5 or 6 - The token's fee collector if it charges a transfer fee
     Transfer(u64),
+    /// Fails with `InvalidArgument` for a zero `amount`, use `Revoke` to clear an allowance,
+    /// and with `MintPaused` while the token is paused
+    ///
     /// key 0 - Owner of the source account
     /// key 1 - Source token account
-    /// key 3 - Delegate account
+    /// key 2 - Delegate account
+    /// key 3 - Token the accounts are associated with
     Approve(u64),
+    /// Fails with `OwnerImmutable` if the account was created with an immutable owner
+    ///
     /// key 0 - Owner of the destination account
     /// key 1 - destination token account
-    /// key 2 - Owner to assign to destination account
+    /// key 2 - Owner to assign to destination account, which can only be the zero key if
+    /// the destination account is empty
+    /// key 3.. - `m` signers if key 0 is a multisig account
     SetOwner,
+    /// key 0 - Token account
+    /// key 1 - Expected owner of the token account
+    AssertAccountOwner,
+    /// Tops the destination up to `target`, transferring what the source can cover
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    TransferIfBelow { target: u64 },
+    /// Moves a token account's state to a new address, zeroing the old account's data
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - New unallocated account to move the source account into, which must sign
+    MoveAccount,
+    /// Transfers `amount` and closes the source account if its balance reaches zero.  A transfer
+    /// authority may sign a partial transfer, but emptying the account needs its owner
+    ///
+    /// key 0 - Owner or transfer authority of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Beneficiary of the source account's lamports if it is closed
+    /// key 5 - Source account if key 1 is a delegate (optional)
+    TransferAndCloseIfEmpty { amount: u64 },
+    /// Creates a new token with its supply split across several accounts, the amounts must sum
+    /// to the token's supply
+    ///
+    /// key 0 - New token
+    /// key 1..N - Token accounts to credit, one per amount
+    NewTokenDistributed(Token, Vec<u64>),
+    /// Atomically exchanges `amount_a` of token X held by party A for `amount_b` of token Y
+    /// held by party B.  Neither token may charge a transfer fee
+    ///
+    /// key 0 - Owner of party A's accounts
+    /// key 1 - Party A's source token account (token X)
+    /// key 2 - Party A's destination token account (token Y)
+    /// key 3 - Owner of party B's accounts
+    /// key 4 - Party B's source token account (token Y)
+    /// key 5 - Party B's destination token account (token X)
+    /// key 6 - Token X
+    /// key 7 - Token Y
+    Swap { amount_a: u64, amount_b: u64 },
+    /// Reports whether transferring `amount` would succeed, writing a `TransferCheck` byte to
+    /// the result account without modifying any token state
+    ///
+    /// key 0 - Source/Delegate token account
+    /// key 1 - Destination account
+    /// key 2 - Token the accounts are associated with
+    /// key 3 - Result account, its first data byte receives the `TransferCheck`
+    /// key 4 - Source account if key 0 is a delegate (optional)
+    CheckTransfer { amount: u64 },
+    /// Reports whether a reserve covers the token's supply at its `peg_ratio`, writing 1 if
+    /// solvent and 0 otherwise to the result account.  The reserve is measured in units of the
+    /// token it holds, which can't be the pegged token itself
+    ///
+    /// key 0 - Token
+    /// key 1 - Reserve, a token account of key 3 owned by this program
+    /// key 2 - Result account, its first data byte receives the outcome
+    /// key 3 - Token the reserve holds
+    CheckPeg,
+    /// Marks a token account confidential and records the client-computed commitment to its
+    /// balance, `amount` is still used for accounting
+    ///
+    /// key 0 - Owner of the token account
+    /// key 1 - Token account
+    SetConfidential { commitment: [u8; 32] },
+    /// Creates a token account and a delegate account bound to it with an `allowance`
+    ///
+    /// key 0 - Owner of the new token account
+    /// key 1 - New unallocated token account
+    /// key 2 - New unallocated delegate account
+    /// key 3 - Token the accounts are associated with
+    NewAccountAndApprove { allowance: u64 },
+    /// Replaces the destinations a token account may transfer to, passing no destinations
+    /// removes the restriction
+    ///
+    /// key 0 - Owner of the token account
+    /// key 1 - Token account
+    /// key 2..N - Allowed destination accounts, at most `MAX_WHITELIST`
+    SetWhitelist,
+    /// Writes the `amount` of each token account as a packed little-endian u64 array into the
+    /// result account, in account order. Any other state, including a token, reads as 0
+    ///
+    /// key 0 - Result account, at least 8 bytes per token account
+    /// key 1..N - Token accounts to read
+    BatchBalance,
+    /// Creates a nonce that can be consumed once by presenting `value`
+    ///
+    /// key 0 - New unallocated nonce account
+    NewNonce(u64),
+    /// Consumes a nonce, failing if `value` doesn't match or the nonce was already consumed
+    ///
+    /// key 0 - Nonce account, signing as the nonce's authority
+    ConsumeNonce(u64),
+    /// Transfers `amount` only if the oracle's price is at least `min_price`
+    ///
+    /// The oracle account's data must begin with the price as a little-endian u64.  The program
+    /// cannot tell who wrote it, so the caller is responsible for passing a trusted oracle.
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Price oracle
+    /// key 5 - Source account if key 1 is a delegate (optional)
+    TransferIfPrice { amount: u64, min_price: u64 },
+    /// Transfers `amount` only if the destination already holds at least `min_holding`
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    TransferIfHolds { amount: u64, min_holding: u64 },
+    /// Assigns a new owner to a token account and zeroes the allowance of each of its
+    /// delegates.  Fails with `OwnerImmutable` like `SetOwner`
+    ///
+    /// key 0 - Current owner of the token account
+    /// key 1 - Token account
+    /// key 2 - New owner
+    /// key 3..N - Delegate accounts bound to the token account
+    RotateOwner,
+    /// Writes the program's `PROGRAM_VERSION` byte followed by its `feature_flags()` as a
+    /// little-endian u32 into the result account
+    ///
+    /// key 0 - Result account, at least 5 bytes
+    ProgramInfo,
+    /// Transfers `amount`, then requires the `amount` fields of the invariant accounts to sum to
+    /// `expected_total`.  On a violation the instruction fails, so the runtime discards the
+    /// transfer
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    /// key 4..N or 5..N - Invariant accounts of the same token
+    TransferWithInvariant { amount: u64, expected_total: u64 },
+    /// Creates a token account and mints `amount` new tokens into it, increasing the supply
+    ///
+    /// key 0 - Token
+    /// key 1 - New token account
+    /// key 2 - Owner of the new account
+    /// key 3 - Mint authority (optional if key 0 is the authority and signs)
+    MintToNew(u64),
+    /// Transfers `amount` to each destination account, debiting the source by `amount` times
+    /// the number of destinations.  Fails if the token charges a transfer fee
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Token the accounts are associated with
+    /// key 3..N - Destination accounts
+    Distribute(u64),
+    /// Copies a token account's serialized `State` into the result account, where
+    /// `State::deserialize` decodes it
+    ///
+    /// key 0 - Token account
+    /// key 1 - Result account, at least `1 + TokenAccount::LEN` bytes
+    GetAccount,
+    /// Like `Approve`, but fails if the delegate still has a remaining allowance, which must
+    /// first be revoked with `Revoke`
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Delegate account
+    /// key 3 - Token the accounts are associated with
+    ApproveIfUnused(u64),
+    /// Copies a token account's `amount` and the current slot into a `State::Mirror`.  Anyone
+    /// may re-sync an existing mirror, but creating one requires the mirror account's signature
+    ///
+    /// key 0 - Mirror account, unallocated or already mirroring key 1
+    /// key 1 - Token account to mirror
+    /// key 2 - Clock sysvar
+    SyncMirror,
+    /// Destroys `amount` tokens held by the account, reducing the token's supply
+    ///
+    /// key 0 - Owner of the token account
+    /// key 1 - Token account, which can't be a delegate
+    /// key 2 - Token the account is associated with
+    Burn(u64),
+    /// Mints `amount` new tokens into an existing account, increasing the supply
+    ///
+    /// key 0 - Token
+    /// key 1 - Destination account, which can't be a delegate
+    /// key 2 - Mint authority (optional if key 0 is the authority and signs)
+    MintTo(u64),
+    /// Releases an empty token account, moving its lamports to the beneficiary.  A delegate
+    /// account is empty once its remaining allowance is 0
+    ///
+    /// key 0 - Owner of the token account
+    /// key 1 - Token account with an `amount` of 0
+    /// key 2 - Beneficiary of the account's lamports
+    CloseAccount,
+    /// Cancels a delegate's remaining allowance, keeping its `original_amount`
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Delegate account
+    Revoke,
+    /// Like `Transfer`, but fails unless `decimals` matches the token's, guarding against a
+    /// client scaling `amount` for the wrong token
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account, which must differ from key 1
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    TransferChecked { amount: u64, decimals: u64 },
+    /// Creates a multisig account that `m` of the listed signers must authorize
+    ///
+    /// key 0 - New unallocated multisig account
+    /// key 1..N - Signers, at most `MAX_SIGNERS` distinct accounts
+    NewMultisig(u8),
+    /// Like `Approve`, but fails unless key 3 is the source's token and `decimals` matches it
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Delegate account
+    /// key 3 - Token the accounts are associated with
+    ApproveChecked { amount: u64, decimals: u64 },
+    /// Blocks transfers into and out of a token account.  Fails if the token has no
+    /// `freeze_authority`
+    ///
+    /// key 0 - Token account to freeze
+    /// key 1 - Token the account is associated with
+    /// key 2 - Freeze authority
+    Freeze,
+    /// Unblocks a frozen token account
+    ///
+    /// key 0 - Token account to thaw
+    /// key 1 - Token the account is associated with
+    /// key 2 - Freeze authority
+    Thaw,
+    /// Transfers each amount to the destination at the same position, debiting the source by
+    /// their total.  Fails without crediting anyone if any transfer would fail, or if the token
+    /// charges a transfer fee
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Token the accounts are associated with
+    /// key 3..N - Destination accounts, one per amount
+    BatchTransfer(Vec<u64>),
+    /// Like `NewTokenAccount`, but succeeds without changes if key 0 is already an account with
+    /// the same owner, token and delegate source
+    ///
+    /// key 0 - New or existing token account
+    /// key 1 - Owner of the account
+    /// key 2 - Token this account is associated with
+    /// key 3 - Source account that this account is a delegate for (optional)
+    NewTokenAccountIdempotent,
+    /// Like `Transfer`, and logs `memo` once the transfer succeeds.  The memo is only there for
+    /// off-chain reconciliation and never affects the transfer
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account, which must differ from key 1
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Source account if key 1 is a delegate (optional)
+    TransferWithMemo { amount: u64, memo: [u8; 32] },
+    /// Sets or clears a token account's transfer authority, which may sign transfers out of
+    /// the account in place of its owner
+    ///
+    /// key 0 - Owner of the token account
+    /// key 1 - Token account
+    /// key 2 - New transfer authority (optional, cleared if absent)
+    SetTransferAuthority,
+    /// Creates a token with no supply, leaving all minting to `MintTo`
+    ///
+    /// key 0 - New token, which must sign
+    /// key 1 - Mint authority, which must sign (optional, defaults to key 0)
+    InitMint { decimals: u64 },
+    /// Like `NewTokenAccount`, but the new account must be the owner's `associated_address`
+    /// for the token.  The owner signs instead of the new account, which can't be a delegate
+    ///
+    /// key 0 - New token account, at `associated_address(key 1, key 2, program_id)`
+    /// key 1 - Owner of the new token account, which must sign
+    /// key 2 - Token this account is associated with
+    NewAssociatedTokenAccount,
+    /// Sets a delegate's allowance to `approve`, then transfers `transfer` of it to the
+    /// destination in the same instruction.  If the transfer fails the instruction fails, so the
+    /// runtime discards the new allowance.  Key 0 must own both the source and the delegate account
+    ///
+    /// key 0 - Owner of the source and delegate accounts
+    /// key 1 - Source token account
+    /// key 2 - Delegate account
+    /// key 3 - Destination account
+    /// key 4 - Token the accounts are associated with
+    /// key 5 - The token's fee collector if it charges a transfer fee
+    ApproveAndTransfer { approve: u64, transfer: u64 },
+    /// Pauses or resumes every transfer and approval of the token, failing them with
+    /// `MintPaused` while paused
+    ///
+    /// key 0 - Token
+    /// key 1 - Mint authority (optional if key 0 is the authority and signs)
+    SetPause(bool),
+    /// Like `NewTokenAccount`, but the account's owner can never be changed afterwards
+    ///
+    /// key 0 - New token account
+    /// key 1 - Owner of the new token account
+    /// key 2 - Token this account is associated with
+    /// key 3 - Source account that this account is a delegate for (optional)
+    NewTokenAccountWithImmutableOwner,
+    /// Like `Approve`, but the delegate can only spend the allowance up to and including
+    /// `expiry_slot`.  An `expiry_slot` of 0 never expires, as with `Approve`, which clears any
+    /// expiry set before
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source token account
+    /// key 2 - Delegate account
+    /// key 3 - Token the accounts are associated with
+    ApproveWithExpiry { amount: u64, expiry_slot: u64 },
+    /// Logs a token account's token, owner, amount and delegate status without writing to any
+    /// account, for inspecting an account from a simulated transaction
+    ///
+    /// key 0 - Token account
+    GetAccountInfo,
+    /// Transfers the source account's whole balance, or a delegate's whole remaining allowance,
+    /// to the destination and then closes the source like `CloseAccount`.  Key 0 must be the
+    /// source's owner, a transfer authority can't close the account.  Either both steps happen
+    /// or neither does
+    ///
+    /// key 0 - Owner of the source account
+    /// key 1 - Source/Delegate token account
+    /// key 2 - Destination account, which must differ from key 1
+    /// key 3 - Token the accounts are associated with
+    /// key 4 - Beneficiary of the source account's lamports
+    /// key 5 - Source account if key 1 is a delegate (optional)
+    CloseAndTransfer,
+    /// Writes a token's mint authority, a freeze authority presence byte and the freeze
+    /// authority into the result account, for other programs to gate on.  The mint authority
+    /// is the token itself if it predates mint authorities, and the freeze authority is zeroed
+    /// if the token has none
+    ///
+    /// key 0 - Token
+    /// key 1 - Result account, at least 65 bytes
+    GetAuthorities,
+    /// Freezes every listed token account of the token that the given owner holds, skipping
+    /// the rest and those already frozen, and writes how many were frozen into the result
+    /// account as a little-endian u64.  Fails like `Freeze` without the token's freeze authority
+    ///
+    /// key 0 - Token
+    /// key 1 - Freeze authority
+    /// key 2 - Result account, at least 8 bytes
+    /// key 3..N - Token accounts to consider
+    FreezeOwner(Pubkey),
 }
 
-impl<'a> State {
-    pub fn process_newtoken<I: Iterator<Item = &'a AccountInfo<'a>>>(
-        account_info_iter: &mut I,
-        token: Token,
-    ) -> ProgramResult {
-        let token_account_info = next_account_info(account_info_iter)?;
-        let dest_account_info = next_account_info(account_info_iter)?;
+/// Version reported by `ProgramInfo`
+pub const PROGRAM_VERSION: u8 = 1;
+/// Layout version written right after the tag of every serialized `State` and `Command`.
+/// Tokens and token accounts written by the original program lack it and are read through their
+/// legacy layouts.  Anything else is rejected, so a future layout can be told apart by bumping it
+pub const LAYOUT_VERSION: u8 = 1;
+/// Tag and `LAYOUT_VERSION` bytes that precede every serialized payload
+const HEADER_LEN: usize = 2 * size_of::<u8>();
+/// `ProgramInfo` feature bit 0, set if built with the `client` feature.  Bits 3-31 are reserved
+/// and always 0
+pub const FEATURE_CLIENT: u32 = 1;
+/// `ProgramInfo` feature bit 1, set if built with the `DEBUG_BALANCE_TRACE` feature
+pub const FEATURE_DEBUG_BALANCE_TRACE: u32 = 1 << 1;
+/// `ProgramInfo` feature bit 2, set if built with the `strict` feature
+pub const FEATURE_STRICT: u32 = 1 << 2;
 
-        let mut dest_account_data = dest_account_info.data.borrow_mut();
-        if let State::Account(mut dest_token_account) = State::deserialize(&dest_account_data)? {
-            if !token_account_info.is_signer {
-                info!("Error: token account not a signer");
-                return Err(ProgramError::MissingRequiredSignature);
-            }
-            if token_account_info.key != &dest_token_account.token {
+/// Bitmask of the cargo features this program was built with, see the `FEATURE_*` constants
+pub fn feature_flags() -> u32 {
+    let mut flags = 0;
+    if cfg!(feature = "client") {
+        flags |= FEATURE_CLIENT;
+    }
+    if cfg!(feature = "DEBUG_BALANCE_TRACE") {
+        flags |= FEATURE_DEBUG_BALANCE_TRACE;
+    }
+    if cfg!(feature = "strict") {
+        flags |= FEATURE_STRICT;
+    }
+    flags
+}
+
+/// Outcome of a transfer precondition check, written as a single byte by `CheckTransfer`
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferCheck {
+    /// The transfer would succeed
+    Allowed = 0,
+    /// The accounts and token are not all associated with the same token
+    TokenMismatch = 1,
+    /// The destination is a delegate account and cannot accept tokens
+    DestinationIsDelegate = 2,
+    /// The amount exceeds the token's `max_transfer`
+    TransferTooLarge = 3,
+    /// The source, or the delegate's source, does not hold enough tokens
+    InsufficientFunds = 4,
+    /// The provided source account is not the one the delegate is bound to
+    NotDelegate = 5,
+    /// The source, or the delegate's source, does not whitelist the destination
+    NotWhitelisted = 6,
+    /// The source, the delegate's source, or the destination is frozen
+    AccountFrozen = 7,
+    /// The token is paused
+    MintPaused = 8,
+    /// The token is non-transferable
+    NonTransferable = 9,
+}
+impl TransferCheck {
+    /// Maps a blocking outcome to the error the transfer itself would fail with
+    pub fn into_result(self) -> ProgramResult {
+        match self {
+            TransferCheck::Allowed => Ok(()),
+            TransferCheck::TokenMismatch => {
                 info!("Error: token mismatch");
-                return Err(TokenError::TokenMismatch.into());
+                Err(TokenError::TokenMismatch.into())
             }
-            if dest_token_account.delegate.is_some() {
-                info!("Error: Destination account is a delegate and cannot accept tokens");
-                return Err(ProgramError::InvalidArgument);
+            TransferCheck::DestinationIsDelegate => {
+                info!("Error: destination account is a delegate and cannot accept tokens");
+                Err(ProgramError::InvalidArgument)
+            }
+            TransferCheck::TransferTooLarge => {
+                info!("Error: transfer exceeds the token's maximum");
+                Err(TokenError::TransferTooLarge.into())
+            }
+            TransferCheck::InsufficientFunds => Err(TokenError::InsufficientFunds.into()),
+            TransferCheck::NotDelegate => {
+                info!("Error: Source account is not a delegate payee");
+                Err(TokenError::NotDelegate.into())
+            }
+            TransferCheck::NotWhitelisted => {
+                info!("Error: destination is not whitelisted");
+                Err(TokenError::NotWhitelisted.into())
+            }
+            TransferCheck::AccountFrozen => {
+                info!("Error: account is frozen");
+                Err(TokenError::AccountFrozen.into())
+            }
+            TransferCheck::MintPaused => {
+                info!("Error: token is paused");
+                Err(TokenError::MintPaused.into())
+            }
+            TransferCheck::NonTransferable => {
+                info!("Error: token is non-transferable");
+                Err(TokenError::NonTransferable.into())
             }
-
-            dest_token_account.amount = token.supply;
-            State::Account(dest_token_account).serialize(&mut dest_account_data)?;
-        } else {
-            info!("Error: Destination account is not an Account");
-            return Err(ProgramError::InvalidArgument);
-        }
-
-        if State::Unallocated != State::deserialize(&token_account_info.data.borrow())? {
-            info!("Error: token account is already allocated");
-            return Err(ProgramError::InvalidArgument);
         }
-
-        State::Token(token).serialize(&mut token_account_info.data.borrow_mut())
     }
+}
 
-    pub fn process_newaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
-        account_info_iter: &mut I,
-    ) -> ProgramResult {
-        let new_account_info = next_account_info(account_info_iter)?;
-        let owner_account_info = next_account_info(account_info_iter)?;
-        let token_account_info = next_account_info(account_info_iter)?;
-
-        if !new_account_info.is_signer {
-            info!("Error: new account not a signer");
-            return Err(ProgramError::MissingRequiredSignature);
-        }
+/// Observer notified of each balance or allowance change once its instruction has succeeded
+pub trait AuditHook {
+    /// `amount` tokens moved from `source` to `dest`
+    fn on_transfer(&mut self, _source: &Pubkey, _dest: &Pubkey, _amount: u64) {}
+    /// `amount` new tokens of `token` credited to `dest`
+    fn on_mint(&mut self, _token: &Pubkey, _dest: &Pubkey, _amount: u64) {}
+    /// `amount` tokens of `token` destroyed from `source`
+    fn on_burn(&mut self, _token: &Pubkey, _source: &Pubkey, _amount: u64) {}
+    /// `delegate` authorized to spend `amount` tokens from `source`
+    fn on_approve(&mut self, _source: &Pubkey, _delegate: &Pubkey, _amount: u64) {}
+}
 
-        let mut new_account_data = new_account_info.data.borrow_mut();
+/// Audit hook that ignores all events
+pub struct NoopAuditHook;
+impl AuditHook for NoopAuditHook {}
 
-        if State::Unallocated != State::deserialize(&new_account_data)? {
-            info!("Error: account is already allocated");
-            return Err(ProgramError::InvalidArgument);
-        }
+/// An `AuditHook` event held back until the instruction has succeeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AuditEvent {
+    Transfer(Pubkey, Pubkey, u64),
+    Mint(Pubkey, Pubkey, u64),
+    Burn(Pubkey, Pubkey, u64),
+    Approve(Pubkey, Pubkey, u64),
+}
 
-        let mut token_account = TokenAccount {
-            token: *token_account_info.key,
-            owner: *owner_account_info.key,
-            amount: 0,
-            delegate: None,
-        };
-        if let Ok(delegate_account) = next_account_info(account_info_iter) {
-            token_account.delegate = Some(TokenAccountDelegate {
-                source: *delegate_account.key,
-                original_amount: 0,
-            });
+/// Audit hook that records events so a failed instruction, whose writes the runtime discards,
+/// reports none of them
+#[derive(Default)]
+struct BufferedAuditHook {
+    events: Vec<AuditEvent>,
+}
+impl AuditHook for BufferedAuditHook {
+    fn on_transfer(&mut self, source: &Pubkey, dest: &Pubkey, amount: u64) {
+        self.events
+            .push(AuditEvent::Transfer(*source, *dest, amount));
+    }
+    fn on_mint(&mut self, token: &Pubkey, dest: &Pubkey, amount: u64) {
+        self.events.push(AuditEvent::Mint(*token, *dest, amount));
+    }
+    fn on_burn(&mut self, token: &Pubkey, source: &Pubkey, amount: u64) {
+        self.events.push(AuditEvent::Burn(*token, *source, amount));
+    }
+    fn on_approve(&mut self, source: &Pubkey, delegate: &Pubkey, amount: u64) {
+        self.events
+            .push(AuditEvent::Approve(*source, *delegate, amount));
+    }
+}
+impl BufferedAuditHook {
+    /// Reports the recorded events to `hook` in the order they occurred
+    fn replay<H: AuditHook>(self, hook: &mut H) {
+        for event in self.events {
+            match event {
+                AuditEvent::Transfer(source, dest, amount) => {
+                    hook.on_transfer(&source, &dest, amount)
+                }
+                AuditEvent::Mint(token, dest, amount) => hook.on_mint(&token, &dest, amount),
+                AuditEvent::Burn(token, source, amount) => hook.on_burn(&token, &source, amount),
+                AuditEvent::Approve(source, delegate, amount) => {
+                    hook.on_approve(&source, &delegate, amount)
+                }
+            }
+        }
+    }
+}
+
+/// Whether a balance trace entry was logged before or after the instruction ran
+#[cfg(feature = "DEBUG_BALANCE_TRACE")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BalanceTraceStage {
+    Pre,
+    Post,
+}
+
+#[cfg(all(test, feature = "DEBUG_BALANCE_TRACE"))]
+thread_local! {
+    /// Every entry `trace_balances` logged on this thread, in order
+    static BALANCE_TRACE: std::cell::RefCell<Vec<(BalanceTraceStage, Pubkey, u64)>> =
+        std::cell::RefCell::new(vec![]);
+}
+
+impl<'a> State {
+    /// Size of a serialized `State::Token`, including the tag and version
+    pub const TOKEN_LEN: usize = HEADER_LEN + Token::LEN;
+    /// Size of a serialized `State::Account`, including the tag and version
+    pub const ACCOUNT_LEN: usize = HEADER_LEN + TokenAccount::LEN;
+    const NONCE_LEN: usize = HEADER_LEN + size_of::<u64>() + size_of::<u8>();
+    const MIRROR_LEN: usize = HEADER_LEN + size_of::<Pubkey>() + 2 * size_of::<u64>();
+    const MULTISIG_LEN: usize = HEADER_LEN + size_of::<u8>() + MAX_SIGNERS * size_of::<Pubkey>();
+    /// Size the original program allocated for every account, the `size_of::<State>()` of its
+    /// layout
+    pub const LEGACY_LEN: usize = 128;
+
+    /// Number of bytes `serialize` writes for this state, including the tag and version.
+    /// `Unallocated` is a lone zero tag so that zeroed accounts read as unallocated
+    pub fn packed_len(&self) -> usize {
+        match self {
+            Self::Unallocated => size_of::<u8>(),
+            Self::Invalid => HEADER_LEN,
+            Self::Token(_) => Self::TOKEN_LEN,
+            Self::Account(_) => Self::ACCOUNT_LEN,
+            Self::Nonce { .. } => Self::NONCE_LEN,
+            Self::Mirror { .. } => Self::MIRROR_LEN,
+            Self::Multisig { .. } => Self::MULTISIG_LEN,
+        }
+    }
+
+    /// Fails early with `AccountDataTooSmall` unless `data` can hold a state of `len` bytes,
+    /// so that a processor rejects an under-sized account before touching anything rather than
+    /// when it finally serializes into it
+    pub fn assert_capacity(data: &[u8], len: usize) -> ProgramResult {
+        if data.len() < len {
+            info!("Error: account data too small");
+            return Err(ProgramError::AccountDataTooSmall);
         }
+        Ok(())
+    }
+
+    /// Balance of a token account, `None` for any other state
+    pub fn amount(&self) -> Option<u64> {
+        if let Self::Account(account) = self {
+            Some(account.amount)
+        } else {
+            None
+        }
+    }
+
+    /// Owner of a token account, `None` for any other state
+    pub fn owner(&self) -> Option<Pubkey> {
+        if let Self::Account(account) = self {
+            Some(account.owner)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that a token account's balance fits within `token`'s supply.  A delegate's
+    /// `amount` is an allowance rather than a balance and isn't checked, nor are other states
+    pub fn validate_account(&self, token: &Token) -> ProgramResult {
+        if let Self::Account(account) = self {
+            if account.delegate.is_none() && account.amount > token.supply {
+                info!("Error: account balance exceeds the token supply");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+        Ok(())
+    }
 
-        State::Account(token_account).serialize(&mut new_account_data)
+    /// `(source, remaining, original)` allowance of a delegate account, `None` for any other
+    /// state
+    pub fn delegate_allowance(&self) -> Option<(Pubkey, u64, u64)> {
+        if let Self::Account(TokenAccount {
+            amount,
+            delegate: Some(delegate),
+            ..
+        }) = self
+        {
+            Some((delegate.source, *amount, delegate.original_amount))
+        } else {
+            None
+        }
     }
 
-    pub fn process_transfer<I: Iterator<Item = &'a AccountInfo<'a>>>(
+    pub fn process_newtoken<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
         account_info_iter: &mut I,
-        amount: u64,
+        mut token: Token,
+        hook: &mut H,
     ) -> ProgramResult {
-        let owner_account_info = next_account_info(account_info_iter)?;
-        let source_account_info = next_account_info(account_info_iter)?;
-        let dest_account_info = next_account_info(account_info_iter)?;
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        State::assert_capacity(&token_account_info.data.borrow(), State::TOKEN_LEN)?;
 
-        let mut source_data = source_account_info.data.borrow_mut();
-        let mut dest_data = dest_account_info.data.borrow_mut();
-        if let (State::Account(mut source_account), State::Account(mut dest_account)) = (
-            State::deserialize(&source_data)?,
-            State::deserialize(&dest_data)?,
-        ) {
-            if source_account.token != dest_account.token {
+        token.total_minted = token.supply;
+        token.total_burned = 0;
+        token.check_supply()?;
+        token.check_max_supply()?;
+        token.check_decimals()?;
+        token.rounding_mode()?;
+        token.check_transfer_fee()?;
+
+        let mut dest_account_data = dest_account_info.data.borrow_mut();
+        if let State::Account(mut dest_token_account) = State::deserialize(&dest_account_data)? {
+            if !token_account_info.is_signer {
+                info!("Error: token account not a signer");
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if token_account_info.key != &dest_token_account.token {
                 info!("Error: token mismatch");
                 return Err(TokenError::TokenMismatch.into());
             }
-            if dest_account.delegate.is_some() {
-                info!("Error: destination account is a delegate and cannot accept tokens");
+            if dest_token_account.delegate.is_some() {
+                info!("Error: Destination account is a delegate and cannot accept tokens");
                 return Err(ProgramError::InvalidArgument);
             }
-            if owner_account_info.key != &source_account.owner {
-                info!("Error: source account owner not present");
-                return Err(TokenError::NoOwner.into());
-            }
-            if !owner_account_info.is_signer {
-                info!("Error: owner account not a signer");
-                return Err(ProgramError::MissingRequiredSignature);
-            }
-            if source_account.amount < amount {
-                return Err(TokenError::InsufficientFunds.into());
+            if dest_token_account.amount != 0 {
+                info!("Error: Destination account already holds tokens");
+                return Err(ProgramError::InvalidArgument);
             }
 
-            if let Some(ref delegate) = source_account.delegate {
-                let source_account_info = next_account_info(account_info_iter)?;
-                let mut actual_source_data = source_account_info.data.borrow_mut();
-                if let State::Account(mut actual_source_account) =
-                    State::deserialize(&actual_source_data)?
-                {
-                    if source_account_info.key != &delegate.source {
-                        info!("Error: Source account is not a delegate payee");
-                        return Err(TokenError::NotDelegate.into());
-                    }
+            dest_token_account.amount = token.supply;
+            State::Account(dest_token_account).serialize(&mut dest_account_data)?;
+        } else {
+            info!("Error: Destination account is not an Account");
+            return Err(ProgramError::InvalidArgument);
+        }
 
-                    if actual_source_account.amount < amount {
-                        return Err(TokenError::InsufficientFunds.into());
-                    }
+        if State::Unallocated != State::deserialize(&token_account_info.data.borrow())? {
+            info!("Error: token account is already allocated");
+            return Err(ProgramError::InvalidArgument);
+        }
 
-                    actual_source_account.amount -= amount;
-                    State::Account(actual_source_account).serialize(&mut actual_source_data)?;
-                } else {
-                    info!("Error: payee is an invalid account");
-                    return Err(ProgramError::InvalidArgument);
+        token.mint_authority = match next_account_info(account_info_iter) {
+            Ok(authority_account_info) => {
+                if !authority_account_info.is_signer {
+                    info!("Error: mint authority not a signer");
+                    return Err(ProgramError::MissingRequiredSignature);
                 }
+                *authority_account_info.key
             }
+            Err(_) => *token_account_info.key,
+        };
 
-            source_account.amount -= amount;
-            State::Account(source_account).serialize(&mut source_data)?;
+        State::Token(token).serialize(&mut token_account_info.data.borrow_mut())?;
+        hook.on_mint(token_account_info.key, dest_account_info.key, token.supply);
+        Ok(())
+    }
 
-            dest_account.amount += amount;
-            State::Account(dest_account).serialize(&mut dest_data)?;
-        } else {
-            info!("Error: destination and/or source accounts are invalid");
+    pub fn process_initmint<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        decimals: u64,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        State::assert_capacity(&token_account_info.data.borrow(), State::TOKEN_LEN)?;
+
+        let mut token = Token {
+            decimals,
+            ..Token::default()
+        };
+        token.check_decimals()?;
+
+        if !token_account_info.is_signer {
+            info!("Error: token account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if State::Unallocated != State::deserialize(&token_account_info.data.borrow())? {
+            info!("Error: token account is already allocated");
             return Err(ProgramError::InvalidArgument);
         }
-        Ok(())
+
+        token.mint_authority = match next_account_info(account_info_iter) {
+            Ok(authority_account_info) => {
+                if !authority_account_info.is_signer {
+                    info!("Error: mint authority not a signer");
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                *authority_account_info.key
+            }
+            Err(_) => *token_account_info.key,
+        };
+
+        State::Token(token).serialize(&mut token_account_info.data.borrow_mut())
     }
 
-    pub fn process_approve<I: Iterator<Item = &'a AccountInfo<'a>>>(
+    pub fn process_newtokendistributed<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
         account_info_iter: &mut I,
-        amount: u64,
+        mut token: Token,
+        amounts: Vec<u64>,
+        hook: &mut H,
     ) -> ProgramResult {
-        let owner_account_info = next_account_info(account_info_iter)?;
-        let source_account_info = next_account_info(account_info_iter)?;
-        let delegate_account_info = next_account_info(account_info_iter)?;
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        State::assert_capacity(&token_account_info.data.borrow(), State::TOKEN_LEN)?;
 
-        let source_data = source_account_info.data.borrow_mut();
-        let mut delegate_data = delegate_account_info.data.borrow_mut();
-        if let (State::Account(source_account), State::Account(mut delegate_account)) = (
-            State::deserialize(&source_data)?,
-            State::deserialize(&delegate_data)?,
-        ) {
-            if source_account.token != delegate_account.token {
-                info!("Error: token mismatch");
-                return Err(TokenError::TokenMismatch.into());
-            }
-            if owner_account_info.key != &source_account.owner {
-                info!("Error: source account owner is not present");
-                return Err(TokenError::NoOwner.into());
-            }
-            if !owner_account_info.is_signer {
-                info!("Error: owner account not a signer");
-                return Err(ProgramError::MissingRequiredSignature);
-            }
-            if source_account.delegate.is_some() {
-                info!("Error: source account is a delegate");
-                return Err(ProgramError::InvalidArgument);
-            }
+        if !token_account_info.is_signer {
+            info!("Error: token account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut total: u64 = 0;
+        for amount in amounts.iter() {
+            total = total.checked_add(*amount).ok_or(TokenError::Overflow)?;
+        }
+        if total != token.supply {
+            info!("Error: distribution does not sum to the supply");
+            return Err(ProgramError::InvalidArgument);
+        }
+        token.total_minted = token.supply;
+        token.total_burned = 0;
+        token.check_supply()?;
+        token.check_max_supply()?;
+        token.check_decimals()?;
+        token.rounding_mode()?;
+        token.check_transfer_fee()?;
+        if State::Unallocated != State::deserialize(&token_account_info.data.borrow())? {
+            info!("Error: token account is already allocated");
+            return Err(ProgramError::InvalidArgument);
+        }
 
-            match &delegate_account.delegate {
-                None => {
-                    info!("Error: delegate account is not a delegate");
-                    return Err(TokenError::NotDelegate.into());
+        for amount in amounts {
+            let dest_account_info = next_account_info(account_info_iter)?;
+            let mut dest_account_data = dest_account_info.data.borrow_mut();
+            if let State::Account(mut dest_token_account) = State::deserialize(&dest_account_data)?
+            {
+                if token_account_info.key != &dest_token_account.token {
+                    info!("Error: token mismatch");
+                    return Err(TokenError::TokenMismatch.into());
                 }
-                Some(delegate) => {
-                    if source_account_info.key != &delegate.source {
-                        info!("Error: delegate account is not a delegate of the source account");
-                        return Err(TokenError::NotDelegate.into());
-                    }
-
-                    delegate_account.amount = amount;
-                    delegate_account.delegate = Some(TokenAccountDelegate {
-                        source: delegate.source,
-                        original_amount: amount,
-                    });
-                    State::Account(delegate_account).serialize(&mut delegate_data)?;
+                if dest_token_account.delegate.is_some() {
+                    info!("Error: Destination account is a delegate and cannot accept tokens");
+                    return Err(ProgramError::InvalidArgument);
                 }
+
+                dest_token_account.amount = dest_token_account
+                    .amount
+                    .checked_add(amount)
+                    .ok_or(TokenError::Overflow)?;
+                State::Account(dest_token_account).serialize(&mut dest_account_data)?;
+                hook.on_mint(token_account_info.key, dest_account_info.key, amount);
+            } else {
+                info!("Error: Destination account is not an Account");
+                return Err(ProgramError::InvalidArgument);
             }
-        } else {
-            info!("Error: destination and/or source accounts are not Accounts");
-            return Err(ProgramError::InvalidArgument);
         }
-        Ok(())
+
+        token.mint_authority = *token_account_info.key;
+
+        State::Token(token).serialize(&mut token_account_info.data.borrow_mut())
     }
 
-    pub fn process_setowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+    pub fn process_newaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
         account_info_iter: &mut I,
     ) -> ProgramResult {
-        let owner_account_info = next_account_info(account_info_iter)?;
-        let dest_account_info = next_account_info(account_info_iter)?;
-        let new_owner_account_info = next_account_info(account_info_iter)?;
+        Self::new_account(account_info_iter, false, None, false)
+    }
 
-        let mut dest_account_data = dest_account_info.data.borrow_mut();
-        if let State::Account(mut dest_account) = State::deserialize(&dest_account_data)? {
-            if owner_account_info.key != &dest_account.owner {
-                info!("Error: destination account owner is not present");
-                return Err(TokenError::NoOwner.into());
+    pub fn process_newaccountwithimmutableowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        Self::new_account(account_info_iter, false, None, true)
+    }
+
+    pub fn process_newaccountidempotent<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        Self::new_account(account_info_iter, true, None, false)
+    }
+
+    pub fn process_newassociatedaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        Self::new_account(account_info_iter, false, Some(program_id), false)
+    }
+
+    /// Creates a token account, or if `if_missing` is set accepts an existing one that matches
+    /// what would have been created.  With `associated_program_id`, the account must be at the
+    /// owner's associated address instead of signing, and can't be a delegate.  An account
+    /// created with `owner_immutable` can never change owner
+    fn new_account<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        if_missing: bool,
+        associated_program_id: Option<&Pubkey>,
+        owner_immutable: bool,
+    ) -> ProgramResult {
+        let new_account_info = next_account_info_at(account_info_iter, 0, "new account")?;
+        let owner_account_info = next_account_info_at(account_info_iter, 1, "owner")?;
+        let token_account_info = next_account_info_at(account_info_iter, 2, "token")?;
+        State::assert_capacity(&new_account_info.data.borrow(), State::ACCOUNT_LEN)?;
+
+        if let Some(program_id) = associated_program_id {
+            let expected =
+                associated_address(owner_account_info.key, token_account_info.key, program_id);
+            if *new_account_info.key != expected {
+                info!("Error: account is not the owner's associated address");
+                return Err(ProgramError::InvalidArgument);
             }
+            // Nobody holds a key for a derived address, the owner signs in its place
             if !owner_account_info.is_signer {
                 info!("Error: owner account not a signer");
                 return Err(ProgramError::MissingRequiredSignature);
             }
+        } else if !new_account_info.is_signer {
+            info!("Error: new account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
 
-            dest_account.owner = *new_owner_account_info.key;
-            State::Account(dest_account).serialize(&mut dest_account_data)?;
-        } else {
-            info!("Error: destination account is invalid");
+        let mut new_account_data = new_account_info.data.borrow_mut();
+
+        let existing = State::deserialize(&new_account_data)?;
+        let initialized = match existing {
+            State::Unallocated => false,
+            State::Account(account) => account.state != AccountState::Uninitialized,
+            _ => true,
+        };
+        if initialized && !if_missing {
+            info!("Error: account is already allocated");
             return Err(ProgramError::InvalidArgument);
         }
-        Ok(())
-    }
-
-    pub fn process(
-        _program_id: &Pubkey,
-        accounts: &'a [AccountInfo<'a>],
-        input: &[u8],
-    ) -> ProgramResult {
-        let command = Command::deserialize(input)?;
-        let account_info_iter = &mut accounts.iter();
 
-        match command {
-            Command::NewToken(token_info) => {
-                info!("Command: NewToken");
-                Self::process_newtoken(account_info_iter, token_info)
+        let mut token_account = TokenAccount {
+            token: *token_account_info.key,
+            owner: *owner_account_info.key,
+            amount: 0,
+            delegate: None,
+            state: AccountState::Initialized,
+            owner_immutable,
+            ..TokenAccount::default()
+        };
+        if let Ok(delegate_account) = next_account_info(account_info_iter) {
+            if associated_program_id.is_some() {
+                info!("Error: associated account cannot be a delegate");
+                return Err(ProgramError::InvalidArgument);
             }
-            Command::NewTokenAccount => {
-                info!("Command: NewTokenAccount");
-                Self::process_newaccount(account_info_iter)
+            if delegate_account.key == new_account_info.key {
+                info!("Error: account cannot be a delegate of itself");
+                return Err(ProgramError::InvalidArgument);
             }
-            Command::Transfer(amount) => {
-                info!("Command: Transfer");
-                Self::process_transfer(account_info_iter, amount)
+            if let Ok(State::Account(source_account)) =
+                State::deserialize(&delegate_account.data.borrow())
+            {
+                if source_account.token != token_account.token {
+                    info!("Error: token mismatch");
+                    return Err(TokenError::TokenMismatch.into());
+                }
             }
-            Command::Approve(amount) => {
-                info!("Command: Approve");
-                Self::process_approve(account_info_iter, amount)
+            token_account.delegate = Some(TokenAccountDelegate {
+                source: *delegate_account.key,
+                original_amount: 0,
+                expiry_slot: 0,
+            });
+        }
+
+        match existing {
+            _ if !initialized => State::Account(token_account).serialize(&mut new_account_data),
+            State::Account(account)
+                if account.token == token_account.token
+                    && account.owner == token_account.owner
+                    && account.delegate.map(|delegate| delegate.source)
+                        == token_account.delegate.map(|delegate| delegate.source) =>
+            {
+                Ok(())
             }
-            Command::SetOwner => {
-                info!("Command: SetOwner");
-                Self::process_setowner(account_info_iter)
+            _ => {
+                info!("Error: account is already allocated");
+                Err(ProgramError::InvalidArgument)
             }
         }
     }
 
-    pub fn deserialize(input: &'a [u8]) -> Result<Self, ProgramError> {
-        if input.len() < size_of::<u8>() {
-            return Err(ProgramError::InvalidAccountData);
+    pub fn process_minttonew<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let new_account_info = next_account_info_at(account_info_iter, 1, "new account")?;
+        let owner_account_info = next_account_info_at(account_info_iter, 2, "owner")?;
+        let authority_account_info = next_account_info(account_info_iter).ok();
+        State::assert_capacity(&new_account_info.data.borrow(), State::ACCOUNT_LEN)?;
+
+        if !new_account_info.is_signer {
+            info!("Error: new account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
         }
-        Ok(match input[0] {
-            0 => Self::Unallocated,
-            1 => {
-                if input.len() < size_of::<u8>() + size_of::<Token>() {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                #[allow(clippy::cast_ptr_alignment)]
-                let token: &Token = unsafe { &*(&input[1] as *const u8 as *const Token) };
-                Self::Token(*token)
-            }
-            2 => {
-                if input.len() < size_of::<u8>() + size_of::<TokenAccount>() {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                #[allow(clippy::cast_ptr_alignment)]
-                let account: &TokenAccount =
-                    unsafe { &*(&input[1] as *const u8 as *const TokenAccount) };
-                Self::Account(*account)
-            }
-            3 => Self::Invalid,
-            _ => return Err(ProgramError::InvalidAccountData),
-        })
-    }
 
-    pub fn serialize(self: &Self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < size_of::<u8>() {
-            return Err(ProgramError::InvalidAccountData);
+        let mut new_account_data = new_account_info.data.borrow_mut();
+        if State::Unallocated != State::deserialize(&new_account_data)? {
+            info!("Error: account is already allocated");
+            return Err(ProgramError::InvalidArgument);
         }
-        match self {
-            Self::Unallocated => output[0] = 0,
-            Self::Token(token) => {
-                if output.len() < size_of::<u8>() + size_of::<Token>() {
-                    return Err(ProgramError::InvalidAccountData);
+
+        let mut token_data = token_account_info.data.borrow_mut();
+        let mut token = if let State::Token(token) = State::deserialize(&token_data)? {
+            token
+        } else {
+            info!("Error: token account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+        Self::require_mint_authority(&token, token_account_info, authority_account_info)?;
+        token.mint(amount)?;
+
+        State::Account(TokenAccount {
+            token: *token_account_info.key,
+            owner: *owner_account_info.key,
+            amount,
+            delegate: None,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        })
+        .serialize(&mut new_account_data)?;
+        State::Token(token).serialize(&mut token_data)?;
+        hook.on_mint(token_account_info.key, new_account_info.key, amount);
+        Ok(())
+    }
+
+    pub fn process_mintto<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        let authority_account_info = next_account_info(account_info_iter).ok();
+
+        let mut dest_account_data = dest_account_info.data.borrow_mut();
+        let mut dest_account =
+            if let State::Account(dest_account) = State::deserialize(&dest_account_data)? {
+                dest_account
+            } else {
+                info!("Error: Destination account is not an Account");
+                return Err(ProgramError::InvalidArgument);
+            };
+        if token_account_info.key != &dest_account.token {
+            info!("Error: token mismatch");
+            return Err(TokenError::TokenMismatch.into());
+        }
+        if dest_account.delegate.is_some() {
+            info!("Error: Destination account is a delegate and cannot accept tokens");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut token_data = token_account_info.data.borrow_mut();
+        let mut token = if let State::Token(token) = State::deserialize(&token_data)? {
+            token
+        } else {
+            info!("Error: token account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+        Self::require_mint_authority(&token, token_account_info, authority_account_info)?;
+        token.mint(amount)?;
+        dest_account.amount = dest_account
+            .amount
+            .checked_add(amount)
+            .ok_or(TokenError::Overflow)?;
+
+        State::Account(dest_account).serialize(&mut dest_account_data)?;
+        State::Token(token).serialize(&mut token_data)?;
+        hook.on_mint(token_account_info.key, dest_account_info.key, amount);
+        Ok(())
+    }
+
+    pub fn process_newaccountandapprove<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        allowance: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let new_account_info = next_account_info_at(account_info_iter, 1, "new account")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+        State::assert_capacity(&new_account_info.data.borrow(), State::ACCOUNT_LEN)?;
+        State::assert_capacity(&delegate_account_info.data.borrow(), State::ACCOUNT_LEN)?;
+
+        if !owner_account_info.is_signer {
+            info!("Error: owner not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !new_account_info.is_signer || !delegate_account_info.is_signer {
+            info!("Error: new account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if new_account_info.key == delegate_account_info.key {
+            info!("Error: token account and delegate account are the same");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut new_account_data = new_account_info.data.borrow_mut();
+        let mut delegate_account_data = delegate_account_info.data.borrow_mut();
+        if State::Unallocated != State::deserialize(&new_account_data)?
+            || State::Unallocated != State::deserialize(&delegate_account_data)?
+        {
+            info!("Error: account is already allocated");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let token_account = TokenAccount {
+            token: *token_account_info.key,
+            owner: *owner_account_info.key,
+            amount: 0,
+            delegate: None,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        let delegate_account = TokenAccount {
+            amount: allowance,
+            delegate: Some(TokenAccountDelegate {
+                source: *new_account_info.key,
+                original_amount: allowance,
+                expiry_slot: 0,
+            }),
+            ..token_account
+        };
+
+        State::Account(token_account).serialize(&mut new_account_data)?;
+        State::Account(delegate_account).serialize(&mut delegate_account_data)?;
+        hook.on_approve(new_account_info.key, delegate_account_info.key, allowance);
+        Ok(())
+    }
+
+    pub fn process_transfer<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        let amount = Self::resolve_transfer_all(source_account_info, amount)?;
+        Self::require_nonzero(amount)?;
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )?;
+        set_return_data(&Self::transfer_return_data(
+            source_account_info,
+            dest_account_info,
+        )?);
+        Ok(())
+    }
+
+    /// Key 1's new balance, the remaining allowance if it is a delegate, followed by the
+    /// destination's, each a little-endian u64
+    fn transfer_return_data(
+        source_account_info: &AccountInfo<'a>,
+        dest_account_info: &AccountInfo<'a>,
+    ) -> Result<[u8; 2 * size_of::<u64>()], ProgramError> {
+        let mut data = [0u8; 2 * size_of::<u64>()];
+        for (i, account_info) in [source_account_info, dest_account_info].iter().enumerate() {
+            let amount = State::deserialize(&account_info.data.borrow())?
+                .amount()
+                .unwrap_or(0);
+            write_u64(&mut data, i * size_of::<u64>(), amount)?;
+        }
+        Ok(data)
+    }
+
+    pub fn process_transferwithmemo<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        memo: &[u8; 32],
+        hook: &mut H,
+    ) -> ProgramResult {
+        Self::process_transfer(program_id, account_info_iter, amount, hook)?;
+        info!("Memo:");
+        info!(
+            read_u64(memo, 0)?,
+            read_u64(memo, size_of::<u64>())?,
+            read_u64(memo, 2 * size_of::<u64>())?,
+            read_u64(memo, 3 * size_of::<u64>())?,
+            0
+        );
+        Ok(())
+    }
+
+    pub fn process_transferchecked<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        decimals: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        let amount = Self::resolve_transfer_all(source_account_info, amount)?;
+        Self::require_nonzero(amount)?;
+        Self::require_decimals(token_account_info, decimals)?;
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )
+    }
+
+    /// Replaces the `u64::MAX` sentinel with the source account's balance, which for a delegate
+    /// is its remaining allowance
+    fn resolve_transfer_all(
+        source_account_info: &AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<u64, ProgramError> {
+        if amount != u64::MAX {
+            return Ok(amount);
+        }
+        State::deserialize(&source_account_info.data.borrow())?
+            .amount()
+            .ok_or_else(|| {
+                info!("Error: source account is invalid");
+                ProgramError::InvalidArgument
+            })
+    }
+
+    /// Rejects a zero `amount` before any account data is touched
+    fn require_nonzero(amount: u64) -> ProgramResult {
+        if amount == 0 {
+            info!("Error: zero amount");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Verifies that the token account is a token with `decimals` decimals
+    fn require_decimals(token_account_info: &AccountInfo<'a>, decimals: u64) -> ProgramResult {
+        if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+            if token.decimals != decimals {
+                info!("Error: decimals mismatch");
+                return Err(ProgramError::InvalidArgument);
+            }
+            Ok(())
+        } else {
+            info!("Error: token account is invalid");
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+
+    /// Moves `amount` tokens from the source to the destination account, pulling the
+    /// delegate's source account and, for an expiring delegate, the clock sysvar from
+    /// `account_info_iter` if needed and then the fee collector if the token charges a transfer
+    /// fee, which is withheld from what the destination receives
+    #[allow(clippy::too_many_arguments)] // The accounts every transfer-like command shares
+    fn transfer<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        owner_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        dest_account_info: &AccountInfo<'a>,
+        token_account_info: &AccountInfo<'a>,
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        // Rejected rather than treated as a no-op, both entries would share one account's data
+        // and a client asking for a self-transfer has most likely built its account list wrong
+        if source_account_info.key == dest_account_info.key {
+            info!("Error: source and destination are the same account");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let mut source_data = source_account_info.data.borrow_mut();
+        let mut dest_data = dest_account_info.data.borrow_mut();
+        let mut fee_credit = None;
+        if let (State::Account(mut source_account), State::Account(mut dest_account)) = (
+            State::deserialize(&source_data)?,
+            State::deserialize(&dest_data)?,
+        ) {
+            if source_account.token != dest_account.token {
+                return TransferCheck::TokenMismatch.into_result();
+            }
+            if dest_account.delegate.is_some() {
+                return TransferCheck::DestinationIsDelegate.into_result();
+            }
+            if source_account.transfer_authority == Some(*owner_account_info.key) {
+                if !owner_account_info.is_signer {
+                    info!("Error: transfer authority not a signer");
+                    return Err(ProgramError::MissingRequiredSignature);
                 }
-                output[0] = 1;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut Token) };
-                *value = *token;
+            } else {
+                Self::require_owner_or_multisig(
+                    program_id,
+                    owner_account_info,
+                    &source_account.owner,
+                    account_info_iter,
+                )?;
             }
-            Self::Account(account) => {
-                if output.len() < size_of::<u8>() + size_of::<TokenAccount>() {
-                    return Err(ProgramError::InvalidAccountData);
+            Self::check_transfer(
+                &source_account,
+                &dest_account,
+                dest_account_info.key,
+                token_account_info,
+                amount,
+            )?
+            .into_result()?;
+            let token = if let State::Token(token) =
+                State::deserialize(&token_account_info.data.borrow())?
+            {
+                token
+            } else {
+                info!("Error: token account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+            let fee = token.transfer_fee(amount)?;
+            let dest_amount = dest_account
+                .amount
+                .checked_add(amount - fee)
+                .ok_or_else(|| {
+                    info!("Error: destination amount overflow");
+                    TokenError::Overflow
+                })?;
+
+            // Written once the fee collector has been checked too
+            let mut actual_source = None;
+            if let Some(ref delegate) = source_account.delegate {
+                let source_account_info = next_account_info(account_info_iter)?;
+                let actual_source_data = source_account_info.data.borrow_mut();
+                if let State::Account(mut actual_source_account) =
+                    State::deserialize(&actual_source_data)?
+                {
+                    if source_account_info.key != &delegate.source {
+                        info!("Error: Source account is not a delegate payee");
+                        return Err(TokenError::NotDelegate.into());
+                    }
+
+                    if !actual_source_account.allows_destination(dest_account_info.key) {
+                        return TransferCheck::NotWhitelisted.into_result();
+                    }
+                    if actual_source_account.is_frozen() {
+                        return TransferCheck::AccountFrozen.into_result();
+                    }
+                    if delegate.expiry_slot != 0 {
+                        let clock_account_info = next_account_info(account_info_iter)?;
+                        if Self::clock_slot(clock_account_info)? > delegate.expiry_slot {
+                            info!("Error: delegate expired");
+                            return Err(TokenError::DelegateExpired.into());
+                        }
+                    }
+                    actual_source_account.amount = actual_source_account
+                        .amount
+                        .checked_sub(amount)
+                        .ok_or(TokenError::InsufficientFunds)?;
+                    actual_source = Some((actual_source_data, actual_source_account));
+                } else {
+                    info!("Error: payee is an invalid account");
+                    return Err(ProgramError::InvalidArgument);
                 }
-                output[0] = 2;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut TokenAccount) };
-                *value = *account;
             }
-            Self::Invalid => output[0] = 3,
+
+            if token.transfer_fee_basis_points != 0 {
+                let fee_collector_account_info = Self::next_fee_collector(
+                    &token,
+                    token_account_info,
+                    source_account_info,
+                    &source_account,
+                    account_info_iter,
+                )?;
+                fee_credit = Some((fee_collector_account_info, fee));
+            }
+
+            if let Some((mut actual_source_data, actual_source_account)) = actual_source {
+                State::Account(actual_source_account).serialize(&mut actual_source_data)?;
+            }
+            source_account.amount = source_account
+                .amount
+                .checked_sub(amount)
+                .ok_or(TokenError::InsufficientFunds)?;
+            State::Account(source_account).serialize(&mut source_data)?;
+
+            dest_account.amount = dest_amount;
+            State::Account(dest_account).serialize(&mut dest_data)?;
+            hook.on_transfer(source_account_info.key, dest_account_info.key, amount - fee);
+        } else {
+            info!("Error: destination and/or source accounts are invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // The collector may be the source or destination, so it is credited once both are
+        // written and released
+        drop(source_data);
+        drop(dest_data);
+        if let Some((fee_collector_account_info, fee)) = fee_credit {
+            State::with_account_mut(
+                &mut fee_collector_account_info.data.borrow_mut(),
+                |fee_collector_account| {
+                    fee_collector_account.amount = fee_collector_account
+                        .amount
+                        .checked_add(fee)
+                        .ok_or(TokenError::Overflow)?;
+                    Ok(())
+                },
+            )?;
+            hook.on_transfer(source_account_info.key, fee_collector_account_info.key, fee);
         }
         Ok(())
     }
-}
 
-impl Command {
-    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() < size_of::<u8>() {
-            return Err(ProgramError::InvalidAccountData);
+    /// Pulls the token's `fee_collector` from `account_info_iter`, failing with
+    /// `InvalidArgument` if it is missing or can't be credited.  `source_account` is key 1's
+    /// state, needed if the collector is key 1 itself
+    fn next_fee_collector<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        token: &Token,
+        token_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        source_account: &TokenAccount,
+        account_info_iter: &mut I,
+    ) -> Result<&'a AccountInfo<'a>, ProgramError> {
+        let fee_collector_account_info = next_account_info(account_info_iter).map_err(|_| {
+            info!("Error: fee collector account missing");
+            ProgramError::InvalidArgument
+        })?;
+        if fee_collector_account_info.key != &token.fee_collector {
+            info!("Error: not the token's fee collector");
+            return Err(ProgramError::InvalidArgument);
         }
-        Ok(match input[0] {
-            0 => {
-                if input.len() < size_of::<u8>() + size_of::<Token>() {
-                    return Err(ProgramError::InvalidAccountData);
+        // Already borrowed if it is one of the transfer's own accounts, all of which are
+        // accounts of the token and only key 1 possibly a delegate
+        let is_delegate = match fee_collector_account_info.data.try_borrow() {
+            Ok(data) => match State::deserialize(&data)? {
+                State::Account(ref account) if account.token == *token_account_info.key => {
+                    account.delegate.is_some()
                 }
-                #[allow(clippy::cast_ptr_alignment)]
-                let token: &Token = unsafe { &*(&input[1] as *const u8 as *const Token) };
-                Self::NewToken(*token)
-            }
-            1 => Self::NewTokenAccount,
-            2 => {
-                if input.len() < size_of::<u8>() + size_of::<u64>() {
-                    return Err(ProgramError::InvalidAccountData);
+                _ => {
+                    info!("Error: fee collector is not an account of the token");
+                    return Err(ProgramError::InvalidArgument);
                 }
-                #[allow(clippy::cast_ptr_alignment)]
-                let amount: &u64 = unsafe { &*(&input[1] as *const u8 as *const u64) };
-                Self::Transfer(*amount)
+            },
+            Err(_) => {
+                fee_collector_account_info.key == source_account_info.key
+                    && source_account.delegate.is_some()
             }
-            3 => {
-                if input.len() < size_of::<u8>() + size_of::<u64>() {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                #[allow(clippy::cast_ptr_alignment)]
-                let amount: &u64 = unsafe { &*(&input[1] as *const u8 as *const u64) };
-                Self::Approve(*amount)
+        };
+        if is_delegate {
+            info!("Error: fee collector is a delegate");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(fee_collector_account_info)
+    }
+
+    /// Fails for a token that charges a transfer fee, which commands that move balances
+    /// without a fee collector account can't withhold
+    fn require_no_transfer_fee(token_account_info: &AccountInfo<'a>) -> ProgramResult {
+        if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+            if token.transfer_fee_basis_points != 0 {
+                info!("Error: token charges a transfer fee");
+                return Err(ProgramError::InvalidArgument);
             }
-            4 => Self::SetOwner,
-            _ => return Err(ProgramError::InvalidAccountData),
-        })
+            Ok(())
+        } else {
+            info!("Error: token account is invalid");
+            Err(ProgramError::InvalidArgument)
+        }
     }
 
-    pub fn serialize(self: &Self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < size_of::<u8>() {
-            return Err(ProgramError::InvalidAccountData);
+    /// Evaluates every precondition of moving `amount` from `source_account` to `dest_account`
+    /// other than the owner's signature, without modifying anything
+    fn check_transfer(
+        source_account: &TokenAccount,
+        dest_account: &TokenAccount,
+        dest_key: &Pubkey,
+        token_account_info: &AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<TransferCheck, ProgramError> {
+        if source_account.token != dest_account.token
+            || token_account_info.key != &source_account.token
+        {
+            return Ok(TransferCheck::TokenMismatch);
         }
-        match self {
-            Self::NewToken(token) => {
-                if output.len() < size_of::<u8>() + size_of::<Token>() {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                output[0] = 0;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut Token) };
-                *value = *token;
+        if dest_account.delegate.is_some() {
+            return Ok(TransferCheck::DestinationIsDelegate);
+        }
+        if !source_account.allows_destination(dest_key) {
+            return Ok(TransferCheck::NotWhitelisted);
+        }
+        if source_account.is_frozen() || dest_account.is_frozen() {
+            return Ok(TransferCheck::AccountFrozen);
+        }
+        if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+            if token.paused {
+                return Ok(TransferCheck::MintPaused);
             }
-            Self::NewTokenAccount => output[0] = 1,
-            Self::Transfer(amount) => {
-                if output.len() < size_of::<u8>() + size_of::<u64>() {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                output[0] = 2;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *amount;
+            if token.non_transferable {
+                return Ok(TransferCheck::NonTransferable);
             }
-            Self::Approve(amount) => {
-                if output.len() < size_of::<u8>() + size_of::<u64>() {
-                    return Err(ProgramError::InvalidAccountData);
+            if token.max_transfer != 0 && amount > token.max_transfer {
+                return Ok(TransferCheck::TransferTooLarge);
+            }
+        } else {
+            info!("Error: token account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if source_account.amount < amount {
+            return Ok(TransferCheck::InsufficientFunds);
+        }
+        Ok(TransferCheck::Allowed)
+    }
+
+    pub fn process_checktransfer<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        amount: u64,
+    ) -> ProgramResult {
+        let source_account_info = next_account_info_at(account_info_iter, 0, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 2, "token")?;
+        let result_account_info = next_account_info_at(account_info_iter, 3, "result")?;
+
+        let result = if let (State::Account(source_account), State::Account(dest_account)) = (
+            State::deserialize(&source_account_info.data.borrow())?,
+            State::deserialize(&dest_account_info.data.borrow())?,
+        ) {
+            let mut result = Self::check_transfer(
+                &source_account,
+                &dest_account,
+                dest_account_info.key,
+                token_account_info,
+                amount,
+            )?;
+            if let (TransferCheck::Allowed, Some(delegate)) = (result, source_account.delegate) {
+                let actual_source_account_info =
+                    next_account_info_at(account_info_iter, 4, "delegate source")?;
+                if actual_source_account_info.key != &delegate.source {
+                    result = TransferCheck::NotDelegate;
+                } else if let State::Account(actual_source_account) =
+                    State::deserialize(&actual_source_account_info.data.borrow())?
+                {
+                    if !actual_source_account.allows_destination(dest_account_info.key) {
+                        result = TransferCheck::NotWhitelisted;
+                    } else if actual_source_account.is_frozen() {
+                        result = TransferCheck::AccountFrozen;
+                    } else if actual_source_account.amount < amount {
+                        result = TransferCheck::InsufficientFunds;
+                    }
+                } else {
+                    info!("Error: payee is an invalid account");
+                    return Err(ProgramError::InvalidArgument);
                 }
-                output[0] = 3;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *amount;
             }
-            Self::SetOwner => output[0] = 4,
+            result
+        } else {
+            info!("Error: destination and/or source accounts are invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+
+        let mut result_data = result_account_info.data.borrow_mut();
+        if result_data.len() < size_of::<u8>() {
+            info!("Error: result account is too small");
+            return Err(ProgramError::InvalidAccountData);
         }
+        result_data[0] = result as u8;
         Ok(())
     }
-}
 
-// Pulls in the stubs required for `info!()`
-#[cfg(not(target_arch = "bpf"))]
-solana_sdk_bpf_test::stubs!();
+    pub fn process_checkpeg<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let reserve_account_info = next_account_info_at(account_info_iter, 1, "reserve")?;
+        let result_account_info = next_account_info_at(account_info_iter, 2, "result")?;
+        let reserve_token_account_info =
+            next_account_info_at(account_info_iter, 3, "reserve token")?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use solana_sdk::{account::Account, account_info::create_is_signer_account_infos};
+        let token =
+            if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+                token
+            } else {
+                info!("Error: token account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        if reserve_token_account_info.key == token_account_info.key {
+            info!("Error: a token can't be its own reserve");
+            return Err(ProgramError::InvalidArgument);
+        }
+        // Another program's data could merely look like a token account
+        if reserve_account_info.owner != program_id {
+            info!("Error: reserve not owned by this program");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let reserve = if let State::Account(reserve_account) =
+            State::deserialize(&reserve_account_info.data.borrow())?
+        {
+            if reserve_account.token != *reserve_token_account_info.key {
+                info!("Error: reserve holds another token");
+                return Err(TokenError::TokenMismatch.into());
+            }
+            reserve_account.amount
+        } else {
+            info!("Error: reserve is not a token account");
+            return Err(ProgramError::InvalidArgument);
+        };
+        let required = u128::from(token.supply) * u128::from(token.peg_ratio);
 
-    fn new_pubkey(id: u8) -> Pubkey {
-        Pubkey::new(&vec![
-            id, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-            1, 1, 1,
-        ])
+        let mut result_data = result_account_info.data.borrow_mut();
+        if result_data.len() < size_of::<u8>() {
+            info!("Error: result account is too small");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        result_data[0] = (u128::from(reserve) >= required) as u8;
+        Ok(())
+    }
+
+    pub fn process_batchbalance<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let result_account_info = next_account_info_at(account_info_iter, 0, "result")?;
+
+        let mut result_data = result_account_info.data.borrow_mut();
+        for (i, account_info) in account_info_iter.enumerate() {
+            let amount = match State::deserialize(&account_info.data.borrow()) {
+                Ok(State::Account(account)) => account.amount,
+                _ => 0,
+            };
+            let offset = i * size_of::<u64>();
+            if result_data.len() < offset + size_of::<u64>() {
+                info!("Error: result account is too small");
+                return Err(ProgramError::InvalidAccountData);
+            }
+            result_data[offset..offset + size_of::<u64>()].copy_from_slice(&amount.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    pub fn process_newnonce<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        value: u64,
+    ) -> ProgramResult {
+        let nonce_account_info = next_account_info_at(account_info_iter, 0, "nonce")?;
+        State::assert_capacity(&nonce_account_info.data.borrow(), State::NONCE_LEN)?;
+
+        if !nonce_account_info.is_signer {
+            info!("Error: nonce account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut nonce_data = nonce_account_info.data.borrow_mut();
+        if State::Unallocated != State::deserialize(&nonce_data)? {
+            info!("Error: nonce account is already allocated");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        State::Nonce {
+            value,
+            consumed: false,
+        }
+        .serialize(&mut nonce_data)
+    }
+
+    pub fn process_newmultisig<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        m: u8,
+    ) -> ProgramResult {
+        let multisig_account_info = next_account_info_at(account_info_iter, 0, "multisig")?;
+        State::assert_capacity(&multisig_account_info.data.borrow(), State::MULTISIG_LEN)?;
+
+        if !multisig_account_info.is_signer {
+            info!("Error: multisig account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut signers = [Pubkey::default(); MAX_SIGNERS];
+        let mut n = 0;
+        for (i, signer_account_info) in account_info_iter.enumerate() {
+            if i >= MAX_SIGNERS {
+                info!("Error: too many multisig signers");
+                return Err(ProgramError::InvalidArgument);
+            }
+            if *signer_account_info.key == Pubkey::default()
+                || signers[..i].contains(signer_account_info.key)
+            {
+                info!("Error: multisig signer invalid or repeated");
+                return Err(ProgramError::InvalidArgument);
+            }
+            signers[i] = *signer_account_info.key;
+            n = i + 1;
+        }
+        if m == 0 || usize::from(m) > n {
+            info!("Error: multisig threshold out of range");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut multisig_data = multisig_account_info.data.borrow_mut();
+        if State::Unallocated != State::deserialize(&multisig_data)? {
+            info!("Error: multisig account is already allocated");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        State::Multisig { m, signers }.serialize(&mut multisig_data)
+    }
+
+    pub fn process_consumenonce<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        value: u64,
+    ) -> ProgramResult {
+        let nonce_account_info = next_account_info_at(account_info_iter, 0, "nonce")?;
+
+        if !nonce_account_info.is_signer {
+            info!("Error: nonce account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut nonce_data = nonce_account_info.data.borrow_mut();
+        if let State::Nonce {
+            value: expected,
+            consumed,
+        } = State::deserialize(&nonce_data)?
+        {
+            if consumed {
+                info!("Error: nonce already consumed");
+                return Err(TokenError::NonceConsumed.into());
+            }
+            if value != expected {
+                info!("Error: nonce mismatch");
+                return Err(ProgramError::InvalidArgument);
+            }
+            State::Nonce {
+                value,
+                consumed: true,
+            }
+            .serialize(&mut nonce_data)
+        } else {
+            info!("Error: nonce account is invalid");
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+
+    pub fn process_syncmirror<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let mirror_account_info = next_account_info_at(account_info_iter, 0, "mirror")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let clock_account_info = next_account_info_at(account_info_iter, 2, "clock")?;
+        State::assert_capacity(&mirror_account_info.data.borrow(), State::MIRROR_LEN)?;
+
+        let mut mirror_data = mirror_account_info.data.borrow_mut();
+        match State::deserialize(&mirror_data)? {
+            State::Unallocated => {
+                if !mirror_account_info.is_signer {
+                    info!("Error: mirror account not a signer");
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+            }
+            State::Mirror { source, .. } => {
+                if source != *source_account_info.key {
+                    info!("Error: mirror tracks another account");
+                    return Err(ProgramError::InvalidArgument);
+                }
+            }
+            _ => {
+                info!("Error: mirror account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        let amount = if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            source_account.amount
+        } else {
+            info!("Error: source account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+
+        State::Mirror {
+            source: *source_account_info.key,
+            amount,
+            slot: Self::clock_slot(clock_account_info)?,
+        }
+        .serialize(&mut mirror_data)
+    }
+
+    /// Reads the current slot from the clock sysvar account
+    fn clock_slot(clock_account_info: &AccountInfo<'a>) -> Result<u64, ProgramError> {
+        // The clock's slot is its first field, a little-endian u64
+        let clock_data = clock_account_info.data.borrow();
+        if !sysvar::clock::check_id(clock_account_info.key) || clock_data.len() < size_of::<u64>() {
+            info!("Error: not the clock sysvar");
+            return Err(ProgramError::InvalidArgument);
+        }
+        read_u64(&clock_data, 0)
+    }
+
+    pub fn process_programinfo<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let result_account_info = next_account_info_at(account_info_iter, 0, "result")?;
+
+        let mut result_data = result_account_info.data.borrow_mut();
+        if result_data.len() < size_of::<u8>() + size_of::<u32>() {
+            info!("Error: result account is too small");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        result_data[0] = PROGRAM_VERSION;
+        result_data[1..1 + size_of::<u32>()].copy_from_slice(&feature_flags().to_le_bytes());
+        Ok(())
+    }
+
+    pub fn process_getaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let account_info = next_account_info_at(account_info_iter, 0, "account")?;
+        let result_account_info = next_account_info_at(account_info_iter, 1, "result")?;
+
+        let data = account_info.data.borrow();
+        if let State::Account(_) = State::deserialize(&data)? {
+            let len = Self::ACCOUNT_LEN;
+            let mut result_data = result_account_info.data.borrow_mut();
+            if result_data.len() < len {
+                info!("Error: result account is too small");
+                return Err(ProgramError::InvalidAccountData);
+            }
+            result_data[..len].copy_from_slice(&data[..len]);
+            Ok(())
+        } else {
+            info!("Error: not an account");
+            Err(TokenError::NotAnAccount.into())
+        }
+    }
+
+    pub fn process_getauthorities<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let result_account_info = next_account_info_at(account_info_iter, 1, "result")?;
+
+        let token =
+            if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+                token
+            } else {
+                info!("Error: token account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        let mint_authority = if token.mint_authority == Pubkey::default() {
+            *token_account_info.key
+        } else {
+            token.mint_authority
+        };
+
+        let mut result_data = result_account_info.data.borrow_mut();
+        if result_data.len() < 2 * size_of::<Pubkey>() + size_of::<u8>() {
+            info!("Error: result account is too small");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        write_pubkey(&mut result_data, 0, &mint_authority)?;
+        result_data[size_of::<Pubkey>()] = token.freeze_authority.is_some() as u8;
+        write_pubkey(
+            &mut result_data,
+            size_of::<Pubkey>() + size_of::<u8>(),
+            &token.freeze_authority.unwrap_or_default(),
+        )
+    }
+
+    pub fn process_getinfo<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let account_info = next_account_info_at(account_info_iter, 0, "account")?;
+
+        if let State::Account(account) = State::deserialize(&account_info.data.borrow())? {
+            info!("Token:");
+            account.token.log();
+            info!("Owner:");
+            account.owner.log();
+            info!("Amount, is delegate:");
+            info!(account.amount, account.delegate.is_some(), 0, 0, 0);
+            if let Some(delegate) = account.delegate {
+                info!("Delegate source:");
+                delegate.source.log();
+            }
+            Ok(())
+        } else {
+            info!("Error: not an account");
+            Err(TokenError::NotAnAccount.into())
+        }
+    }
+
+    pub fn process_approve<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        Self::require_nonzero(amount)?;
+        Self::require_token_unpaused(source_account_info, token_account_info)?;
+        Self::approve(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+            amount,
+            0,
+            false,
+            hook,
+        )
+    }
+
+    pub fn process_approvewithexpiry<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        expiry_slot: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        Self::require_nonzero(amount)?;
+        Self::require_token_unpaused(source_account_info, token_account_info)?;
+        Self::approve(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+            amount,
+            expiry_slot,
+            false,
+            hook,
+        )
+    }
+
+    pub fn process_approveandtransfer<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        approve: u64,
+        transfer: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 3, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 4, "token")?;
+
+        Self::require_nonzero(approve)?;
+        Self::require_token_unpaused(source_account_info, token_account_info)?;
+        Self::approve(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+            approve,
+            0,
+            false,
+            hook,
+        )?;
+
+        let transfer = Self::resolve_transfer_all(delegate_account_info, transfer)?;
+        Self::require_nonzero(transfer)?;
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            delegate_account_info,
+            dest_account_info,
+            token_account_info,
+            &mut std::iter::once(source_account_info).chain(account_info_iter),
+            transfer,
+            hook,
+        )
+    }
+
+    pub fn process_approveifunused<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        Self::require_nonzero(amount)?;
+        Self::require_token_unpaused(source_account_info, token_account_info)?;
+        Self::approve(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+            amount,
+            0,
+            true,
+            hook,
+        )
+    }
+
+    pub fn process_approvechecked<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        decimals: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        Self::require_nonzero(amount)?;
+        Self::require_decimals(token_account_info, decimals)?;
+        Self::require_token_unpaused(source_account_info, token_account_info)?;
+        Self::approve(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+            amount,
+            0,
+            false,
+            hook,
+        )
+    }
+
+    /// Checks that the token is the source account's and is not paused
+    fn require_token_unpaused(
+        source_account_info: &AccountInfo<'a>,
+        token_account_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            if source_account.token != *token_account_info.key {
+                info!("Error: token mismatch");
+                return Err(TokenError::TokenMismatch.into());
+            }
+        }
+        if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+            if token.paused {
+                info!("Error: token is paused");
+                return Err(TokenError::MintPaused.into());
+            }
+            Ok(())
+        } else {
+            info!("Error: token account is invalid");
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+
+    /// Sets a delegate's allowance to `amount`, expiring after `expiry_slot` unless it is 0,
+    /// and refusing to replace a remaining allowance if `only_if_unused` is set
+    fn approve<H: AuditHook>(
+        owner_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        delegate_account_info: &AccountInfo<'a>,
+        amount: u64,
+        expiry_slot: u64,
+        only_if_unused: bool,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let delegate_account = Self::check_delegate_of(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+        )?;
+        if only_if_unused && delegate_account.amount != 0 {
+            info!("Error: delegate has a remaining allowance");
+            return Err(TokenError::DelegateBusy.into());
+        }
+
+        State::set_delegate_allowance_in_place(
+            &mut delegate_account_info.data.borrow_mut(),
+            amount,
+            expiry_slot,
+        )?;
+        hook.on_approve(source_account_info.key, delegate_account_info.key, amount);
+        Ok(())
+    }
+
+    /// Verifies that the delegate account is a delegate of the source account and that the
+    /// source's owner signed, returning the delegate account
+    fn check_delegate_of(
+        owner_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        delegate_account_info: &AccountInfo<'a>,
+    ) -> Result<TokenAccount, ProgramError> {
+        let source_account = if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            source_account
+        } else {
+            info!("Error: source account is not an Account");
+            return Err(ProgramError::InvalidArgument);
+        };
+
+        let delegate_account = if let State::Account(delegate_account) =
+            State::deserialize(&delegate_account_info.data.borrow())?
+        {
+            delegate_account
+        } else {
+            info!("Error: account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+        if source_account.token != delegate_account.token {
+            info!("Error: token mismatch");
+            return Err(TokenError::TokenMismatch.into());
+        }
+        Self::require_owner(owner_account_info, &source_account.owner)?;
+        if source_account.delegate.is_some() {
+            info!("Error: source account is a delegate");
+            return Err(ProgramError::InvalidArgument);
+        }
+        match &delegate_account.delegate {
+            None => {
+                info!("Error: delegate account is not a delegate");
+                return Err(TokenError::NotDelegate.into());
+            }
+            Some(delegate) => {
+                if source_account_info.key != &delegate.source {
+                    info!("Error: delegate account is not a delegate of the source account");
+                    return Err(TokenError::NotDelegate.into());
+                }
+            }
+        }
+        Ok(delegate_account)
+    }
+
+    pub fn process_revoke<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let delegate_account_info = next_account_info_at(account_info_iter, 2, "delegate")?;
+
+        Self::check_delegate_of(
+            owner_account_info,
+            source_account_info,
+            delegate_account_info,
+        )?;
+        State::with_account_mut(
+            &mut delegate_account_info.data.borrow_mut(),
+            |delegate_account| {
+                delegate_account.amount = 0;
+                Ok(())
+            },
+        )?;
+        hook.on_approve(source_account_info.key, delegate_account_info.key, 0);
+        Ok(())
+    }
+
+    pub fn process_setowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        let new_owner_account_info = next_account_info_at(account_info_iter, 2, "new owner")?;
+
+        State::with_account_mut(&mut dest_account_info.data.borrow_mut(), |dest_account| {
+            Self::require_owner_or_multisig(
+                program_id,
+                owner_account_info,
+                &dest_account.owner,
+                account_info_iter,
+            )?;
+            dest_account.check_owner_mutable()?;
+
+            // Nobody can sign for the zero key, so only an empty account may be abandoned to it
+            if *new_owner_account_info.key == Pubkey::default() && dest_account.amount != 0 {
+                info!("Error: cannot assign a funded account to the zero key");
+                return Err(ProgramError::InvalidArgument);
+            }
+            dest_account.owner = *new_owner_account_info.key;
+            Ok(())
+        })
+    }
+
+    pub fn process_setconfidential<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        commitment: [u8; 32],
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+
+        State::with_account_mut(&mut dest_account_info.data.borrow_mut(), |dest_account| {
+            Self::require_owner(owner_account_info, &dest_account.owner)?;
+
+            dest_account.confidential = true;
+            dest_account.commitment = commitment;
+            Ok(())
+        })
+    }
+
+    pub fn process_settransferauthority<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        let transfer_authority = account_info_iter.next().map(|info| *info.key);
+
+        State::with_account_mut(&mut dest_account_info.data.borrow_mut(), |dest_account| {
+            Self::require_owner(owner_account_info, &dest_account.owner)?;
+
+            dest_account.transfer_authority = transfer_authority;
+            Ok(())
+        })
+    }
+
+    pub fn process_setwhitelist<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+
+        let mut whitelist = [Pubkey::default(); MAX_WHITELIST];
+        for (i, allowed_account_info) in account_info_iter.enumerate() {
+            if i >= MAX_WHITELIST {
+                info!("Error: too many whitelisted destinations");
+                return Err(ProgramError::InvalidArgument);
+            }
+            whitelist[i] = *allowed_account_info.key;
+        }
+
+        State::with_account_mut(&mut dest_account_info.data.borrow_mut(), |dest_account| {
+            Self::require_owner(owner_account_info, &dest_account.owner)?;
+
+            dest_account.whitelist = whitelist;
+            Ok(())
+        })
+    }
+
+    pub fn process_rotateowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 1, "destination")?;
+        let new_owner_account_info = next_account_info_at(account_info_iter, 2, "new owner")?;
+
+        // Check every delegate before modifying anything
+        let mut delegates = vec![];
+        for delegate_account_info in account_info_iter {
+            match State::deserialize(&delegate_account_info.data.borrow())? {
+                State::Account(TokenAccount {
+                    delegate: Some(delegate),
+                    ..
+                }) if delegate.source == *dest_account_info.key => {}
+                _ => {
+                    info!("Error: delegate account is not a delegate of the token account");
+                    return Err(TokenError::NotDelegate.into());
+                }
+            }
+            delegates.push(delegate_account_info);
+        }
+
+        State::with_account_mut(&mut dest_account_info.data.borrow_mut(), |dest_account| {
+            Self::require_owner(owner_account_info, &dest_account.owner)?;
+            dest_account.check_owner_mutable()?;
+
+            dest_account.owner = *new_owner_account_info.key;
+            Ok(())
+        })?;
+        for delegate_account_info in delegates {
+            State::with_account_mut(
+                &mut delegate_account_info.data.borrow_mut(),
+                |delegate_account| {
+                    delegate_account.amount = 0;
+                    if let Some(ref mut delegate) = delegate_account.delegate {
+                        delegate.original_amount = 0;
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn process_transferifbelow<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        target: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        let amount = if let (State::Account(source_account), State::Account(dest_account)) = (
+            State::deserialize(&source_account_info.data.borrow())?,
+            State::deserialize(&dest_account_info.data.borrow())?,
+        ) {
+            if dest_account.amount >= target {
+                info!("Destination account already at or above target");
+                return Ok(());
+            }
+            std::cmp::min(target - dest_account.amount, source_account.amount)
+        } else {
+            info!("Error: destination and/or source accounts are invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )
+    }
+
+    pub fn process_transferifprice<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        min_price: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+        let oracle_account_info = next_account_info_at(account_info_iter, 4, "oracle")?;
+
+        let mut price = [0u8; 8];
+        {
+            let oracle_data = oracle_account_info.data.borrow();
+            if oracle_data.len() < size_of::<u64>() {
+                info!("Error: oracle account is too small");
+                return Err(ProgramError::InvalidAccountData);
+            }
+            price.copy_from_slice(&oracle_data[..size_of::<u64>()]);
+        }
+        if u64::from_le_bytes(price) < min_price {
+            info!("Error: oracle price below minimum");
+            return Err(TokenError::PriceConditionNotMet.into());
+        }
+
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )
+    }
+
+    pub fn process_transferifholds<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        min_holding: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        if let State::Account(dest_account) = State::deserialize(&dest_account_info.data.borrow())?
+        {
+            if dest_account.amount < min_holding {
+                info!("Error: destination holds less than the minimum");
+                return Err(TokenError::HoldingConditionNotMet.into());
+            }
+        } else {
+            info!("Error: destination account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )
+    }
+
+    pub fn process_transferwithinvariant<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        expected_total: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )?;
+
+        let mut total = Some(0u64);
+        for account_info in account_info_iter {
+            if let State::Account(account) = State::deserialize(&account_info.data.borrow())? {
+                if account.token != *token_account_info.key {
+                    info!("Error: token mismatch");
+                    return Err(TokenError::TokenMismatch.into());
+                }
+                total = total.and_then(|total| total.checked_add(account.amount));
+            } else {
+                info!("Error: invariant account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+        if total != Some(expected_total) {
+            info!("Error: invariant violated");
+            return Err(TokenError::InvariantViolated.into());
+        }
+        Ok(())
+    }
+
+    pub fn process_distribute<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let token_account_info = next_account_info_at(account_info_iter, 2, "token")?;
+        let dest_account_infos: Vec<&'a AccountInfo<'a>> = account_info_iter.collect();
+        if dest_account_infos.is_empty() {
+            info!("Error: no destination accounts");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Self::distribute(
+            owner_account_info,
+            source_account_info,
+            token_account_info,
+            &dest_account_infos,
+            &vec![amount; dest_account_infos.len()],
+            hook,
+        )
+    }
+
+    pub fn process_batchtransfer<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amounts: &[u64],
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let token_account_info = next_account_info_at(account_info_iter, 2, "token")?;
+        let dest_account_infos: Vec<&'a AccountInfo<'a>> = account_info_iter.collect();
+        if dest_account_infos.is_empty() || dest_account_infos.len() != amounts.len() {
+            info!("Error: expected one destination account per amount");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Self::distribute(
+            owner_account_info,
+            source_account_info,
+            token_account_info,
+            &dest_account_infos,
+            amounts,
+            hook,
+        )
+    }
+
+    /// Transfers `amounts[i]` to each `dest_account_infos[i]`, debiting their total from the
+    /// source once.  Nothing is written unless every destination can be credited
+    fn distribute<H: AuditHook>(
+        owner_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        token_account_info: &AccountInfo<'a>,
+        dest_account_infos: &[&'a AccountInfo<'a>],
+        amounts: &[u64],
+        hook: &mut H,
+    ) -> ProgramResult {
+        let mut source_data = source_account_info.data.borrow_mut();
+        let mut source_account =
+            if let State::Account(source_account) = State::deserialize(&source_data)? {
+                source_account
+            } else {
+                info!("Error: source account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        Self::require_owner(owner_account_info, &source_account.owner)?;
+        if source_account.delegate.is_some() {
+            info!("Error: source account is a delegate");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Self::require_no_transfer_fee(token_account_info)?;
+        let total = amounts
+            .iter()
+            .try_fold(0u64, |total, amount| total.checked_add(*amount))
+            .ok_or(TokenError::Overflow)?;
+        if source_account.amount < total {
+            return TransferCheck::InsufficientFunds.into_result();
+        }
+
+        // Validate every destination before crediting any of them
+        let mut dest_accounts = Vec::with_capacity(dest_account_infos.len());
+        for (i, (dest_account_info, amount)) in dest_account_infos.iter().zip(amounts).enumerate() {
+            if dest_account_info.key == source_account_info.key
+                || dest_account_infos[..i]
+                    .iter()
+                    .any(|other| other.key == dest_account_info.key)
+            {
+                info!("Error: destination account repeated");
+                return Err(ProgramError::InvalidArgument);
+            }
+            if let State::Account(mut dest_account) =
+                State::deserialize(&dest_account_info.data.borrow())?
+            {
+                Self::check_transfer(
+                    &source_account,
+                    &dest_account,
+                    dest_account_info.key,
+                    token_account_info,
+                    *amount,
+                )?
+                .into_result()?;
+                dest_account.amount = dest_account
+                    .amount
+                    .checked_add(*amount)
+                    .ok_or(TokenError::Overflow)?;
+                dest_accounts.push(dest_account);
+            } else {
+                info!("Error: destination account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        source_account.amount = source_account
+            .amount
+            .checked_sub(total)
+            .ok_or(TokenError::InsufficientFunds)?;
+        State::Account(source_account).serialize(&mut source_data)?;
+        for ((dest_account_info, dest_account), amount) in
+            dest_account_infos.iter().zip(dest_accounts).zip(amounts)
+        {
+            State::Account(dest_account).serialize(&mut dest_account_info.data.borrow_mut())?;
+            hook.on_transfer(source_account_info.key, dest_account_info.key, *amount);
+        }
+        Ok(())
+    }
+
+    pub fn process_burn<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let token_account_info = next_account_info_at(account_info_iter, 2, "token")?;
+
+        let mut source_data = source_account_info.data.borrow_mut();
+        let mut source_account =
+            if let State::Account(source_account) = State::deserialize(&source_data)? {
+                source_account
+            } else {
+                info!("Error: source account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        if token_account_info.key != &source_account.token {
+            info!("Error: token mismatch");
+            return Err(TokenError::TokenMismatch.into());
+        }
+        Self::require_owner(owner_account_info, &source_account.owner)?;
+        if source_account.delegate.is_some() {
+            info!("Error: cannot burn from a delegate account");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if source_account.amount < amount {
+            return Err(TokenError::InsufficientFunds.into());
+        }
+
+        let mut token_data = token_account_info.data.borrow_mut();
+        let mut token = if let State::Token(token) = State::deserialize(&token_data)? {
+            token
+        } else {
+            info!("Error: token account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+        token.supply = token
+            .supply
+            .checked_sub(amount)
+            .ok_or(TokenError::InsufficientFunds)?;
+        token.total_burned = token
+            .total_burned
+            .checked_add(amount)
+            .ok_or(TokenError::Overflow)?;
+        token.check_supply()?;
+
+        source_account.amount = source_account
+            .amount
+            .checked_sub(amount)
+            .ok_or(TokenError::InsufficientFunds)?;
+        State::Account(source_account).serialize(&mut source_data)?;
+        State::Token(token).serialize(&mut token_data)?;
+        hook.on_burn(token_account_info.key, source_account_info.key, amount);
+        Ok(())
+    }
+
+    pub fn process_moveaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let new_account_info = next_account_info_at(account_info_iter, 2, "new account")?;
+        State::assert_capacity(&new_account_info.data.borrow(), State::ACCOUNT_LEN)?;
+
+        if source_account_info.key == new_account_info.key {
+            info!("Error: cannot move an account onto itself");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !new_account_info.is_signer {
+            info!("Error: new account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut source_data = source_account_info.data.borrow_mut();
+        let mut new_account_data = new_account_info.data.borrow_mut();
+        if let State::Account(source_account) = State::deserialize(&source_data)? {
+            Self::require_owner(owner_account_info, &source_account.owner)?;
+            if State::Unallocated != State::deserialize(&new_account_data)? {
+                info!("Error: new account is already allocated");
+                return Err(ProgramError::InvalidArgument);
+            }
+
+            State::Account(source_account).serialize(&mut new_account_data)?;
+            // Wipe the old account entirely so no stale balance or owner remains readable
+            for byte in source_data.iter_mut() {
+                *byte = 0;
+            }
+        } else {
+            info!("Error: source account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub fn process_transferandcloseifempty<
+        I: Iterator<Item = &'a AccountInfo<'a>>,
+        H: AuditHook,
+    >(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        amount: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+        let beneficiary_account_info = next_account_info_at(account_info_iter, 4, "beneficiary")?;
+
+        if source_account_info.key == beneficiary_account_info.key {
+            info!("Error: source account cannot be its own beneficiary");
+            return Err(ProgramError::InvalidArgument);
+        }
+        // A transfer authority may empty the account but only the owner may close it
+        if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            if source_account.amount == amount {
+                Self::require_owner(owner_account_info, &source_account.owner)?;
+            }
+        }
+
+        Self::transfer(
+            program_id,
+            owner_account_info,
+            source_account_info,
+            dest_account_info,
+            token_account_info,
+            account_info_iter,
+            amount,
+            hook,
+        )?;
+
+        if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            if source_account.amount != 0 {
+                return Ok(());
+            }
+        }
+        Self::close_account(source_account_info, beneficiary_account_info)
+    }
+
+    pub fn process_closeandtransfer<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        program_id: &Pubkey,
+        account_info_iter: &mut I,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let source_account_info = next_account_info_at(account_info_iter, 1, "source")?;
+        let dest_account_info = next_account_info_at(account_info_iter, 2, "destination")?;
+        let token_account_info = next_account_info_at(account_info_iter, 3, "token")?;
+        let beneficiary_account_info = next_account_info_at(account_info_iter, 4, "beneficiary")?;
+
+        // Everything that could stop the close is checked before the transfer writes anything
+        if source_account_info.key == beneficiary_account_info.key {
+            info!("Error: source account cannot be its own beneficiary");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if let State::Account(source_account) =
+            State::deserialize(&source_account_info.data.borrow())?
+        {
+            Self::require_owner(owner_account_info, &source_account.owner)?;
+        } else {
+            info!("Error: source account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        beneficiary_account_info
+            .lamports()
+            .checked_add(source_account_info.lamports())
+            .ok_or(TokenError::Overflow)?;
+
+        let amount = Self::resolve_transfer_all(source_account_info, u64::MAX)?;
+        if amount != 0 {
+            Self::transfer(
+                program_id,
+                owner_account_info,
+                source_account_info,
+                dest_account_info,
+                token_account_info,
+                account_info_iter,
+                amount,
+                hook,
+            )?;
+        }
+        Self::close_account(source_account_info, beneficiary_account_info)
+    }
+
+    pub fn process_closeaccount<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let owner_account_info = next_account_info_at(account_info_iter, 0, "owner")?;
+        let account_info = next_account_info_at(account_info_iter, 1, "account")?;
+        let beneficiary_account_info = next_account_info_at(account_info_iter, 2, "beneficiary")?;
+
+        if account_info.key == beneficiary_account_info.key {
+            info!("Error: cannot close an account into itself");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if let State::Account(account) = State::deserialize(&account_info.data.borrow())? {
+            Self::require_owner(owner_account_info, &account.owner)?;
+            if account.amount != 0 {
+                info!("Error: account is not empty");
+                return Err(ProgramError::InvalidArgument);
+            }
+        } else {
+            info!("Error: account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Self::close_account(account_info, beneficiary_account_info)
+    }
+
+    /// Zeroes the account's data and moves all of its lamports to the beneficiary
+    fn close_account(
+        account_info: &AccountInfo<'a>,
+        beneficiary_account_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        for byte in account_info.data.borrow_mut().iter_mut() {
+            *byte = 0;
+        }
+
+        let mut lamports = account_info.lamports.borrow_mut();
+        let mut beneficiary_lamports = beneficiary_account_info.lamports.borrow_mut();
+        **beneficiary_lamports = beneficiary_lamports
+            .checked_add(**lamports)
+            .ok_or(TokenError::Overflow)?;
+        **lamports = 0;
+        Ok(())
+    }
+
+    pub fn process_swap<I: Iterator<Item = &'a AccountInfo<'a>>, H: AuditHook>(
+        account_info_iter: &mut I,
+        amount_a: u64,
+        amount_b: u64,
+        hook: &mut H,
+    ) -> ProgramResult {
+        let owner_a_account_info = next_account_info_at(account_info_iter, 0, "owner a")?;
+        let source_a_account_info = next_account_info_at(account_info_iter, 1, "source a")?;
+        let dest_a_account_info = next_account_info_at(account_info_iter, 2, "destination a")?;
+        let owner_b_account_info = next_account_info_at(account_info_iter, 3, "owner b")?;
+        let source_b_account_info = next_account_info_at(account_info_iter, 4, "source b")?;
+        let dest_b_account_info = next_account_info_at(account_info_iter, 5, "destination b")?;
+        let token_x_account_info = next_account_info_at(account_info_iter, 6, "token x")?;
+        let token_y_account_info = next_account_info_at(account_info_iter, 7, "token y")?;
+
+        if token_x_account_info.key == token_y_account_info.key {
+            info!("Error: swap requires two different tokens");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if source_a_account_info.key == dest_b_account_info.key
+            || source_b_account_info.key == dest_a_account_info.key
+        {
+            info!("Error: swap source and destination are the same account");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Validate both legs before touching either side
+        let (mut source_a_account, mut dest_b_account) = Self::check_swap_leg(
+            owner_a_account_info,
+            source_a_account_info,
+            dest_b_account_info,
+            token_x_account_info,
+            amount_a,
+        )?;
+        let (mut source_b_account, mut dest_a_account) = Self::check_swap_leg(
+            owner_b_account_info,
+            source_b_account_info,
+            dest_a_account_info,
+            token_y_account_info,
+            amount_b,
+        )?;
+
+        source_a_account.amount = source_a_account
+            .amount
+            .checked_sub(amount_a)
+            .ok_or(TokenError::InsufficientFunds)?;
+        dest_b_account.amount = dest_b_account
+            .amount
+            .checked_add(amount_a)
+            .ok_or(TokenError::Overflow)?;
+        source_b_account.amount = source_b_account
+            .amount
+            .checked_sub(amount_b)
+            .ok_or(TokenError::InsufficientFunds)?;
+        dest_a_account.amount = dest_a_account
+            .amount
+            .checked_add(amount_b)
+            .ok_or(TokenError::Overflow)?;
+        State::Account(source_a_account).serialize(&mut source_a_account_info.data.borrow_mut())?;
+        State::Account(dest_b_account).serialize(&mut dest_b_account_info.data.borrow_mut())?;
+        State::Account(source_b_account).serialize(&mut source_b_account_info.data.borrow_mut())?;
+        State::Account(dest_a_account).serialize(&mut dest_a_account_info.data.borrow_mut())?;
+        hook.on_transfer(source_a_account_info.key, dest_b_account_info.key, amount_a);
+        hook.on_transfer(source_b_account_info.key, dest_a_account_info.key, amount_b);
+        Ok(())
+    }
+
+    /// Checks one side of a swap without modifying any account, returning the source and
+    /// destination accounts to update
+    fn check_swap_leg(
+        owner_account_info: &AccountInfo<'a>,
+        source_account_info: &AccountInfo<'a>,
+        dest_account_info: &AccountInfo<'a>,
+        token_account_info: &AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<(TokenAccount, TokenAccount), ProgramError> {
+        if let (State::Account(source_account), State::Account(dest_account)) = (
+            State::deserialize(&source_account_info.data.borrow())?,
+            State::deserialize(&dest_account_info.data.borrow())?,
+        ) {
+            if source_account.delegate.is_some() || dest_account.delegate.is_some() {
+                info!("Error: delegate accounts cannot take part in a swap");
+                return Err(ProgramError::InvalidArgument);
+            }
+            Self::require_owner(owner_account_info, &source_account.owner)?;
+            Self::check_transfer(
+                &source_account,
+                &dest_account,
+                dest_account_info.key,
+                token_account_info,
+                amount,
+            )?
+            .into_result()?;
+            Self::require_no_transfer_fee(token_account_info)?;
+            Ok((source_account, dest_account))
+        } else {
+            info!("Error: destination and/or source accounts are invalid");
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+
+    pub fn process_assertaccountowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        let account_info = next_account_info_at(account_info_iter, 0, "account")?;
+        let owner_account_info = next_account_info_at(account_info_iter, 1, "owner")?;
+
+        if let State::Account(account) = State::deserialize(&account_info.data.borrow())? {
+            if owner_account_info.key != &account.owner {
+                info!("Error: account owner does not match");
+                return Err(TokenError::NoOwner.into());
+            }
+        } else {
+            info!("Error: account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub fn process(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'a>],
+        input: &[u8],
+    ) -> ProgramResult {
+        Self::process_with_hook(program_id, accounts, input, &mut NoopAuditHook)
+    }
+
+    /// Processes an instruction, reporting its balance and allowance changes to `hook` only
+    /// once the whole instruction has succeeded
+    pub fn process_with_hook<H: AuditHook>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'a>],
+        input: &[u8],
+        audit_hook: &mut H,
+    ) -> ProgramResult {
+        let command = Command::deserialize(input)?;
+        let account_info_iter = &mut accounts.iter();
+        let mut events = BufferedAuditHook::default();
+        let hook = &mut events;
+
+        #[cfg(feature = "DEBUG_BALANCE_TRACE")]
+        Self::trace_balances(BalanceTraceStage::Pre, accounts);
+        let result = match command {
+            Command::NewToken(token_info) => {
+                info!("Command: NewToken");
+                Self::process_newtoken(account_info_iter, token_info, hook)
+            }
+            Command::NewTokenAccount => {
+                info!("Command: NewTokenAccount");
+                Self::process_newaccount(account_info_iter)
+            }
+            Command::Transfer(amount) => {
+                info!("Command: Transfer");
+                Self::process_transfer(program_id, account_info_iter, amount, hook)
+            }
+            Command::Approve(amount) => {
+                info!("Command: Approve");
+                Self::process_approve(account_info_iter, amount, hook)
+            }
+            Command::SetOwner => {
+                info!("Command: SetOwner");
+                Self::process_setowner(program_id, account_info_iter)
+            }
+            Command::AssertAccountOwner => {
+                info!("Command: AssertAccountOwner");
+                Self::process_assertaccountowner(account_info_iter)
+            }
+            Command::TransferIfBelow { target } => {
+                info!("Command: TransferIfBelow");
+                Self::process_transferifbelow(program_id, account_info_iter, target, hook)
+            }
+            Command::MoveAccount => {
+                info!("Command: MoveAccount");
+                Self::process_moveaccount(account_info_iter)
+            }
+            Command::TransferAndCloseIfEmpty { amount } => {
+                info!("Command: TransferAndCloseIfEmpty");
+                Self::process_transferandcloseifempty(program_id, account_info_iter, amount, hook)
+            }
+            Command::NewTokenDistributed(token_info, amounts) => {
+                info!("Command: NewTokenDistributed");
+                Self::process_newtokendistributed(account_info_iter, token_info, amounts, hook)
+            }
+            Command::Swap { amount_a, amount_b } => {
+                info!("Command: Swap");
+                Self::process_swap(account_info_iter, amount_a, amount_b, hook)
+            }
+            Command::CheckTransfer { amount } => {
+                info!("Command: CheckTransfer");
+                Self::process_checktransfer(account_info_iter, amount)
+            }
+            Command::CheckPeg => {
+                info!("Command: CheckPeg");
+                Self::process_checkpeg(program_id, account_info_iter)
+            }
+            Command::SetConfidential { commitment } => {
+                info!("Command: SetConfidential");
+                Self::process_setconfidential(account_info_iter, commitment)
+            }
+            Command::NewAccountAndApprove { allowance } => {
+                info!("Command: NewAccountAndApprove");
+                Self::process_newaccountandapprove(account_info_iter, allowance, hook)
+            }
+            Command::SetWhitelist => {
+                info!("Command: SetWhitelist");
+                Self::process_setwhitelist(account_info_iter)
+            }
+            Command::BatchBalance => {
+                info!("Command: BatchBalance");
+                Self::process_batchbalance(account_info_iter)
+            }
+            Command::NewNonce(value) => {
+                info!("Command: NewNonce");
+                Self::process_newnonce(account_info_iter, value)
+            }
+            Command::ConsumeNonce(value) => {
+                info!("Command: ConsumeNonce");
+                Self::process_consumenonce(account_info_iter, value)
+            }
+            Command::TransferIfPrice { amount, min_price } => {
+                info!("Command: TransferIfPrice");
+                Self::process_transferifprice(
+                    program_id,
+                    account_info_iter,
+                    amount,
+                    min_price,
+                    hook,
+                )
+            }
+            Command::TransferIfHolds {
+                amount,
+                min_holding,
+            } => {
+                info!("Command: TransferIfHolds");
+                Self::process_transferifholds(
+                    program_id,
+                    account_info_iter,
+                    amount,
+                    min_holding,
+                    hook,
+                )
+            }
+            Command::RotateOwner => {
+                info!("Command: RotateOwner");
+                Self::process_rotateowner(account_info_iter)
+            }
+            Command::ProgramInfo => {
+                info!("Command: ProgramInfo");
+                Self::process_programinfo(account_info_iter)
+            }
+            Command::TransferWithInvariant {
+                amount,
+                expected_total,
+            } => {
+                info!("Command: TransferWithInvariant");
+                Self::process_transferwithinvariant(
+                    program_id,
+                    account_info_iter,
+                    amount,
+                    expected_total,
+                    hook,
+                )
+            }
+            Command::MintToNew(amount) => {
+                info!("Command: MintToNew");
+                Self::process_minttonew(account_info_iter, amount, hook)
+            }
+            Command::Distribute(amount) => {
+                info!("Command: Distribute");
+                Self::process_distribute(account_info_iter, amount, hook)
+            }
+            Command::GetAccount => {
+                info!("Command: GetAccount");
+                Self::process_getaccount(account_info_iter)
+            }
+            Command::ApproveIfUnused(amount) => {
+                info!("Command: ApproveIfUnused");
+                Self::process_approveifunused(account_info_iter, amount, hook)
+            }
+            Command::SyncMirror => {
+                info!("Command: SyncMirror");
+                Self::process_syncmirror(account_info_iter)
+            }
+            Command::Burn(amount) => {
+                info!("Command: Burn");
+                Self::process_burn(account_info_iter, amount, hook)
+            }
+            Command::MintTo(amount) => {
+                info!("Command: MintTo");
+                Self::process_mintto(account_info_iter, amount, hook)
+            }
+            Command::CloseAccount => {
+                info!("Command: CloseAccount");
+                Self::process_closeaccount(account_info_iter)
+            }
+            Command::Revoke => {
+                info!("Command: Revoke");
+                Self::process_revoke(account_info_iter, hook)
+            }
+            Command::TransferChecked { amount, decimals } => {
+                info!("Command: TransferChecked");
+                Self::process_transferchecked(program_id, account_info_iter, amount, decimals, hook)
+            }
+            Command::NewMultisig(m) => {
+                info!("Command: NewMultisig");
+                Self::process_newmultisig(account_info_iter, m)
+            }
+            Command::ApproveChecked { amount, decimals } => {
+                info!("Command: ApproveChecked");
+                Self::process_approvechecked(account_info_iter, amount, decimals, hook)
+            }
+            Command::Freeze => {
+                info!("Command: Freeze");
+                Self::process_freeze(account_info_iter)
+            }
+            Command::Thaw => {
+                info!("Command: Thaw");
+                Self::process_thaw(account_info_iter)
+            }
+            Command::BatchTransfer(amounts) => {
+                info!("Command: BatchTransfer");
+                Self::process_batchtransfer(account_info_iter, &amounts, hook)
+            }
+            Command::NewTokenAccountIdempotent => {
+                info!("Command: NewTokenAccountIdempotent");
+                Self::process_newaccountidempotent(account_info_iter)
+            }
+            Command::TransferWithMemo { amount, memo } => {
+                info!("Command: TransferWithMemo");
+                Self::process_transferwithmemo(program_id, account_info_iter, amount, &memo, hook)
+            }
+            Command::SetTransferAuthority => {
+                info!("Command: SetTransferAuthority");
+                Self::process_settransferauthority(account_info_iter)
+            }
+            Command::InitMint { decimals } => {
+                info!("Command: InitMint");
+                Self::process_initmint(account_info_iter, decimals)
+            }
+            Command::NewAssociatedTokenAccount => {
+                info!("Command: NewAssociatedTokenAccount");
+                Self::process_newassociatedaccount(program_id, account_info_iter)
+            }
+            Command::ApproveAndTransfer { approve, transfer } => {
+                info!("Command: ApproveAndTransfer");
+                Self::process_approveandtransfer(
+                    program_id,
+                    account_info_iter,
+                    approve,
+                    transfer,
+                    hook,
+                )
+            }
+            Command::SetPause(paused) => {
+                info!("Command: SetPause");
+                Self::process_setpause(account_info_iter, paused)
+            }
+            Command::NewTokenAccountWithImmutableOwner => {
+                info!("Command: NewTokenAccountWithImmutableOwner");
+                Self::process_newaccountwithimmutableowner(account_info_iter)
+            }
+            Command::ApproveWithExpiry {
+                amount,
+                expiry_slot,
+            } => {
+                info!("Command: ApproveWithExpiry");
+                Self::process_approvewithexpiry(account_info_iter, amount, expiry_slot, hook)
+            }
+            Command::GetAccountInfo => {
+                info!("Command: GetAccountInfo");
+                Self::process_getinfo(account_info_iter)
+            }
+            Command::CloseAndTransfer => {
+                info!("Command: CloseAndTransfer");
+                Self::process_closeandtransfer(program_id, account_info_iter, hook)
+            }
+            Command::GetAuthorities => {
+                info!("Command: GetAuthorities");
+                Self::process_getauthorities(account_info_iter)
+            }
+            Command::FreezeOwner(owner) => {
+                info!("Command: FreezeOwner");
+                Self::process_freezeowner(account_info_iter, owner)
+            }
+        };
+        #[cfg(feature = "DEBUG_BALANCE_TRACE")]
+        {
+            if result.is_ok() {
+                Self::trace_balances(BalanceTraceStage::Post, accounts);
+            }
+        }
+        #[cfg(feature = "strict")]
+        let result = result.and_then(|()| Self::validate_accounts(accounts));
+        if result.is_ok() {
+            events.replay(audit_hook);
+        }
+        result
+    }
+
+    /// Runs `validate_account` on each token account in `accounts` whose token is also passed
+    #[cfg(feature = "strict")]
+    fn validate_accounts(accounts: &[AccountInfo]) -> ProgramResult {
+        for account_info in accounts.iter() {
+            let account = match State::deserialize(&account_info.data.borrow()) {
+                Ok(State::Account(account)) => account,
+                _ => continue,
+            };
+            for token_account_info in accounts.iter().filter(|info| *info.key == account.token) {
+                if let Ok(State::Token(token)) =
+                    State::deserialize(&token_account_info.data.borrow())
+                {
+                    State::Account(account).validate_account(&token)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs the key and `amount` of each token account in `accounts`, as "Balance pre" or
+    /// "Balance post" followed by the key and then the amount
+    #[cfg(feature = "DEBUG_BALANCE_TRACE")]
+    fn trace_balances(stage: BalanceTraceStage, accounts: &[AccountInfo]) {
+        for account_info in accounts.iter() {
+            if let Ok(State::Account(account)) = State::deserialize(&account_info.data.borrow()) {
+                match stage {
+                    BalanceTraceStage::Pre => info!("Balance pre"),
+                    BalanceTraceStage::Post => info!("Balance post"),
+                }
+                account_info.key.log();
+                info!(account.amount, 0, 0, 0, 0);
+                #[cfg(test)]
+                BALANCE_TRACE.with(|trace| {
+                    trace
+                        .borrow_mut()
+                        .push((stage, *account_info.key, account.amount))
+                });
+            }
+        }
+    }
+
+    /// Checks that `owner_account_info` is the expected owner and that it signed
+    fn require_owner(owner_account_info: &AccountInfo, expected_owner: &Pubkey) -> ProgramResult {
+        if owner_account_info.key != expected_owner {
+            info!("Error: owner not present");
+            return Err(TokenError::NoOwner.into());
+        }
+        if !owner_account_info.is_signer {
+            info!("Error: owner account not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    pub fn process_freeze<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        Self::set_frozen(account_info_iter, true)
+    }
+
+    pub fn process_thaw<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        Self::set_frozen(account_info_iter, false)
+    }
+
+    pub fn process_setpause<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        paused: bool,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let authority_account_info = next_account_info(account_info_iter).ok();
+
+        let mut token_data = token_account_info.data.borrow_mut();
+        let mut token = if let State::Token(token) = State::deserialize(&token_data)? {
+            token
+        } else {
+            info!("Error: token account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        };
+        Self::require_mint_authority(&token, token_account_info, authority_account_info)?;
+        token.paused = paused;
+        State::Token(token).serialize(&mut token_data)
+    }
+
+    /// Sets whether a token account is frozen, on the authority of its token
+    fn set_frozen<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        frozen: bool,
+    ) -> ProgramResult {
+        let account_info = next_account_info_at(account_info_iter, 0, "account")?;
+        let token_account_info = next_account_info_at(account_info_iter, 1, "token")?;
+        let authority_account_info = next_account_info_at(account_info_iter, 2, "authority")?;
+
+        let token =
+            if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+                token
+            } else {
+                info!("Error: token account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        Self::require_freeze_authority(&token, authority_account_info)?;
+
+        State::with_account_mut(&mut account_info.data.borrow_mut(), |account| {
+            if account.token != *token_account_info.key {
+                info!("Error: token mismatch");
+                return Err(TokenError::TokenMismatch.into());
+            }
+            account.state = if frozen {
+                AccountState::Frozen
+            } else {
+                AccountState::Initialized
+            };
+            Ok(())
+        })
+    }
+
+    pub fn process_freezeowner<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+        owner: Pubkey,
+    ) -> ProgramResult {
+        let token_account_info = next_account_info_at(account_info_iter, 0, "token")?;
+        let authority_account_info = next_account_info_at(account_info_iter, 1, "authority")?;
+        let result_account_info = next_account_info_at(account_info_iter, 2, "result")?;
+
+        let token =
+            if let State::Token(token) = State::deserialize(&token_account_info.data.borrow())? {
+                token
+            } else {
+                info!("Error: token account is invalid");
+                return Err(ProgramError::InvalidArgument);
+            };
+        Self::require_freeze_authority(&token, authority_account_info)?;
+        if result_account_info.data.borrow().len() < size_of::<u64>() {
+            info!("Error: result account is too small");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut frozen: u64 = 0;
+        for account_info in account_info_iter {
+            let mut data = account_info.data.borrow_mut();
+            if let Ok(State::Account(mut account)) = State::deserialize(&data) {
+                // Already frozen accounts, including one passed twice, aren't counted again
+                if account.owner == owner
+                    && account.token == *token_account_info.key
+                    && account.state != AccountState::Frozen
+                {
+                    account.state = AccountState::Frozen;
+                    State::Account(account).serialize(&mut data)?;
+                    frozen += 1;
+                }
+            }
+        }
+        write_u64(&mut result_account_info.data.borrow_mut(), 0, frozen)
+    }
+
+    /// Checks that `authority_account_info` is the token's freeze authority and that it signed
+    fn require_freeze_authority(
+        token: &Token,
+        authority_account_info: &AccountInfo,
+    ) -> ProgramResult {
+        match token.freeze_authority {
+            Some(freeze_authority) if freeze_authority == *authority_account_info.key => {}
+            Some(_) => {
+                info!("Error: not the freeze authority");
+                return Err(ProgramError::InvalidArgument);
+            }
+            None => {
+                info!("Error: token has no freeze authority");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+        if !authority_account_info.is_signer {
+            info!("Error: freeze authority not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    /// Like `require_owner`, but an owner that is a multisig account is authorized instead by
+    /// the next `m` accounts, each a distinct signer it lists that signed.  Only a multisig
+    /// owned by `program_id` counts, another program's data could merely look like one
+    fn require_owner_or_multisig<I: Iterator<Item = &'a AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        owner_account_info: &AccountInfo<'a>,
+        expected_owner: &Pubkey,
+        account_info_iter: &mut I,
+    ) -> ProgramResult {
+        // The owner may alias an account the caller already borrowed, which is never a multisig
+        let owner_state = owner_account_info
+            .data
+            .try_borrow()
+            .map_err(|_| ProgramError::AccountBorrowFailed)
+            .and_then(|data| State::deserialize(&data));
+        let (m, signers) = match owner_state {
+            Ok(State::Multisig { m, signers }) if owner_account_info.owner == program_id => {
+                (m, signers)
+            }
+            _ => return Self::require_owner(owner_account_info, expected_owner),
+        };
+        if owner_account_info.key != expected_owner {
+            info!("Error: owner not present");
+            return Err(TokenError::NoOwner.into());
+        }
+
+        let mut used = [false; MAX_SIGNERS];
+        for _ in 0..m {
+            let signer_account_info = next_account_info(account_info_iter)?;
+            if !signer_account_info.is_signer {
+                info!("Error: multisig signer not a signer");
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            match signers
+                .iter()
+                .position(|key| key == signer_account_info.key && *key != Pubkey::default())
+            {
+                Some(i) if !used[i] => used[i] = true,
+                _ => {
+                    info!("Error: account is not a multisig signer or is repeated");
+                    return Err(ProgramError::InvalidArgument);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the token's mint authority signed.  The authority is `authority_account_info`
+    /// if given, otherwise the token account itself
+    fn require_mint_authority(
+        token: &Token,
+        token_account_info: &AccountInfo<'a>,
+        authority_account_info: Option<&AccountInfo<'a>>,
+    ) -> ProgramResult {
+        let authority_account_info = authority_account_info.unwrap_or(token_account_info);
+        let expected_authority = if token.mint_authority == Pubkey::default() {
+            token_account_info.key
+        } else {
+            &token.mint_authority
+        };
+        if authority_account_info.key != expected_authority {
+            info!("Error: not the mint authority");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !authority_account_info.is_signer {
+            info!("Error: mint authority not a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    /// Deserializes an `Account` from `data`, applies `f` to it, and writes it back only if `f`
+    /// succeeds, leaving `data` untouched otherwise
+    pub fn with_account_mut<F>(data: &mut [u8], f: F) -> ProgramResult
+    where
+        F: FnOnce(&mut TokenAccount) -> ProgramResult,
+    {
+        if let State::Account(mut account) = State::deserialize(data)? {
+            f(&mut account)?;
+            State::Account(account).serialize(data)
+        } else {
+            info!("Error: account is invalid");
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+
+    /// Sets a delegate account's remaining and original allowance to `original` and its expiry
+    /// to `expiry_slot` directly in `data`, without deserializing and re-serializing the rest of
+    /// the account
+    pub fn set_delegate_allowance_in_place(
+        data: &mut [u8],
+        original: u64,
+        expiry_slot: u64,
+    ) -> ProgramResult {
+        if data.len() < Self::ACCOUNT_LEN || data[0] != 2 || data[1] != LAYOUT_VERSION {
+            info!("Error: account is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let account_data = &mut data[HEADER_LEN..];
+        if account_data[TokenAccount::DELEGATE_OFFSET] == 0 {
+            info!("Error: delegate account is not a delegate");
+            return Err(TokenError::NotDelegate.into());
+        }
+        let delegate_offset = TokenAccount::DELEGATE_OFFSET + size_of::<u8>();
+        write_u64(
+            account_data,
+            delegate_offset + TokenAccountDelegate::ORIGINAL_AMOUNT_OFFSET,
+            original,
+        )?;
+        write_u64(
+            account_data,
+            delegate_offset + TokenAccountDelegate::EXPIRY_SLOT_OFFSET,
+            expiry_slot,
+        )?;
+        write_u64(account_data, TokenAccount::AMOUNT_OFFSET, original)
+    }
+
+    /// Whether `input`, tagged as a state whose versioned form is `len` bytes, was written by the
+    /// original program, which put the data right after the tag with no version byte.  Its first
+    /// data byte may equal `LAYOUT_VERSION` by chance, but its `LEGACY_LEN` byte accounts are
+    /// too small to hold a versioned token or token account
+    fn is_legacy(input: &[u8], len: usize) -> bool {
+        input.len() < len || input[1] != LAYOUT_VERSION
+    }
+
+    pub fn deserialize(input: &'a [u8]) -> Result<Self, ProgramError> {
+        if input.len() < size_of::<u8>() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        match input[0] {
+            1 if Self::is_legacy(input, Self::TOKEN_LEN) => {
+                return Ok(Self::Token(Token::deserialize_legacy(
+                    &input[size_of::<u8>()..],
+                )?));
+            }
+            2 if Self::is_legacy(input, Self::ACCOUNT_LEN) => {
+                return Ok(Self::Account(TokenAccount::deserialize_legacy(
+                    &input[size_of::<u8>()..],
+                )?));
+            }
+            _ => {}
+        }
+        // Zeroed data is unallocated whatever follows the tag
+        if input[0] != 0 && (input.len() < HEADER_LEN || input[1] != LAYOUT_VERSION) {
+            info!("Error: unsupported state version");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(match input[0] {
+            0 => Self::Unallocated,
+            1 => Self::Token(Token::deserialize(&input[HEADER_LEN..])?),
+            2 => {
+                if input.len() < Self::ACCOUNT_LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                Self::Account(TokenAccount::deserialize(&input[HEADER_LEN..])?)
+            }
+            3 => {
+                info!("Error: account holds the invalid state marker");
+                return Err(ProgramError::InvalidAccountData);
+            }
+            4 => {
+                if input.len() < Self::NONCE_LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let value = read_u64(input, HEADER_LEN)?;
+                Self::Nonce {
+                    value,
+                    consumed: input[HEADER_LEN + size_of::<u64>()] != 0,
+                }
+            }
+            5 => {
+                if input.len() < Self::MIRROR_LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let amount_offset = HEADER_LEN + size_of::<Pubkey>();
+                let amount = read_u64(input, amount_offset)?;
+                let slot = read_u64(input, amount_offset + size_of::<u64>())?;
+                Self::Mirror {
+                    source: Pubkey::new(&input[HEADER_LEN..amount_offset]),
+                    amount,
+                    slot,
+                }
+            }
+            6 => {
+                if input.len() < Self::MULTISIG_LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let mut signers = [Pubkey::default(); MAX_SIGNERS];
+                for (i, signer) in signers.iter_mut().enumerate() {
+                    *signer = read_pubkey(
+                        input,
+                        HEADER_LEN + size_of::<u8>() + i * size_of::<Pubkey>(),
+                    )?;
+                }
+                Self::Multisig {
+                    m: input[HEADER_LEN],
+                    signers,
+                }
+            }
+            _ => return Err(ProgramError::InvalidAccountData),
+        })
+    }
+
+    /// Whether an account holding `from` may be overwritten with `to`
+    pub fn can_transition(from: &State, to: &State) -> bool {
+        match (from, to) {
+            (State::Unallocated, State::Unallocated)
+            | (State::Unallocated, State::Token(_))
+            | (State::Unallocated, State::Account(_))
+            | (State::Token(_), State::Token(_))
+            | (State::Account(_), State::Account(_))
+            | (State::Account(_), State::Unallocated)
+            | (State::Unallocated, State::Nonce { .. })
+            | (State::Nonce { .. }, State::Nonce { .. })
+            | (State::Unallocated, State::Mirror { .. })
+            | (State::Mirror { .. }, State::Mirror { .. })
+            | (State::Unallocated, State::Multisig { .. }) => true,
+            _ => false,
+        }
+    }
+
+    pub fn serialize(self: &Self, output: &mut [u8]) -> ProgramResult {
+        if output.len() < size_of::<u8>() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if !Self::can_transition(&State::deserialize(output)?, self) {
+            info!("Error: illegal state transition");
+            return Err(TokenError::IllegalStateTransition.into());
+        }
+        if output.len() < self.packed_len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        match self {
+            Self::Unallocated => output[0] = 0,
+            Self::Token(token) => {
+                output[0] = 1;
+                token.serialize(&mut output[HEADER_LEN..])?;
+            }
+            Self::Account(account) => {
+                output[0] = 2;
+                account.serialize(&mut output[HEADER_LEN..])?;
+            }
+            Self::Invalid => output[0] = 3,
+            Self::Nonce { value, consumed } => {
+                output[0] = 4;
+                write_u64(output, HEADER_LEN, *value)?;
+                output[HEADER_LEN + size_of::<u64>()] = *consumed as u8;
+            }
+            Self::Mirror {
+                source,
+                amount,
+                slot,
+            } => {
+                output[0] = 5;
+                let amount_offset = HEADER_LEN + size_of::<Pubkey>();
+                output[HEADER_LEN..amount_offset].copy_from_slice(source.as_ref());
+                write_u64(output, amount_offset, *amount)?;
+                write_u64(output, amount_offset + size_of::<u64>(), *slot)?;
+            }
+            Self::Multisig { m, signers } => {
+                output[0] = 6;
+                output[HEADER_LEN] = *m;
+                for (i, signer) in signers.iter().enumerate() {
+                    write_pubkey(
+                        output,
+                        HEADER_LEN + size_of::<u8>() + i * size_of::<Pubkey>(),
+                        signer,
+                    )?;
+                }
+            }
+        }
+        if *self != Self::Unallocated {
+            output[1] = LAYOUT_VERSION;
+        }
+        Ok(())
+    }
+}
+
+impl Command {
+    /// Decodes a command, which must fill `input` exactly
+    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() < HEADER_LEN {
+            return Err(TokenError::InvalidInstruction.into());
+        }
+        if input[1] != LAYOUT_VERSION {
+            info!("Error: unsupported command version");
+            return Err(TokenError::InvalidInstruction.into());
+        }
+        let command = match input[0] {
+            0 => {
+                if input.len() < HEADER_LEN + Token::LEN {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let token = Token::deserialize(&input[HEADER_LEN..])?;
+                Self::NewToken(token)
+            }
+            1 => Self::NewTokenAccount,
+            2 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::Transfer(amount)
+            }
+            3 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::Approve(amount)
+            }
+            4 => Self::SetOwner,
+            5 => Self::AssertAccountOwner,
+            6 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let target = read_u64(input, HEADER_LEN)?;
+                Self::TransferIfBelow { target }
+            }
+            7 => Self::MoveAccount,
+            8 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::TransferAndCloseIfEmpty { amount }
+            }
+            9 => {
+                let amounts_offset = HEADER_LEN + Token::LEN + size_of::<u8>();
+                if input.len() < amounts_offset {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let token = Token::deserialize(&input[HEADER_LEN..])?;
+                let count = input[amounts_offset - size_of::<u8>()] as usize;
+                if input.len() < amounts_offset + count * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let mut amounts = Vec::with_capacity(count);
+                for i in 0..count {
+                    let amount = read_u64(input, amounts_offset + i * size_of::<u64>())?;
+                    amounts.push(amount);
+                }
+                Self::NewTokenDistributed(token, amounts)
+            }
+            10 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount_a = read_u64(input, HEADER_LEN)?;
+                let amount_b = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::Swap { amount_a, amount_b }
+            }
+            11 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::CheckTransfer { amount }
+            }
+            12 => Self::CheckPeg,
+            13 => {
+                if input.len() < HEADER_LEN + size_of::<[u8; 32]>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let mut commitment = [0u8; 32];
+                commitment.copy_from_slice(&input[HEADER_LEN..HEADER_LEN + size_of::<[u8; 32]>()]);
+                Self::SetConfidential { commitment }
+            }
+            14 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let allowance = read_u64(input, HEADER_LEN)?;
+                Self::NewAccountAndApprove { allowance }
+            }
+            15 => Self::SetWhitelist,
+            16 => Self::BatchBalance,
+            17 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let value = read_u64(input, HEADER_LEN)?;
+                Self::NewNonce(value)
+            }
+            18 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let value = read_u64(input, HEADER_LEN)?;
+                Self::ConsumeNonce(value)
+            }
+            19 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let min_price = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::TransferIfPrice { amount, min_price }
+            }
+            20 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let min_holding = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::TransferIfHolds {
+                    amount,
+                    min_holding,
+                }
+            }
+            21 => Self::RotateOwner,
+            22 => Self::ProgramInfo,
+            23 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let expected_total = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::TransferWithInvariant {
+                    amount,
+                    expected_total,
+                }
+            }
+            24 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::MintToNew(amount)
+            }
+            25 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::Distribute(amount)
+            }
+            26 => Self::GetAccount,
+            27 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::ApproveIfUnused(amount)
+            }
+            28 => Self::SyncMirror,
+            29 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::Burn(amount)
+            }
+            30 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                Self::MintTo(amount)
+            }
+            31 => Self::CloseAccount,
+            32 => Self::Revoke,
+            33 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let decimals = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::TransferChecked { amount, decimals }
+            }
+            34 => {
+                if input.len() < HEADER_LEN + size_of::<u8>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                Self::NewMultisig(input[HEADER_LEN])
+            }
+            35 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let decimals = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::ApproveChecked { amount, decimals }
+            }
+            36 => Self::Freeze,
+            37 => Self::Thaw,
+            38 => {
+                let amounts_offset = HEADER_LEN + size_of::<u8>();
+                if input.len() < amounts_offset {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let count = input[amounts_offset - size_of::<u8>()] as usize;
+                if input.len() < amounts_offset + count * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let mut amounts = Vec::with_capacity(count);
+                for i in 0..count {
+                    let amount = read_u64(input, amounts_offset + i * size_of::<u64>())?;
+                    amounts.push(amount);
+                }
+                Self::BatchTransfer(amounts)
+            }
+            39 => Self::NewTokenAccountIdempotent,
+            40 => {
+                let memo_offset = HEADER_LEN + size_of::<u64>();
+                if input.len() < memo_offset + size_of::<[u8; 32]>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let mut memo = [0u8; 32];
+                memo.copy_from_slice(&input[memo_offset..memo_offset + size_of::<[u8; 32]>()]);
+                Self::TransferWithMemo { amount, memo }
+            }
+            41 => Self::SetTransferAuthority,
+            42 => {
+                if input.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let decimals = read_u64(input, HEADER_LEN)?;
+                Self::InitMint { decimals }
+            }
+            43 => Self::NewAssociatedTokenAccount,
+            44 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let approve = read_u64(input, HEADER_LEN)?;
+                let transfer = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::ApproveAndTransfer { approve, transfer }
+            }
+            45 => {
+                if input.len() < HEADER_LEN + size_of::<u8>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                match input[HEADER_LEN] {
+                    0 => Self::SetPause(false),
+                    1 => Self::SetPause(true),
+                    _ => return Err(TokenError::InvalidInstruction.into()),
+                }
+            }
+            46 => Self::NewTokenAccountWithImmutableOwner,
+            47 => {
+                if input.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                let amount = read_u64(input, HEADER_LEN)?;
+                let expiry_slot = read_u64(input, HEADER_LEN + size_of::<u64>())?;
+                Self::ApproveWithExpiry {
+                    amount,
+                    expiry_slot,
+                }
+            }
+            48 => Self::GetAccountInfo,
+            49 => Self::CloseAndTransfer,
+            50 => Self::GetAuthorities,
+            51 => {
+                if input.len() < HEADER_LEN + size_of::<Pubkey>() {
+                    return Err(TokenError::InvalidInstruction.into());
+                }
+                Self::FreezeOwner(read_pubkey(input, HEADER_LEN)?)
+            }
+            _ => {
+                info!("Error: unknown command");
+                info!(input[0], 0, 0, 0, 0);
+                return Err(TokenError::InvalidInstruction.into());
+            }
+        };
+        if input.len() != command.serialized_len() {
+            info!("Error: instruction data length does not match the command");
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        Ok(command)
+    }
+
+    /// Number of bytes `serialize` writes for this command, including the tag and version
+    pub fn serialized_len(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                Self::NewToken(_) => Token::LEN,
+                Self::NewTokenDistributed(_, amounts) => {
+                    Token::LEN + size_of::<u8>() + amounts.len() * size_of::<u64>()
+                }
+                Self::BatchTransfer(amounts) => size_of::<u8>() + amounts.len() * size_of::<u64>(),
+                Self::SetConfidential { .. } => size_of::<[u8; 32]>(),
+                Self::TransferWithMemo { .. } => size_of::<u64>() + size_of::<[u8; 32]>(),
+                Self::NewMultisig(_) | Self::SetPause(_) => size_of::<u8>(),
+                Self::FreezeOwner(_) => size_of::<Pubkey>(),
+                Self::Transfer(_)
+                | Self::Approve(_)
+                | Self::TransferIfBelow { .. }
+                | Self::TransferAndCloseIfEmpty { .. }
+                | Self::CheckTransfer { .. }
+                | Self::NewAccountAndApprove { .. }
+                | Self::NewNonce(_)
+                | Self::ConsumeNonce(_)
+                | Self::MintToNew(_)
+                | Self::Distribute(_)
+                | Self::ApproveIfUnused(_)
+                | Self::Burn(_)
+                | Self::MintTo(_)
+                | Self::InitMint { .. } => size_of::<u64>(),
+                Self::Swap { .. }
+                | Self::TransferIfPrice { .. }
+                | Self::TransferIfHolds { .. }
+                | Self::TransferWithInvariant { .. }
+                | Self::TransferChecked { .. }
+                | Self::ApproveChecked { .. }
+                | Self::ApproveAndTransfer { .. }
+                | Self::ApproveWithExpiry { .. } => 2 * size_of::<u64>(),
+                Self::NewTokenAccount
+                | Self::SetOwner
+                | Self::AssertAccountOwner
+                | Self::MoveAccount
+                | Self::CheckPeg
+                | Self::SetWhitelist
+                | Self::BatchBalance
+                | Self::RotateOwner
+                | Self::ProgramInfo
+                | Self::GetAccount
+                | Self::SyncMirror
+                | Self::CloseAccount
+                | Self::Revoke
+                | Self::Freeze
+                | Self::Thaw
+                | Self::NewTokenAccountIdempotent
+                | Self::SetTransferAuthority
+                | Self::NewAssociatedTokenAccount
+                | Self::NewTokenAccountWithImmutableOwner
+                | Self::GetAccountInfo
+                | Self::CloseAndTransfer
+                | Self::GetAuthorities => 0,
+            }
+    }
+
+    /// Serializes the command into a buffer of exactly `serialized_len` bytes
+    pub fn serialize_to_vec(&self) -> Result<Vec<u8>, ProgramError> {
+        let mut output = vec![0u8; self.serialized_len()];
+        self.serialize(&mut output)?;
+        Ok(output)
+    }
+
+    pub fn serialize(self: &Self, output: &mut [u8]) -> ProgramResult {
+        if output.len() < HEADER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        match self {
+            Self::NewToken(token) => {
+                if output.len() < HEADER_LEN + Token::LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 0;
+                token.serialize(&mut output[HEADER_LEN..])?;
+            }
+            Self::NewTokenAccount => output[0] = 1,
+            Self::Transfer(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 2;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::Approve(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 3;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::SetOwner => output[0] = 4,
+            Self::AssertAccountOwner => output[0] = 5,
+            Self::TransferIfBelow { target } => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 6;
+                write_u64(output, HEADER_LEN, *target)?;
+            }
+            Self::MoveAccount => output[0] = 7,
+            Self::TransferAndCloseIfEmpty { amount } => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 8;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::NewTokenDistributed(token, amounts) => {
+                let amounts_offset = HEADER_LEN + Token::LEN + size_of::<u8>();
+                if amounts.len() > u8::MAX as usize
+                    || output.len() < amounts_offset + amounts.len() * size_of::<u64>()
+                {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 9;
+                token.serialize(&mut output[HEADER_LEN..])?;
+                output[amounts_offset - size_of::<u8>()] = amounts.len() as u8;
+                for (i, amount) in amounts.iter().enumerate() {
+                    write_u64(output, amounts_offset + i * size_of::<u64>(), *amount)?;
+                }
+            }
+            Self::Swap { amount_a, amount_b } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 10;
+                write_u64(output, HEADER_LEN, *amount_a)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *amount_b)?;
+            }
+            Self::CheckTransfer { amount } => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 11;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::CheckPeg => output[0] = 12,
+            Self::SetConfidential { commitment } => {
+                if output.len() < HEADER_LEN + size_of::<[u8; 32]>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 13;
+                output[HEADER_LEN..HEADER_LEN + size_of::<[u8; 32]>()].copy_from_slice(commitment);
+            }
+            Self::NewAccountAndApprove { allowance } => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 14;
+                write_u64(output, HEADER_LEN, *allowance)?;
+            }
+            Self::SetWhitelist => output[0] = 15,
+            Self::BatchBalance => output[0] = 16,
+            Self::NewNonce(value) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 17;
+                write_u64(output, HEADER_LEN, *value)?;
+            }
+            Self::ConsumeNonce(value) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 18;
+                write_u64(output, HEADER_LEN, *value)?;
+            }
+            Self::TransferIfPrice { amount, min_price } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 19;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *min_price)?;
+            }
+            Self::TransferIfHolds {
+                amount,
+                min_holding,
+            } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 20;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *min_holding)?;
+            }
+            Self::RotateOwner => output[0] = 21,
+            Self::ProgramInfo => output[0] = 22,
+            Self::TransferWithInvariant {
+                amount,
+                expected_total,
+            } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 23;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *expected_total)?;
+            }
+            Self::MintToNew(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 24;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::Distribute(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 25;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::GetAccount => output[0] = 26,
+            Self::ApproveIfUnused(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 27;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::SyncMirror => output[0] = 28,
+            Self::Burn(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 29;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::MintTo(amount) => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 30;
+                write_u64(output, HEADER_LEN, *amount)?;
+            }
+            Self::CloseAccount => output[0] = 31,
+            Self::Revoke => output[0] = 32,
+            Self::TransferChecked { amount, decimals } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 33;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *decimals)?;
+            }
+            Self::NewMultisig(m) => {
+                if output.len() < HEADER_LEN + size_of::<u8>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 34;
+                output[HEADER_LEN] = *m;
+            }
+            Self::ApproveChecked { amount, decimals } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 35;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *decimals)?;
+            }
+            Self::Freeze => output[0] = 36,
+            Self::Thaw => output[0] = 37,
+            Self::BatchTransfer(amounts) => {
+                let amounts_offset = HEADER_LEN + size_of::<u8>();
+                if amounts.len() > u8::MAX as usize
+                    || output.len() < amounts_offset + amounts.len() * size_of::<u64>()
+                {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 38;
+                output[amounts_offset - size_of::<u8>()] = amounts.len() as u8;
+                for (i, amount) in amounts.iter().enumerate() {
+                    write_u64(output, amounts_offset + i * size_of::<u64>(), *amount)?;
+                }
+            }
+            Self::NewTokenAccountIdempotent => output[0] = 39,
+            Self::TransferWithMemo { amount, memo } => {
+                let memo_offset = HEADER_LEN + size_of::<u64>();
+                if output.len() < memo_offset + size_of::<[u8; 32]>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 40;
+                write_u64(output, HEADER_LEN, *amount)?;
+                output[memo_offset..memo_offset + size_of::<[u8; 32]>()].copy_from_slice(memo);
+            }
+            Self::SetTransferAuthority => output[0] = 41,
+            Self::InitMint { decimals } => {
+                if output.len() < HEADER_LEN + size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 42;
+                write_u64(output, HEADER_LEN, *decimals)?;
+            }
+            Self::NewAssociatedTokenAccount => output[0] = 43,
+            Self::ApproveAndTransfer { approve, transfer } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 44;
+                write_u64(output, HEADER_LEN, *approve)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *transfer)?;
+            }
+            Self::SetPause(paused) => {
+                if output.len() < HEADER_LEN + size_of::<u8>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 45;
+                output[HEADER_LEN] = *paused as u8;
+            }
+            Self::NewTokenAccountWithImmutableOwner => output[0] = 46,
+            Self::ApproveWithExpiry {
+                amount,
+                expiry_slot,
+            } => {
+                if output.len() < HEADER_LEN + 2 * size_of::<u64>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 47;
+                write_u64(output, HEADER_LEN, *amount)?;
+                write_u64(output, HEADER_LEN + size_of::<u64>(), *expiry_slot)?;
+            }
+            Self::GetAccountInfo => output[0] = 48,
+            Self::CloseAndTransfer => output[0] = 49,
+            Self::GetAuthorities => output[0] = 50,
+            Self::FreezeOwner(owner) => {
+                if output.len() < HEADER_LEN + size_of::<Pubkey>() {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                output[0] = 51;
+                write_pubkey(output, HEADER_LEN, owner)?;
+            }
+        }
+        output[1] = LAYOUT_VERSION;
+        Ok(())
+    }
+}
+
+// Pulls in the stubs required for `info!()`
+#[cfg(not(target_arch = "bpf"))]
+solana_sdk_bpf_test::stubs!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::FromPrimitive;
+    use solana_sdk::{account::Account, account_info::create_is_signer_account_infos};
+
+    fn new_pubkey(id: u8) -> Pubkey {
+        Pubkey::new(&vec![
+            id, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+            1, 1, 1,
+        ])
+    }
+
+    fn get_account(data: &[u8]) -> TokenAccount {
+        match State::deserialize(data).unwrap() {
+            State::Account(account) => account,
+            _ => panic!("not an account"),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_unknown_command() {
+        assert_eq!(
+            Err(TokenError::InvalidInstruction.into()),
+            Command::deserialize(&[99, LAYOUT_VERSION])
+        );
+
+        // known command, unknown version
+        let mut instruction_data = Command::Transfer(100).serialize_to_vec().unwrap();
+        assert_eq!(instruction_data[..HEADER_LEN], [2, LAYOUT_VERSION]);
+        instruction_data[1] = LAYOUT_VERSION + 1;
+        assert_eq!(
+            Err(TokenError::InvalidInstruction.into()),
+            Command::deserialize(&instruction_data)
+        );
+
+        // too short for the header or the payload
+        assert_eq!(
+            Err(TokenError::InvalidInstruction.into()),
+            Command::deserialize(&[2])
+        );
+        assert_eq!(
+            Err(TokenError::InvalidInstruction.into()),
+            Command::deserialize(&[2, LAYOUT_VERSION, 0, 0])
+        );
+
+        // account data keeps its own error
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&[99, LAYOUT_VERSION])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_invalid_state() {
+        let mut data = vec![0u8; size_of::<State>()];
+        assert_eq!(
+            Err(TokenError::IllegalStateTransition.into()),
+            State::Invalid.serialize(&mut data)
+        );
+        data[..HEADER_LEN].copy_from_slice(&[3, LAYOUT_VERSION]);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&data)
+        );
+    }
+
+    #[test]
+    fn test_state_amount_owner() {
+        let state = State::Account(TokenAccount {
+            owner: new_pubkey(1),
+            amount: 42,
+            ..TokenAccount::default()
+        });
+        assert_eq!(state.amount(), Some(42));
+        assert_eq!(state.owner(), Some(new_pubkey(1)));
+
+        let state = State::Token(Token {
+            supply: 1000,
+            ..Token::default()
+        });
+        assert_eq!(state.amount(), None);
+        assert_eq!(state.owner(), None);
+
+        assert_eq!(State::Unallocated.amount(), None);
+        assert_eq!(State::Unallocated.owner(), None);
+    }
+
+    #[test]
+    fn test_state_packed_len() {
+        assert_eq!(State::TOKEN_LEN, 2 + Token::LEN);
+        assert_eq!(State::ACCOUNT_LEN, 2 + TokenAccount::LEN);
+        let states = [
+            State::Unallocated,
+            State::Token(Token {
+                supply: 1000,
+                freeze_authority: Some(new_pubkey(1)),
+                ..Token::default()
+            }),
+            State::Account(TokenAccount {
+                delegate: Some(TokenAccountDelegate::default()),
+                state: AccountState::Frozen,
+                ..TokenAccount::default()
+            }),
+            State::Nonce {
+                value: 1,
+                consumed: true,
+            },
+            State::Mirror {
+                source: new_pubkey(2),
+                amount: 3,
+                slot: 4,
+            },
+            State::Multisig {
+                m: 1,
+                signers: [new_pubkey(5); MAX_SIGNERS],
+            },
+        ];
+        for state in states.iter() {
+            let mut data = vec![0u8; state.packed_len()];
+            state.serialize(&mut data).unwrap();
+            assert_eq!(Ok(state.clone()), State::deserialize(&data));
+            assert!(state.packed_len() <= size_of::<State>());
+
+            if state.packed_len() > 1 {
+                let mut data = vec![0u8; state.packed_len() - 1];
+                assert_eq!(
+                    Err(ProgramError::InvalidAccountData),
+                    state.serialize(&mut data)
+                );
+            }
+        }
+        assert_eq!(
+            State::Token(Token::default()).packed_len(),
+            State::TOKEN_LEN
+        );
+        assert_eq!(
+            State::Account(TokenAccount::default()).packed_len(),
+            State::ACCOUNT_LEN
+        );
+    }
+
+    #[test]
+    fn test_deserialize_trailing_data() {
+        let instruction = Command::Transfer(100);
+        let mut instruction_data = instruction.serialize_to_vec().unwrap();
+        assert_eq!(instruction_data.len(), instruction.serialized_len());
+        assert_eq!(Ok(instruction), Command::deserialize(&instruction_data));
+
+        instruction_data.push(0);
+        assert_eq!(
+            Err(ProgramError::InvalidInstructionData),
+            Command::deserialize(&instruction_data)
+        );
+
+        // a buffer sized for the largest command
+        let mut instruction_data = vec![0u8; size_of::<Command>()];
+        Command::Transfer(100)
+            .serialize(&mut instruction_data)
+            .unwrap();
+        assert_eq!(
+            Err(ProgramError::InvalidInstructionData),
+            Command::deserialize(&instruction_data)
+        );
+
+        // variable length commands are sized by their amounts
+        let instruction = Command::BatchTransfer(vec![1, 2, 3]);
+        let mut instruction_data = instruction.serialize_to_vec().unwrap();
+        assert_eq!(
+            instruction_data.len(),
+            HEADER_LEN + 1 + 3 * size_of::<u64>()
+        );
+        assert_eq!(Ok(instruction), Command::deserialize(&instruction_data));
+        instruction_data.extend_from_slice(&4u64.to_le_bytes());
+        assert_eq!(
+            Err(ProgramError::InvalidInstructionData),
+            Command::deserialize(&instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_check_supply() {
+        let token = Token {
+            supply: 700,
+            total_minted: 1000,
+            total_burned: 300,
+            ..Token::default()
+        };
+        token.check_supply().unwrap();
+
+        let token = Token {
+            supply: 800,
+            ..token
+        };
+        assert_eq!(Err(TokenError::SupplyMismatch.into()), token.check_supply());
+
+        let token = Token {
+            supply: 0,
+            total_minted: 100,
+            total_burned: 300,
+            ..Token::default()
+        };
+        assert_eq!(Err(TokenError::SupplyMismatch.into()), token.check_supply());
+    }
+
+    #[test]
+    fn test_check_max_supply() {
+        let token = Token {
+            supply: u64::MAX,
+            ..Token::default()
+        };
+        token.check_max_supply().unwrap();
+
+        let token = Token {
+            supply: 1000,
+            max_supply: 1000,
+            ..Token::default()
+        };
+        token.check_max_supply().unwrap();
+
+        let token = Token {
+            supply: 1001,
+            ..token
+        };
+        assert_eq!(
+            Err(TokenError::SupplyCapExceeded.into()),
+            token.check_max_supply()
+        );
+    }
+
+    #[test]
+    fn test_serialized_layout() {
+        // byte layouts built field by field, independent of the host's endianness
+        let mut expected = vec![2u8, LAYOUT_VERSION];
+        expected.extend_from_slice(new_pubkey(1).as_ref());
+        expected.extend_from_slice(new_pubkey(2).as_ref());
+        expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+        expected.push(1);
+        expected.extend_from_slice(new_pubkey(3).as_ref());
+        expected.extend_from_slice(&[0x10, 0x0f, 0x0e, 0x0d, 0x0c, 0x0b, 0x0a, 0x09]);
+        expected.extend_from_slice(&[0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11]);
+        expected.push(1);
+        expected.extend_from_slice(&[7u8; 32]);
+        expected.extend_from_slice(new_pubkey(4).as_ref());
+        expected.extend_from_slice(&[0u8; 3 * 32]);
+        expected.push(2);
+        expected.push(1);
+        expected.extend_from_slice(new_pubkey(5).as_ref());
+        expected.push(1);
+        assert_eq!(expected.len(), State::ACCOUNT_LEN);
+        let account = TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 0x0102_0304_0506_0708,
+            delegate: Some(TokenAccountDelegate {
+                source: new_pubkey(3),
+                original_amount: 0x090a_0b0c_0d0e_0f10,
+                expiry_slot: 0x1112_1314_1516_1718,
+            }),
+            confidential: true,
+            commitment: [7u8; 32],
+            whitelist: [
+                new_pubkey(4),
+                Pubkey::default(),
+                Pubkey::default(),
+                Pubkey::default(),
+            ],
+            state: AccountState::Frozen,
+            transfer_authority: Some(new_pubkey(5)),
+            owner_immutable: true,
+        };
+        assert_eq!(Ok(State::Account(account)), State::deserialize(&expected));
+        let mut data = vec![0u8; State::ACCOUNT_LEN];
+        State::Account(account).serialize(&mut data).unwrap();
+        assert_eq!(expected, data);
+
+        let mut expected = vec![1u8, LAYOUT_VERSION];
+        for value in 1..=6u64 {
+            expected.extend_from_slice(&[value as u8, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        expected.push(2);
+        expected.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(new_pubkey(5).as_ref());
+        expected.push(1);
+        expected.extend_from_slice(new_pubkey(6).as_ref());
+        expected.push(1);
+        expected.extend_from_slice(&[0x10, 0x27]);
+        expected.extend_from_slice(new_pubkey(7).as_ref());
+        expected.push(1);
+        assert_eq!(expected.len(), State::TOKEN_LEN);
+        let token = Token {
+            supply: 1,
+            decimals: 2,
+            max_transfer: 3,
+            total_minted: 4,
+            total_burned: 5,
+            peg_ratio: 6,
+            rounding: 2,
+            max_supply: 256,
+            mint_authority: new_pubkey(5),
+            freeze_authority: Some(new_pubkey(6)),
+            paused: true,
+            transfer_fee_basis_points: 10_000,
+            fee_collector: new_pubkey(7),
+            non_transferable: true,
+        };
+        assert_eq!(Ok(State::Token(token)), State::deserialize(&expected));
+        let mut data = vec![0u8; State::TOKEN_LEN];
+        State::Token(token).serialize(&mut data).unwrap();
+        assert_eq!(expected, data);
+
+        // a buffer written assuming a big-endian layout decodes byte-swapped on every host
+        let mut input = vec![0u8; State::TOKEN_LEN];
+        State::Token(token).serialize(&mut input).unwrap();
+        input[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&1000u64.to_be_bytes());
+        assert_eq!(
+            1000u64.swap_bytes(),
+            Token::deserialize(&input[HEADER_LEN..]).unwrap().supply
+        );
+
+        // a delegate or transfer authority presence byte other than 0 or 1 is rejected
+        let mut input = vec![0u8; State::ACCOUNT_LEN];
+        input[0] = 2;
+        input[1] = LAYOUT_VERSION;
+        input[HEADER_LEN + TokenAccount::DELEGATE_OFFSET] = 2;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&input)
+        );
+        input[HEADER_LEN + TokenAccount::DELEGATE_OFFSET] = 0;
+        input[HEADER_LEN + TokenAccount::TRANSFER_AUTHORITY_OFFSET] = 2;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&input)
+        );
+
+        // legacy token accounts as the original program allocated them, with no version byte
+        // and the delegate's presence byte padded to 8 bytes
+        let mut input = vec![0u8; State::LEGACY_LEN];
+        input[0] = 2;
+        input[1..33].copy_from_slice(new_pubkey(1).as_ref());
+        input[33..65].copy_from_slice(new_pubkey(2).as_ref());
+        input[65..73].copy_from_slice(&500u64.to_le_bytes());
+        let legacy_account = TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 500,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        assert_eq!(
+            Ok(State::Account(legacy_account)),
+            State::deserialize(&input)
+        );
+        input[73] = 1;
+        input[81..113].copy_from_slice(new_pubkey(3).as_ref());
+        input[113..121].copy_from_slice(&700u64.to_le_bytes());
+        assert_eq!(
+            Ok(State::Account(TokenAccount {
+                delegate: Some(TokenAccountDelegate {
+                    source: new_pubkey(3),
+                    original_amount: 700,
+                    expiry_slot: 0,
+                }),
+                ..legacy_account
+            })),
+            State::deserialize(&input)
+        );
+        input[73] = 2;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&input)
+        );
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&input[..TokenAccount::LEGACY_LEN])
+        );
+
+        // a legacy token, whose supply may begin with the version byte
+        let mut input = vec![0u8; State::LEGACY_LEN];
+        input[0] = 1;
+        input[1..9].copy_from_slice(&1u64.to_le_bytes());
+        input[9..17].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(
+            Ok(State::Token(Token {
+                supply: 1,
+                decimals: 2,
+                total_minted: 1,
+                ..Token::default()
+            })),
+            State::deserialize(&input)
+        );
+
+        // unknown versions of other states are rejected, zeroed data is unallocated whatever
+        // its version
+        let mut input = vec![0u8; State::NONCE_LEN];
+        State::Nonce {
+            value: 7,
+            consumed: false,
+        }
+        .serialize(&mut input)
+        .unwrap();
+        for version in [0, LAYOUT_VERSION + 1].iter() {
+            input[1] = *version;
+            assert_eq!(
+                Err(ProgramError::InvalidAccountData),
+                State::deserialize(&input)
+            );
+        }
+        assert_eq!(Ok(State::Unallocated), State::deserialize(&[0, 7]));
+        assert_eq!(Ok(State::Unallocated), State::deserialize(&[0]));
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&[2])
+        );
+    }
+
+    #[test]
+    fn test_token_deserialize() {
+        let token = Token {
+            supply: 1000,
+            decimals: 2,
+            total_minted: 1000,
+            ..Token::default()
+        };
+
+        // legacy layout in an account the original program allocated, supply and decimals
+        // right after the tag followed by padding
+        let mut input = vec![0u8; State::LEGACY_LEN];
+        input[0] = 1;
+        input[1..9].copy_from_slice(&1000u64.to_le_bytes());
+        input[9..17].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(Ok(State::Token(token)), State::deserialize(&input));
+        Token::deserialize_legacy(&input[1..])
+            .unwrap()
+            .check_supply()
+            .unwrap();
+
+        // a legacy supply whose first byte matches the version byte is still legacy
+        input[1..9].copy_from_slice(&257u64.to_le_bytes());
+        assert_eq!(
+            Ok(State::Token(Token {
+                supply: 257,
+                decimals: 2,
+                total_minted: 257,
+                ..Token::default()
+            })),
+            State::deserialize(&input)
+        );
+
+        // current layout
+        let mut input = vec![0u8; State::TOKEN_LEN];
+        State::Token(token).serialize(&mut input).unwrap();
+        assert_eq!(Ok(token), Token::deserialize(&input[HEADER_LEN..]));
+        assert_eq!(Ok(State::Token(token)), State::deserialize(&input));
+
+        // truncated
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            Token::deserialize(&input[HEADER_LEN..Token::LEGACY_LEN])
+        );
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            Token::deserialize(&input[HEADER_LEN..Token::LEN])
+        );
+    }
+
+    #[test]
+    fn test_state_transition() {
+        let token = State::Token(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let account = State::Account(TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 100,
+            delegate: None,
+            ..TokenAccount::default()
+        });
+        let mut data = vec![0u8; size_of::<State>()];
+
+        // token cannot be overwritten as an account
+        token.serialize(&mut data).unwrap();
+        assert_eq!(
+            Err(TokenError::IllegalStateTransition.into()),
+            account.serialize(&mut data)
+        );
+        assert_eq!(Ok(token.clone()), State::deserialize(&data));
+
+        // account balance change is allowed
+        let mut data = vec![0u8; size_of::<State>()];
+        account.serialize(&mut data).unwrap();
+        let changed = State::Account(TokenAccount {
+            amount: 50,
+            ..get_account(&data)
+        });
+        changed.serialize(&mut data).unwrap();
+        assert_eq!(Ok(changed), State::deserialize(&data));
+
+        // account cannot become a token, but may be released
+        assert_eq!(
+            Err(TokenError::IllegalStateTransition.into()),
+            token.serialize(&mut data)
+        );
+        State::Unallocated.serialize(&mut data).unwrap();
+        assert!(!State::can_transition(&State::Invalid, &State::Unallocated));
+
+        // mirror stays a mirror
+        let mirror = State::Mirror {
+            source: new_pubkey(1),
+            amount: 100,
+            slot: 1,
+        };
+        mirror.serialize(&mut data).unwrap();
+        assert_eq!(Ok(mirror), State::deserialize(&data));
+        assert_eq!(
+            Err(TokenError::IllegalStateTransition.into()),
+            account.serialize(&mut data)
+        );
+    }
+
+    #[test]
+    fn test_check_decimals() {
+        let token = Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        };
+        token.check_decimals().unwrap();
+
+        // the largest supplies whose whole-token round up still fits
+        let token = Token {
+            supply: u64::MAX,
+            decimals: 0,
+            ..Token::default()
+        };
+        token.check_decimals().unwrap();
+        let token = Token {
+            supply: 18 * 10u64.pow(18),
+            decimals: Token::MAX_DECIMALS,
+            ..Token::default()
+        };
+        token.check_decimals().unwrap();
+
+        // 18.000000000000000001 tokens would need 19 whole tokens to be representable
+        let token = Token {
+            supply: 18 * 10u64.pow(18) + 1,
+            ..token
+        };
+        assert_eq!(Err(ProgramError::InvalidArgument), token.check_decimals());
+        let token = Token {
+            supply: u64::MAX,
+            decimals: 18,
+            ..Token::default()
+        };
+        assert_eq!(Err(ProgramError::InvalidArgument), token.check_decimals());
+        let token = Token {
+            decimals: 2,
+            ..token
+        };
+        assert_eq!(Err(ProgramError::InvalidArgument), token.check_decimals());
+
+        // beyond the bound
+        let token = Token {
+            supply: 0,
+            decimals: Token::MAX_DECIMALS + 1,
+            ..token
+        };
+        assert_eq!(Err(ProgramError::InvalidArgument), token.check_decimals());
+    }
+
+    #[test]
+    fn test_rounding_mode() {
+        // 255 basis points of 101 is 2.5755
+        assert_eq!(RoundingMode::Down.mul_div(101, 255, 10_000), Some(2));
+        assert_eq!(RoundingMode::Up.mul_div(101, 255, 10_000), Some(3));
+        assert_eq!(RoundingMode::HalfUp.mul_div(101, 255, 10_000), Some(3));
+
+        // 240 basis points of 101 is 2.424
+        assert_eq!(RoundingMode::Down.mul_div(101, 240, 10_000), Some(2));
+        assert_eq!(RoundingMode::Up.mul_div(101, 240, 10_000), Some(3));
+        assert_eq!(RoundingMode::HalfUp.mul_div(101, 240, 10_000), Some(2));
+
+        // exact results are never rounded, halves round up
+        assert_eq!(RoundingMode::Up.mul_div(100, 200, 10_000), Some(2));
+        assert_eq!(RoundingMode::HalfUp.mul_div(1, 1, 2), Some(1));
+
+        assert_eq!(RoundingMode::Down.mul_div(1, 1, 0), None);
+        assert_eq!(RoundingMode::Up.mul_div(u64::MAX, 3, 2), None);
+
+        let mut token = Token::default();
+        assert_eq!(token.rounding_mode(), Ok(RoundingMode::Down));
+        token.rounding = RoundingMode::HalfUp as u8;
+        assert_eq!(token.rounding_mode(), Ok(RoundingMode::HalfUp));
+        token.rounding = 3;
+        assert_eq!(token.rounding_mode(), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_with_account_mut() {
+        let mut data = vec![0u8; size_of::<State>()];
+        let account = TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 100,
+            delegate: None,
+            ..TokenAccount::default()
+        };
+        State::Account(account).serialize(&mut data).unwrap();
+        let original = data.clone();
+
+        // closure error leaves the buffer untouched
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::with_account_mut(&mut data, |account| {
+                account.amount = 50;
+                Err(ProgramError::InvalidArgument)
+            })
+        );
+        assert_eq!(original, data);
+
+        // closure success persists the mutation
+        State::with_account_mut(&mut data, |account| {
+            account.amount = 50;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(get_account(&data).amount, 50);
+        assert_eq!(get_account(&data).owner, new_pubkey(2));
+
+        // not an account
+        let mut data = vec![0u8; size_of::<State>()];
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::with_account_mut(&mut data, |_| Ok(()))
+        );
+    }
+
+    #[test]
+    fn test_set_delegate_allowance_in_place() {
+        let mut data = vec![0u8; size_of::<State>()];
+        let account = TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 30,
+            delegate: Some(TokenAccountDelegate {
+                source: new_pubkey(3),
+                original_amount: 100,
+                expiry_slot: 0,
+            }),
+            confidential: true,
+            commitment: [7; 32],
+            whitelist: [new_pubkey(4); MAX_WHITELIST],
+            state: AccountState::Frozen,
+            transfer_authority: Some(new_pubkey(5)),
+            owner_immutable: false,
+        };
+        State::Account(account).serialize(&mut data).unwrap();
+        let original = data.clone();
+
+        State::set_delegate_allowance_in_place(&mut data, 250, 0).unwrap();
+        assert_eq!(
+            State::deserialize(&data),
+            Ok(State::Account(TokenAccount {
+                amount: 250,
+                delegate: Some(TokenAccountDelegate {
+                    source: new_pubkey(3),
+                    original_amount: 250,
+                    expiry_slot: 0,
+                }),
+                ..account
+            }))
+        );
+
+        // only the bytes of the two allowance fields changed, the expiry stays 0
+        let changed = original
+            .iter()
+            .zip(data.iter())
+            .filter(|(before, after)| before != after)
+            .count();
+        assert!(changed > 0 && changed <= 2 * size_of::<u64>());
+
+        // not a delegate
+        let mut data = vec![0u8; size_of::<State>()];
+        State::Account(TokenAccount {
+            delegate: None,
+            ..account
+        })
+        .serialize(&mut data)
+        .unwrap();
+        let original = data.clone();
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::set_delegate_allowance_in_place(&mut data, 250, 0)
+        );
+        assert_eq!(original, data);
+
+        // not an account
+        let mut data = vec![0u8; size_of::<State>()];
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::set_delegate_allowance_in_place(&mut data, 250, 0)
+        );
+    }
+
+    #[test]
+    fn test_new_token() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(7);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // token account not created
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // too many decimals
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: Token::MAX_DECIMALS + 1,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // a supply that can't be rounded up to whole tokens at its decimals
+        let instruction = Command::NewToken(Token {
+            supply: u64::MAX,
+            decimals: 18,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 0);
+
+        // create new token, ignoring any counters supplied by the caller
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            total_minted: 5,
+            total_burned: 7,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.total_minted, 1000);
+            assert_eq!(token.total_burned, 0);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // token mismatch
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token2_key, true, &mut token2_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create delegate account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // account is a delegate token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create twice, into an empty account
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_new_token_decimals() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // too many decimals, the destination is left untouched
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 20,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 0);
+        assert_eq!(
+            Ok(State::Unallocated),
+            State::deserialize(&token_account.data)
+        );
+
+        // 9 decimals
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 9,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        if let Ok(State::Token(token)) = State::deserialize(&token_account.data) {
+            assert_eq!(token.decimals, 9);
+        } else {
+            panic!("not a token");
+        }
+    }
+
+    #[test]
+    fn test_new_token_account() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(5);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(6);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // missing signer
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create token account
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create twice
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // delegate of itself, sharing its data as in the runtime
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let delegate_account_info = account_infos[0].clone();
+        account_infos.push(delegate_account_info);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(
+            Ok(State::Unallocated),
+            State::deserialize(&delegate_account_account.data)
+        );
+
+        // delegate of an account holding another token
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // delegate
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&delegate_account_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            Some((token_account_key, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(3);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let mismatch_account_key = new_pubkey(5);
+        let mut mismatch_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let mut mismatch_delegate_account_account =
+            Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(7);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(8);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(9);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create mismatch token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&mismatch_account_key, true, &mut mismatch_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create delegate account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // mismatch delegate account, written directly since NewTokenAccount refuses it
+        State::Account(TokenAccount {
+            token: token2_key,
+            owner: owner_key,
+            delegate: Some(TokenAccountDelegate {
+                source: token_account_key,
+                original_amount: 0,
+                expiry_slot: 0,
+            }),
+            ..TokenAccount::default()
+        })
+        .serialize(&mut mismatch_delegate_account_account.data)
+        .unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // missing signer
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // destination is delegate
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // mismatch token
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&mismatch_account_key, false, &mut mismatch_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // missing owner
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // insufficient funds
+        let instruction = Command::Transfer(1);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer half back
+        let instruction = Command::Transfer(500);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer rest
+        let instruction = Command::Transfer(500);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // insufficient funds
+        let instruction = Command::Transfer(1);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // approve delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // not a delegate of source account
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer via delegate
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // insufficient funds approved via delegate
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer rest
+        let instruction = Command::Transfer(900);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // insufficient funds in source account via delegate
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_approve() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let mismatch_delegate_account_key = new_pubkey(5);
+        let mut mismatch_delegate_account_account =
+            Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(7);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(8);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(9);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create delegate account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // mismatch delegate account, written directly since NewTokenAccount refuses it
+        State::Account(TokenAccount {
+            token: token2_key,
+            owner: owner_key,
+            delegate: Some(TokenAccountDelegate {
+                source: token_account_key,
+                original_amount: 0,
+                expiry_slot: 0,
+            }),
+            ..TokenAccount::default()
+        })
+        .serialize(&mut mismatch_delegate_account_account.data)
+        .unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // token mismatch
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (
+                &mismatch_delegate_account_key,
+                false,
+                &mut mismatch_delegate_account_account,
+            ),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // missing signer
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // missing signer
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // destination is delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // not a delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // not a delegate of source
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // approve delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+    }
+
+    #[test]
+    fn test_set_owner() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(4);
+        let mut owner2_account = Account::default();
+        let owner3_key = new_pubkey(5);
+        let mut owner3_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // invalid token account
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // missing owner
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, false, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner3_key, false, &mut owner3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // owner did not sign
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // set owner
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+    }
+    #[test]
+    fn test_assert_account_owner() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(4);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // invalid token account
+        let instruction = Command::AssertAccountOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let data = token_account_account.data.clone();
+
+        // mismatched owner
+        let instruction = Command::AssertAccountOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // matching owner
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(data, token_account_account.data);
+    }
+    #[test]
+    fn test_transfer_if_below() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // below target, topped up
+        let instruction = Command::TransferIfBelow { target: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // already at target, no transfer
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // fund a small source
+        let instruction = Command::Transfer(30);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // source can't cover the full shortfall, transfers what it can
+        let instruction = Command::TransferIfBelow { target: 500 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account3_account.data).amount, 0);
+        assert_eq!(get_account(&token_account2_account.data).amount, 130);
+    }
+    #[test]
+    fn test_move_account() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let new_account_key = new_pubkey(4);
+        let mut new_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(6);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // target already allocated
+        let instruction = Command::MoveAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, true, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // missing owner
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&new_account_key, true, &mut new_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // new account not a signer
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&new_account_key, false, &mut new_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(
+            State::deserialize(&new_account_account.data).unwrap(),
+            State::Unallocated
+        );
+
+        // move account
+        let source_account = get_account(&token_account_account.data);
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&new_account_key, true, &mut new_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let new_account = get_account(&new_account_account.data);
+        assert_eq!(new_account, source_account);
+        assert_eq!(new_account.amount, 1000);
+        assert_eq!(new_account.owner, owner_key);
+        assert_eq!(
+            State::deserialize(&token_account_account.data).unwrap(),
+            State::Unallocated
+        );
+        assert!(token_account_account.data.iter().all(|byte| *byte == 0));
+    }
+    #[test]
+    fn test_transfer_max() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account4_key = new_pubkey(5);
+        let mut token_account4_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(8);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create token account for an unlimited token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account for an unlimited token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account4_key, true, &mut token_account4_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token with a transfer maximum
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            max_transfer: 100,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new unlimited token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            max_transfer: 0,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token2_key, true, &mut token2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer too large
+        let instruction = Command::Transfer(150);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TransferTooLarge.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer at the maximum
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // unlimited token allows any amount
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account4_account.data).amount, 1000);
+    }
+    #[test]
+    fn test_transfer_and_close_if_empty() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(10, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(10, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(10, size_of::<State>(), &program_id);
+        let beneficiary_key = new_pubkey(5);
+        let mut beneficiary_account = Account::new(3, 0, &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // partial transfer leaves the source open
+        let instruction = Command::TransferAndCloseIfEmpty { amount: 400 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 600);
+        assert_eq!(get_account(&token_account2_account.data).amount, 400);
+        assert_eq!(token_account_account.lamports, 10);
+        assert_eq!(beneficiary_account.lamports, 3);
+
+        // full sweep closes the source
+        let instruction = Command::TransferAndCloseIfEmpty { amount: 600 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account3_account.data).amount, 600);
+        assert_eq!(
+            State::deserialize(&token_account_account.data).unwrap(),
+            State::Unallocated
+        );
+        assert!(token_account_account.data.iter().all(|byte| *byte == 0));
+        assert_eq!(token_account_account.lamports, 0);
+        assert_eq!(beneficiary_account.lamports, 13);
+    }
+    #[test]
+    fn test_require_owner() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(6);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create delegate account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // wrong owner: transfer
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // wrong owner: approve
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // wrong owner: set owner
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // owner did not sign: transfer
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // owner did not sign: approve
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // owner did not sign: set owner
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+    #[test]
+    fn test_new_token_distributed() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create another token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // distribution doesn't sum to supply
+        let instruction = Command::NewTokenDistributed(
+            Token {
+                supply: 1000,
+                decimals: 2,
+                ..Token::default()
+            },
+            vec![500, 300, 100],
+        );
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        assert_eq!(Ok(instruction), Command::deserialize(&instruction_data));
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 0);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+        assert_eq!(get_account(&token_account3_account.data).amount, 0);
+        assert_eq!(
+            State::deserialize(&token_account.data).unwrap(),
+            State::Unallocated
+        );
+
+        // distribute
+        let instruction = Command::NewTokenDistributed(
+            Token {
+                supply: 1000,
+                decimals: 2,
+                ..Token::default()
+            },
+            vec![500, 300, 200],
+        );
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 500);
+        assert_eq!(get_account(&token_account2_account.data).amount, 300);
+        assert_eq!(get_account(&token_account3_account.data).amount, 200);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 1000);
+            assert_eq!(token.total_minted, 1000);
+        } else {
+            panic!("not a token");
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingAuditHook {
+        transfers: u64,
+        transferred: u64,
+        mints: u64,
+        minted: u64,
+        approvals: u64,
+    }
+    impl AuditHook for CountingAuditHook {
+        fn on_transfer(&mut self, _source: &Pubkey, _dest: &Pubkey, amount: u64) {
+            self.transfers += 1;
+            self.transferred += amount;
+        }
+        fn on_mint(&mut self, _token: &Pubkey, _dest: &Pubkey, amount: u64) {
+            self.mints += 1;
+            self.minted += amount;
+        }
+        fn on_approve(&mut self, _source: &Pubkey, _delegate: &Pubkey, _amount: u64) {
+            self.approvals += 1;
+        }
+    }
+
+    #[test]
+    fn test_audit_hook() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let mut hook = CountingAuditHook::default();
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        assert_eq!(hook.mints, 1);
+        assert_eq!(hook.minted, 1000);
+
+        // transfer twice
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        assert_eq!(hook.transfers, 2);
+        assert_eq!(hook.transferred, 200);
+
+        // failed transfer is not reported
+        let instruction = Command::Transfer(10_000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process_with_hook(
+                &program_id,
+                &mut account_infos,
+                &instruction_data,
+                &mut hook
+            )
+        );
+        assert_eq!(hook.transfers, 2);
+
+        // approve
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process_with_hook(
+            &program_id,
+            &mut account_infos,
+            &instruction_data,
+            &mut hook,
+        )
+        .unwrap();
+        assert_eq!(hook.approvals, 1);
+        assert_eq!(hook.mints, 1);
+        assert_eq!(hook.transfers, 2);
+
+        // a transfer undone by a violated invariant is not reported
+        let instruction = Command::TransferWithInvariant {
+            amount: 100,
+            expected_total: 1,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InvariantViolated.into()),
+            State::process_with_hook(
+                &program_id,
+                &mut account_infos,
+                &instruction_data,
+                &mut hook
+            )
+        );
+        assert_eq!(hook.transfers, 2);
+        assert_eq!(hook.transferred, 200);
+
+        // an approval undone by a failed transfer is not reported
+        let instruction = Command::ApproveAndTransfer {
+            approve: 50,
+            transfer: 80,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process_with_hook(
+                &program_id,
+                &mut account_infos,
+                &instruction_data,
+                &mut hook
+            )
+        );
+        assert_eq!(hook.approvals, 1);
+        assert_eq!(hook.transfers, 2);
+    }
+
+    #[test]
+    fn test_swap() {
+        let program_id = new_pubkey(1);
+        let account_a_x_key = new_pubkey(2);
+        let mut account_a_x_account = Account::new(0, size_of::<State>(), &program_id);
+        let account_a_y_key = new_pubkey(3);
+        let mut account_a_y_account = Account::new(0, size_of::<State>(), &program_id);
+        let account_b_y_key = new_pubkey(4);
+        let mut account_b_y_account = Account::new(0, size_of::<State>(), &program_id);
+        let account_b_x_key = new_pubkey(5);
+        let mut account_b_x_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_a_key = new_pubkey(6);
+        let mut owner_a_account = Account::default();
+        let owner_b_key = new_pubkey(7);
+        let mut owner_b_account = Account::default();
+        let token_x_key = new_pubkey(8);
+        let mut token_x_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_y_key = new_pubkey(9);
+        let mut token_y_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&account_a_x_key, true, &mut account_a_x_account),
+            (&owner_a_key, false, &mut owner_a_account),
+            (&token_x_key, false, &mut token_x_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&account_a_y_key, true, &mut account_a_y_account),
+            (&owner_a_key, false, &mut owner_a_account),
+            (&token_y_key, false, &mut token_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&account_b_y_key, true, &mut account_b_y_account),
+            (&owner_b_key, false, &mut owner_b_account),
+            (&token_y_key, false, &mut token_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&account_b_x_key, true, &mut account_b_x_account),
+            (&owner_b_key, false, &mut owner_b_account),
+            (&token_x_key, false, &mut token_x_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create tokens X and Y
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_x_key, true, &mut token_x_account),
+            (&account_a_x_key, false, &mut account_a_x_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 500,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_y_key, true, &mut token_y_account),
+            (&account_b_y_key, false, &mut account_b_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // missing signer
+        let instruction = Command::Swap {
+            amount_a: 100,
+            amount_b: 50,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_a_key, true, &mut owner_a_account),
+            (&account_a_x_key, false, &mut account_a_x_account),
+            (&account_a_y_key, false, &mut account_a_y_account),
+            (&owner_b_key, false, &mut owner_b_account),
+            (&account_b_y_key, false, &mut account_b_y_account),
+            (&account_b_x_key, false, &mut account_b_x_account),
+            (&token_x_key, false, &mut token_x_account),
+            (&token_y_key, false, &mut token_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // swap
+        let mut accounts = vec![
+            (&owner_a_key, true, &mut owner_a_account),
+            (&account_a_x_key, false, &mut account_a_x_account),
+            (&account_a_y_key, false, &mut account_a_y_account),
+            (&owner_b_key, true, &mut owner_b_account),
+            (&account_b_y_key, false, &mut account_b_y_account),
+            (&account_b_x_key, false, &mut account_b_x_account),
+            (&token_x_key, false, &mut token_x_account),
+            (&token_y_key, false, &mut token_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&account_a_x_account.data).amount, 900);
+        assert_eq!(get_account(&account_a_y_account.data).amount, 50);
+        assert_eq!(get_account(&account_b_y_account.data).amount, 450);
+        assert_eq!(get_account(&account_b_x_account.data).amount, 100);
+
+        // party B is short, neither side moves
+        let instruction = Command::Swap {
+            amount_a: 100,
+            amount_b: 1000,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_a_key, true, &mut owner_a_account),
+            (&account_a_x_key, false, &mut account_a_x_account),
+            (&account_a_y_key, false, &mut account_a_y_account),
+            (&owner_b_key, true, &mut owner_b_account),
+            (&account_b_y_key, false, &mut account_b_y_account),
+            (&account_b_x_key, false, &mut account_b_x_account),
+            (&token_x_key, false, &mut token_x_account),
+            (&token_y_key, false, &mut token_y_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&account_a_x_account.data).amount, 900);
+        assert_eq!(get_account(&account_a_y_account.data).amount, 50);
+        assert_eq!(get_account(&account_b_y_account.data).amount, 450);
+        assert_eq!(get_account(&account_b_x_account.data).amount, 100);
+    }
+
+    #[test]
+    fn test_check_transfer() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let mismatch_account_key = new_pubkey(5);
+        let mut mismatch_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(8);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+        let result_key = new_pubkey(9);
+        let mut result_account = Account::new(0, size_of::<u8>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&mismatch_account_key, true, &mut mismatch_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            max_transfer: 500,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve delegate
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // allowed
+        let instruction = Command::CheckTransfer { amount: 500 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], TransferCheck::Allowed as u8);
+
+        // token mismatch
+        let instruction = Command::CheckTransfer { amount: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&mismatch_account_key, false, &mut mismatch_account_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], TransferCheck::TokenMismatch as u8);
+
+        // destination is a delegate
+        let instruction = Command::CheckTransfer { amount: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            result_account.data[0],
+            TransferCheck::DestinationIsDelegate as u8
+        );
+
+        // over the token's maximum
+        let instruction = Command::CheckTransfer { amount: 501 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            result_account.data[0],
+            TransferCheck::TransferTooLarge as u8
+        );
+
+        // insufficient funds
+        let instruction = Command::CheckTransfer { amount: 1 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            result_account.data[0],
+            TransferCheck::InsufficientFunds as u8
+        );
+
+        // delegate within allowance
+        let instruction = Command::CheckTransfer { amount: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], TransferCheck::Allowed as u8);
+
+        // delegate over allowance
+        let instruction = Command::CheckTransfer { amount: 101 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            result_account.data[0],
+            TransferCheck::InsufficientFunds as u8
+        );
+
+        // delegate with the wrong source
+        let instruction = Command::CheckTransfer { amount: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+            (&mismatch_account_key, false, &mut mismatch_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], TransferCheck::NotDelegate as u8);
+
+        // nothing moved
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+        assert_eq!(get_account(&delegate_account_account.data).amount, 100);
+    }
+
+    #[test]
+    fn test_check_peg() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let solvent_reserve_key = new_pubkey(5);
+        let mut solvent_reserve_account = Account::new(0, size_of::<State>(), &program_id);
+        let short_reserve_key = new_pubkey(6);
+        let mut short_reserve_account = Account::new(0, size_of::<State>(), &program_id);
+        let result_key = new_pubkey(7);
+        let mut result_account = Account::new(0, size_of::<u8>(), &program_id);
+        let reserve_token_key = new_pubkey(8);
+        let mut reserve_token_account = Account::new(0, size_of::<State>(), &program_id);
+        let other_token_key = new_pubkey(9);
+        let mut other_token_account = Account::default();
+        let lamports_reserve_key = new_pubkey(10);
+        let mut lamports_reserve_account = Account::new(5000, 0, &program_id);
+
+        // create the token account and the two reserves
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&solvent_reserve_key, true, &mut solvent_reserve_account),
+            (&owner_key, false, &mut owner_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&short_reserve_key, true, &mut short_reserve_account),
+            (&owner_key, false, &mut owner_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token pegged at 2 reserve units per token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            peg_ratio: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // fund the reserves with 2000 and 1999 of the reserve token
+        let instruction = Command::NewToken(Token {
+            supply: 3999,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&reserve_token_key, true, &mut reserve_token_account),
+            (&solvent_reserve_key, false, &mut solvent_reserve_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction_data = Command::Transfer(1999).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&solvent_reserve_key, false, &mut solvent_reserve_account),
+            (&short_reserve_key, false, &mut short_reserve_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // solvent reserve
+        let instruction = Command::CheckPeg;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&solvent_reserve_key, false, &mut solvent_reserve_account),
+            (&result_key, false, &mut result_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], 1);
+
+        // under-collateralized reserve
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&short_reserve_key, false, &mut short_reserve_account),
+            (&result_key, false, &mut result_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], 0);
+
+        // reserve of another token than claimed
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&solvent_reserve_key, false, &mut solvent_reserve_account),
+            (&result_key, false, &mut result_account),
+            (&other_token_key, false, &mut other_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // the pegged token backing itself
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        account_infos.push(account_infos[0].clone());
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // lamports are not reserve units
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&lamports_reserve_key, false, &mut lamports_reserve_account),
+            (&result_key, false, &mut result_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // a look-alike reserve owned by another program
+        let mut foreign_reserve_account = Account::new(0, size_of::<State>(), &new_pubkey(11));
+        foreign_reserve_account
+            .data
+            .copy_from_slice(&solvent_reserve_account.data);
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&solvent_reserve_key, false, &mut foreign_reserve_account),
+            (&result_key, false, &mut result_account),
+            (&reserve_token_key, false, &mut reserve_token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_set_confidential() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let commitment = [7u8; 32];
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).commitment(), None);
+
+        // missing signer
+        let instruction = Command::SetConfidential { commitment };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).commitment(), None);
+
+        // enable confidentiality
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account_account.data);
+        assert!(account.confidential);
+        assert_eq!(account.commitment(), Some(commitment));
+        assert_eq!(account.amount, 0);
+
+        // round trip the extended account
+        let mut output = vec![0u8; size_of::<State>()];
+        State::Account(account).serialize(&mut output).unwrap();
+        assert_eq!(Ok(State::Account(account)), State::deserialize(&output));
+    }
+
+    #[test]
+    fn test_new_account_and_approve() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(3);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(4);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // missing owner signature
+        let instruction = Command::NewAccountAndApprove { allowance: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create both accounts
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account_account.data);
+        assert_eq!(account.token, token_key);
+        assert_eq!(account.owner, owner_key);
+        assert_eq!(account.amount, 0);
+        assert_eq!(account.delegate, None);
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.token, token_key);
+        assert_eq!(delegate_account.owner, owner_key);
+        assert_eq!(delegate_account.amount, 100);
+        assert_eq!(
+            delegate_account.delegate,
+            Some(TokenAccountDelegate {
+                source: token_account_key,
+                original_amount: 100,
+                expiry_slot: 0,
+            })
+        );
+
+        // delegate account already allocated
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, true, &mut token_account2_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(
+            Ok(State::Unallocated),
+            State::deserialize(&token_account2_account.data)
+        );
+    }
+
+    #[test]
+    fn test_set_whitelist() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let mut other_accounts = vec![Account::default(); MAX_WHITELIST];
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // missing signer
+        let instruction = Command::SetWhitelist;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // too many destinations
+        let other_keys: Vec<Pubkey> = (0..MAX_WHITELIST)
+            .map(|i| new_pubkey(10 + i as u8))
+            .collect();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        for (key, account) in other_keys.iter().zip(other_accounts.iter_mut()) {
+            accounts.push((key, false, account));
+        }
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // whitelist token_account2
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account_account.data);
+        assert!(account.allows_destination(&token_account2_key));
+        assert!(!account.allows_destination(&token_account3_key));
+
+        // transfer to whitelisted destination
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // transfer to destination not on the whitelist
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotWhitelisted.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account3_account.data).amount, 0);
+
+        // clear the whitelist
+        let instruction = Command::SetWhitelist;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account3_account.data).amount, 100);
+    }
+
+    #[test]
+    fn test_batch_balance() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let result_key = new_pubkey(6);
+        let mut result_account = Account::new(0, 3 * size_of::<u64>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer
+        let instruction = Command::Transfer(300);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // read both accounts and the token, which reads as 0
+        let instruction = Command::BatchBalance;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut expected = vec![];
+        expected.extend_from_slice(&700u64.to_le_bytes());
+        expected.extend_from_slice(&0u64.to_le_bytes());
+        expected.extend_from_slice(&300u64.to_le_bytes());
+        assert_eq!(result_account.data, expected);
+
+        // result account too small
+        let mut result_account = Account::new(0, 2 * size_of::<u64>(), &program_id);
+        let mut accounts = vec![
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_consume_nonce() {
+        let program_id = new_pubkey(1);
+        let nonce_key = new_pubkey(2);
+        let mut nonce_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // missing signer
+        let instruction = Command::NewNonce(42);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&nonce_key, false, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create nonce
+        let mut accounts = vec![(&nonce_key, true, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            Ok(State::Nonce {
+                value: 42,
+                consumed: false
+            }),
+            State::deserialize(&nonce_account.data)
+        );
+
+        // missing signer
+        let instruction = Command::ConsumeNonce(42);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&nonce_key, false, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // value mismatch
+        let instruction = Command::ConsumeNonce(41);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&nonce_key, true, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // consume
+        let instruction = Command::ConsumeNonce(42);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&nonce_key, true, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            Ok(State::Nonce {
+                value: 42,
+                consumed: true
+            }),
+            State::deserialize(&nonce_account.data)
+        );
+
+        // replay
+        let mut accounts = vec![(&nonce_key, true, &mut nonce_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NonceConsumed.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_transfer_if_price() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let oracle_key = new_pubkey(6);
+        let mut oracle_account = Account::new(0, size_of::<u64>(), &new_pubkey(7));
+        oracle_account.data = 50u64.to_le_bytes().to_vec();
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // price below threshold
+        let instruction = Command::TransferIfPrice {
+            amount: 100,
+            min_price: 51,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&oracle_key, false, &mut oracle_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::PriceConditionNotMet.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // price at threshold
+        let instruction = Command::TransferIfPrice {
+            amount: 100,
+            min_price: 50,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&oracle_key, false, &mut oracle_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // price above threshold
+        let instruction = Command::TransferIfPrice {
+            amount: 200,
+            min_price: 10,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&oracle_key, false, &mut oracle_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 700);
+        assert_eq!(get_account(&token_account2_account.data).amount, 300);
+    }
+
+    #[test]
+    fn test_transfer_if_holds() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // destination below the minimum
+        let instruction = Command::TransferIfHolds {
+            amount: 100,
+            min_holding: 1,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::HoldingConditionNotMet.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // no minimum
+        let instruction = Command::TransferIfHolds {
+            amount: 100,
+            min_holding: 0,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // destination meets the minimum
+        let instruction = Command::TransferIfHolds {
+            amount: 50,
+            min_holding: 100,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 850);
+        assert_eq!(get_account(&token_account2_account.data).amount, 150);
+    }
+
+    #[test]
+    fn test_rotate_owner() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account2_key = new_pubkey(5);
+        let mut delegate_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(7);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(8);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token, token2, and two delegate accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account2_key, true, &mut delegate_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approvals check the token's pause, so it must exist
+        State::Token(Token::default())
+            .serialize(&mut token_account.data)
+            .unwrap();
+
+        // approve both delegates
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (
+                &delegate_account2_key,
+                false,
+                &mut delegate_account2_account,
+            ),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // not a delegate of the account, nothing changes
+        let instruction = Command::RotateOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).owner, owner_key);
+        assert_eq!(get_account(&delegate_account_account.data).amount, 100);
+
+        // missing signer
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (
+                &delegate_account2_key,
+                false,
+                &mut delegate_account2_account,
+            ),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 100);
+
+        // rotate and revoke both delegates
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (
+                &delegate_account2_key,
+                false,
+                &mut delegate_account2_account,
+            ),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).owner, owner2_key);
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.amount, 0);
+        assert_eq!(
+            delegate_account.delegate,
+            Some(TokenAccountDelegate {
+                source: token_account_key,
+                original_amount: 0,
+                expiry_slot: 0,
+            })
+        );
+        assert_eq!(get_account(&delegate_account2_account.data).amount, 0);
+    }
+
+    #[test]
+    fn test_program_info() {
+        let program_id = new_pubkey(1);
+        let result_key = new_pubkey(2);
+        let mut result_account = Account::new(0, 5, &program_id);
+
+        let instruction = Command::ProgramInfo;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&result_key, false, &mut result_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[0], PROGRAM_VERSION);
+        let mut flags = [0u8; 4];
+        flags.copy_from_slice(&result_account.data[1..5]);
+        let flags = u32::from_le_bytes(flags);
+        assert_eq!(flags, feature_flags());
+        assert_eq!(flags & FEATURE_CLIENT != 0, cfg!(feature = "client"));
+        assert_eq!(
+            flags & FEATURE_DEBUG_BALANCE_TRACE != 0,
+            cfg!(feature = "DEBUG_BALANCE_TRACE")
+        );
+        assert_eq!(flags & FEATURE_STRICT != 0, cfg!(feature = "strict"));
+        assert_eq!(
+            flags & !(FEATURE_CLIENT | FEATURE_DEBUG_BALANCE_TRACE | FEATURE_STRICT),
+            0
+        );
+
+        // result account too small
+        let mut result_account = Account::new(0, 4, &program_id);
+        let mut accounts = vec![(&result_key, false, &mut result_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_transfer_with_invariant() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // fund the invariant account
+        let instruction = Command::Transfer(300);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // invariant holds
+        let instruction = Command::TransferWithInvariant {
+            amount: 100,
+            expected_total: 300,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 600);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // invariant violated, the runtime discards the transfer
+        let instruction = Command::TransferWithInvariant {
+            amount: 100,
+            expected_total: 100,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let saved_accounts = (
+            token_account_account.clone(),
+            token_account2_account.clone(),
+        );
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account3_key, false, &mut token_account3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InvariantViolated.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let (saved_account, saved_account2) = saved_accounts;
+        token_account_account = saved_account;
+        token_account2_account = saved_account2;
+
+        // no invariant accounts sum to zero
+        let instruction = Command::TransferWithInvariant {
+            amount: 100,
+            expected_total: 200,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InvariantViolated.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_mint_to_new() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(6);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account and token with a capped supply
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            max_supply: 1500,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // mint authority must sign
+        let instruction = Command::MintToNew(400);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create and mint
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account2_account.data);
+        assert_eq!(account.token, token_key);
+        assert_eq!(account.owner, owner2_key);
+        assert_eq!(account.amount, 400);
+        assert_eq!(account.delegate, None);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 1400);
+            assert_eq!(token.total_minted, 1400);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+
+        // destination already exists
+        let instruction = Command::MintToNew(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account2_account.data).amount, 400);
+
+        // cap would be exceeded
+        let instruction = Command::MintToNew(101);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::SupplyCapExceeded.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(
+            State::deserialize(&token_account3_account.data),
+            Ok(State::Unallocated)
+        );
+
+        // exactly reaching the cap
+        let instruction = Command::MintToNew(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner2_key, false, &mut owner2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account3_account.data).amount, 100);
+    }
+
+    #[test]
+    #[cfg(feature = "DEBUG_BALANCE_TRACE")]
+    fn test_balance_trace() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer logs every token account before and after
+        BALANCE_TRACE.with(|trace| trace.borrow_mut().clear());
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            BALANCE_TRACE.with(|trace| trace.borrow().clone()),
+            vec![
+                (BalanceTraceStage::Pre, token_account_key, 1000),
+                (BalanceTraceStage::Pre, token_account2_key, 0),
+                (BalanceTraceStage::Post, token_account_key, 900),
+                (BalanceTraceStage::Post, token_account2_key, 100),
+            ]
+        );
+
+        // a failed instruction logs no post balances
+        BALANCE_TRACE.with(|trace| trace.borrow_mut().clear());
+        let instruction = Command::Transfer(1000);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert!(State::process(&program_id, &mut account_infos, &instruction_data).is_err());
+        assert_eq!(
+            BALANCE_TRACE.with(|trace| trace.borrow().clone()),
+            vec![
+                (BalanceTraceStage::Pre, token_account_key, 900),
+                (BalanceTraceStage::Pre, token_account2_key, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_distribute() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account4_key = new_pubkey(5);
+        let mut token_account4_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account4_key, true, &mut token_account4_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 100,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // distribute 10 each
+        let instruction = Command::Distribute(10);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 70);
+        assert_eq!(get_account(&token_account2_account.data).amount, 10);
+        assert_eq!(get_account(&token_account3_account.data).amount, 10);
+        assert_eq!(get_account(&token_account4_account.data).amount, 10);
+
+        // source can't cover the total, nothing moves
+        let instruction = Command::Distribute(30);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 70);
+        assert_eq!(get_account(&token_account2_account.data).amount, 10);
+        assert_eq!(get_account(&token_account3_account.data).amount, 10);
+        assert_eq!(get_account(&token_account4_account.data).amount, 10);
+
+        // owner must sign
+        let instruction = Command::Distribute(10);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // no destinations
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 70);
+    }
+
+    #[test]
+    fn test_get_account() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let result_key = new_pubkey(3);
+        let mut result_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        let account = TokenAccount {
+            token: token_key,
+            owner: new_pubkey(5),
+            amount: 123,
+            delegate: Some(TokenAccountDelegate {
+                source: new_pubkey(6),
+                original_amount: 456,
+                expiry_slot: 0,
+            }),
+            confidential: true,
+            commitment: [7; 32],
+            whitelist: [new_pubkey(8); MAX_WHITELIST],
+            state: AccountState::Frozen,
+            transfer_authority: Some(new_pubkey(9)),
+            owner_immutable: false,
+        };
+        State::Account(account)
+            .serialize(&mut token_account_account.data)
+            .unwrap();
+
+        // round trips
+        let instruction = Command::GetAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&result_account.data),
+            Ok(State::Account(account))
+        );
+
+        // not an account
+        State::Token(Token::default())
+            .serialize(&mut token_account.data)
+            .unwrap();
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotAnAccount.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // result account too small
+        let mut result_account = Account::new(0, TokenAccount::LEN, &program_id);
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&result_key, false, &mut result_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_approve_if_unused() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(3);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create delegate account
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // fresh delegate
+        let instruction = Command::ApproveIfUnused(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 100);
+
+        // active delegate
+        let instruction = Command::ApproveIfUnused(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::DelegateBusy.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.amount, 100);
+        assert_eq!(delegate_account.delegate.unwrap().original_amount, 100);
+
+        // revoke, then approve again
+        let instruction = Command::Revoke;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // a zero allowance is rejected even for an unused delegate
+        let instruction = Command::ApproveIfUnused(0);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 0);
+
+        let instruction = Command::ApproveIfUnused(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 50);
+    }
+
+    #[test]
+    fn test_transfer_to_self() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // source and destination are the same account, sharing its data as in the runtime
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let source_account_info = account_infos[1].clone();
+        account_infos.insert(2, source_account_info);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+    }
+
+    #[test]
+    fn test_transfer_overflow() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // destination near the maximum balance, with a supply to match
+        State::Account(TokenAccount {
+            amount: u64::MAX - 10,
+            ..get_account(&token_account2_account.data)
+        })
+        .serialize(&mut token_account2_account.data)
+        .unwrap();
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            State::Token(Token {
+                supply: u64::MAX,
+                total_minted: u64::MAX,
+                ..token
+            })
+            .serialize(&mut token_account.data)
+            .unwrap();
+        } else {
+            panic!("not a token");
+        }
+
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::Overflow.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(
+            get_account(&token_account2_account.data).amount,
+            u64::MAX - 10
+        );
+
+        // up to the maximum is fine
+        let instruction = Command::Transfer(10);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 990);
+        assert_eq!(get_account(&token_account2_account.data).amount, u64::MAX);
+    }
+
+    #[test]
+    fn test_transfer_reordered_accounts() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(5);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts with different owners
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner2_key, false, &mut owner2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // source and destination swapped
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // destination and token swapped
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+
+        // owner and source swapped
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // documented order
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+    }
+
+    #[test]
+    fn test_sync_mirror() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let mirror_key = new_pubkey(4);
+        let mut mirror_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let clock_key = sysvar::clock::id();
+        let mut clock_account = Account::new(0, size_of::<u64>(), &program_id);
+        clock_account.data.copy_from_slice(&10u64.to_le_bytes());
+
+        // create token accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // creating a mirror requires its signature
+        let instruction = Command::SyncMirror;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&mirror_key, false, &mut mirror_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&clock_key, false, &mut clock_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // sync
+        let mut accounts = vec![
+            (&mirror_key, true, &mut mirror_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&clock_key, false, &mut clock_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&mirror_account.data),
+            Ok(State::Mirror {
+                source: token_account_key,
+                amount: 1000,
+                slot: 10,
+            })
+        );
+
+        // change the balance
+        let instruction = Command::Transfer(300);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // anyone can re-sync
+        clock_account.data.copy_from_slice(&11u64.to_le_bytes());
+        let instruction = Command::SyncMirror;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&mirror_key, false, &mut mirror_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&clock_key, false, &mut clock_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&mirror_account.data),
+            Ok(State::Mirror {
+                source: token_account_key,
+                amount: 700,
+                slot: 11,
+            })
+        );
+
+        // mirror of another account
+        let mut accounts = vec![
+            (&mirror_key, false, &mut mirror_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&clock_key, false, &mut clock_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // not the clock sysvar
+        let mut accounts = vec![
+            (&mirror_key, false, &mut mirror_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_burn() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(3);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(5);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // serde
+        let instruction = Command::Burn(123);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        assert_eq!(Ok(instruction), Command::deserialize(&instruction_data));
+
+        // create token account, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // burn
+        let instruction = Command::Burn(300);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 700);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 700);
+            assert_eq!(token.decimals, 2);
+            assert_eq!(token.total_burned, 300);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+
+        // not the owner
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // insufficient funds
+        let instruction = Command::Burn(701);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // delegate
+        let instruction = Command::Burn(1);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 700);
+    }
+
+    #[test]
+    fn test_mint_to() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let mismatch_account_key = new_pubkey(5);
+        let mut mismatch_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(8);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&mismatch_account_key, true, &mut mismatch_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // mint twice
+        let instruction = Command::MintTo(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::MintTo(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 150);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 1150);
+            assert_eq!(token.total_minted, 1150);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+
+        // mint authority must sign
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // destination of another token
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&mismatch_account_key, false, &mut mismatch_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // delegate destination
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 1150);
+        } else {
+            panic!("not a token");
+        }
+    }
+
+    #[test]
+    fn test_supply_overflow() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create account and a token holding the largest possible supply
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: u64::MAX,
+            decimals: 0,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // minting more overflows the supply
+        let instruction = Command::MintTo(1);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::Overflow.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, u64::MAX);
+        if let Ok(State::Token(token)) = State::deserialize(&token_account.data) {
+            assert_eq!(token.supply, u64::MAX);
+            assert_eq!(token.total_minted, u64::MAX);
+        } else {
+            panic!("not a token");
+        }
+
+        // the lifetime minted total can overflow even when the supply has room
+        let mut token = Token {
+            supply: 0,
+            total_minted: u64::MAX,
+            total_burned: u64::MAX,
+            ..Token::default()
+        };
+        assert_eq!(Err(TokenError::Overflow.into()), token.mint(1));
+    }
+
+    #[test]
+    fn test_mint_authority() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let authority_key = new_pubkey(5);
+        let mut authority_account = Account::default();
+        let other_key = new_pubkey(6);
+        let mut other_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(8);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // authority must sign to be assigned
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            mint_authority: other_key,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&authority_key, false, &mut authority_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // explicit authority, the payload's is ignored
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            mint_authority: other_key,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&authority_key, true, &mut authority_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.mint_authority, authority_key);
+        } else {
+            panic!("not a token");
+        }
+
+        // default authority is the token itself
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token2_key, true, &mut token2_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        if let State::Token(token) = State::deserialize(&token2_account.data).unwrap() {
+            assert_eq!(token.mint_authority, token2_key);
+        } else {
+            panic!("not a token");
+        }
+
+        // token signing isn't enough once an authority is set
+        let instruction = Command::MintTo(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // non-authority signer
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&other_key, true, &mut other_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // authority didn't sign
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&authority_key, false, &mut authority_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+
+        // authority signed
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&authority_key, true, &mut authority_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 1100);
+
+        // legacy tokens without an authority are minted by the token itself
+        let legacy = State::Token(Token {
+            supply: 1000,
+            decimals: 2,
+            total_minted: 1000,
+            ..Token::default()
+        });
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+        legacy.serialize(&mut token2_account.data).unwrap();
+        let mut accounts = vec![
+            (&token2_key, true, &mut token2_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 1100);
+    }
+
+    #[test]
+    fn test_close_account() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(7, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(5);
+        let mut owner2_account = Account::default();
+        let beneficiary_key = new_pubkey(6);
+        let mut beneficiary_account = Account::new(3, 0, &program_id);
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // nonzero balance
+        let instruction = Command::CloseAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+
+        // not the owner
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // empty account
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&token_account2_account.data),
+            Ok(State::Unallocated)
+        );
+        assert!(token_account2_account.data.iter().all(|byte| *byte == 0));
+        assert_eq!(token_account2_account.lamports, 0);
+        assert_eq!(beneficiary_account.lamports, 10);
+    }
+
+    #[test]
+    fn test_revoke() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(6);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve 50
+        let instruction = Command::Approve(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // not the owner
+        let instruction = Command::Revoke;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner2_key, true, &mut owner2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 50);
+
+        // not a delegate
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // delegate of another source
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NotDelegate.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // revoke
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.amount, 0);
+        assert_eq!(delegate_account.delegate.unwrap().original_amount, 50);
+    }
+
+    #[test]
+    fn test_transfer_checked() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // decimals mismatch
+        let instruction = Command::TransferChecked {
+            amount: 100,
+            decimals: 9,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // decimals match
+        let instruction = Command::TransferChecked {
+            amount: 100,
+            decimals: 2,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // through a delegate
+        let instruction = Command::Approve(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::TransferChecked {
+            amount: 50,
+            decimals: 2,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 850);
+        assert_eq!(get_account(&token_account2_account.data).amount, 150);
+        assert_eq!(get_account(&delegate_account_account.data).amount, 0);
+    }
+
+    #[test]
+    fn test_multisig() {
+        let program_id = new_pubkey(1);
+        let multisig_key = new_pubkey(2);
+        let mut multisig_account = Account::new(0, size_of::<State>(), &program_id);
+        let signer1_key = new_pubkey(3);
+        let mut signer1_account = Account::default();
+        let signer2_key = new_pubkey(4);
+        let mut signer2_account = Account::default();
+        let signer3_key = new_pubkey(5);
+        let mut signer3_account = Account::default();
+        let token_account_key = new_pubkey(6);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(7);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_key = new_pubkey(8);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let new_owner_key = new_pubkey(9);
+        let mut new_owner_account = Account::default();
+
+        // threshold above the number of signers
+        let instruction = Command::NewMultisig(4);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&multisig_key, true, &mut multisig_account),
+            (&signer1_key, false, &mut signer1_account),
+            (&signer2_key, false, &mut signer2_account),
+            (&signer3_key, false, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // repeated signer
+        let instruction = Command::NewMultisig(2);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&multisig_key, true, &mut multisig_account),
+            (&signer1_key, false, &mut signer1_account),
+            (&signer2_key, false, &mut signer2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let signer = account_infos[1].clone();
+        account_infos.push(signer);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // 2 of 3
+        let mut accounts = vec![
+            (&multisig_key, true, &mut multisig_account),
+            (&signer1_key, false, &mut signer1_account),
+            (&signer2_key, false, &mut signer2_account),
+            (&signer3_key, false, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        match State::deserialize(&multisig_account.data).unwrap() {
+            State::Multisig { m, signers } => {
+                assert_eq!(m, 2);
+                assert_eq!(signers[..3], [signer1_key, signer2_key, signer3_key]);
+                assert_eq!(signers[3], Pubkey::default());
+            }
+            _ => panic!("not a multisig"),
+        }
+
+        // create token accounts owned by the multisig, and the token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&multisig_key, false, &mut multisig_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&multisig_key, false, &mut multisig_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // only 1 of 3 signed
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&signer1_key, true, &mut signer1_account),
+            (&signer3_key, false, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // the same signer twice
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&signer1_key, true, &mut signer1_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let signer = account_infos[4].clone();
+        account_infos.push(signer);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // 2 of 3 signed
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&signer1_key, true, &mut signer1_account),
+            (&signer3_key, true, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // the same multisig data held by another program is not trusted
+        multisig_account.owner = new_pubkey(10);
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&signer1_key, true, &mut signer1_account),
+            (&signer3_key, true, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        multisig_account.owner = program_id;
+
+        // set owner with 1 of 3, then 2 of 3
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&new_owner_key, false, &mut new_owner_account),
+            (&signer2_key, true, &mut signer2_account),
+            (&signer3_key, false, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).owner, multisig_key);
+        let mut accounts = vec![
+            (&multisig_key, false, &mut multisig_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&new_owner_key, false, &mut new_owner_account),
+            (&signer2_key, true, &mut signer2_account),
+            (&signer3_key, true, &mut signer3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            get_account(&token_account_account.data).owner,
+            new_owner_key
+        );
+    }
+
+    #[test]
+    fn test_approve_checked() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(7);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, delegate and tokens
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token2_key, true, &mut token2_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // decimals mismatch
+        let instruction = Command::ApproveChecked {
+            amount: 50,
+            decimals: 3,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 0);
+
+        // another token with the same decimals
+        let instruction = Command::ApproveChecked {
+            amount: 50,
+            decimals: 2,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::TokenMismatch.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 0);
+
+        // matching token and decimals
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 50);
+    }
+
+    #[test]
+    fn test_delegate_partial_spend() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve 100
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // spend 60 of the allowance
+        let instruction = Command::Transfer(60);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.amount, 40);
+        assert_eq!(delegate_account.delegate.unwrap().original_amount, 100);
+        assert_eq!(get_account(&token_account_account.data).amount, 940);
+        assert_eq!(get_account(&token_account2_account.data).amount, 60);
+
+        // another 60 exceeds what remains, even though the source could cover it
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let delegate_account = get_account(&delegate_account_account.data);
+        assert_eq!(delegate_account.amount, 40);
+        assert_eq!(delegate_account.delegate.unwrap().original_amount, 100);
+        assert_eq!(get_account(&token_account_account.data).amount, 940);
+        assert_eq!(get_account(&token_account2_account.data).amount, 60);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let freeze_key = new_pubkey(7);
+        let mut freeze_account = Account::default();
+
+        // create token accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            freeze_authority: Some(freeze_key),
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // fund the second account and approve the delegate
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::Approve(50);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // not the freeze authority
+        let instruction = Command::Freeze;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, true, &mut token_account),
+            (&owner_key, true, &mut owner_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // authority did not sign
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&freeze_key, false, &mut freeze_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert!(!get_account(&token_account_account.data).is_frozen());
+
+        // freeze
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&freeze_key, true, &mut freeze_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert!(get_account(&token_account_account.data).is_frozen());
+
+        // transfer out
+        let instruction = Command::Transfer(10);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::AccountFrozen.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer in
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::AccountFrozen.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // through a delegate of the frozen account
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::AccountFrozen.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+        assert_eq!(get_account(&delegate_account_account.data).amount, 50);
+
+        // thaw
+        let instruction = Command::Thaw;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&freeze_key, true, &mut freeze_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert!(!get_account(&token_account_account.data).is_frozen());
+
+        // transfers in and out succeed again
+        let instruction = Command::Transfer(10);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 890);
+        assert_eq!(get_account(&token_account2_account.data).amount, 110);
+        assert_eq!(get_account(&delegate_account_account.data).amount, 40);
+    }
+
+    #[test]
+    fn test_token_freeze_authority() {
+        // round trips with and without a freeze authority, clearing a previous key
+        let mut data = vec![0u8; size_of::<State>()];
+        for freeze_authority in [Some(new_pubkey(1)), None].iter() {
+            let token = Token {
+                supply: 1000,
+                decimals: 2,
+                mint_authority: new_pubkey(2),
+                freeze_authority: *freeze_authority,
+                ..Token::default()
+            };
+            State::Token(token).serialize(&mut data).unwrap();
+            assert_eq!(Ok(State::Token(token)), State::deserialize(&data));
+        }
+        let freeze_authority_offset = HEADER_LEN + Token::FREEZE_AUTHORITY_OFFSET;
+        assert_eq!(
+            data[freeze_authority_offset..freeze_authority_offset + 1 + size_of::<Pubkey>()],
+            [0u8; 1 + size_of::<Pubkey>()]
+        );
+
+        // bad presence byte
+        let mut data = vec![0u8; size_of::<State>()];
+        State::Token(Token::default()).serialize(&mut data).unwrap();
+        data[HEADER_LEN + Token::FREEZE_AUTHORITY_OFFSET] = 2;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&data)
+        );
+
+        // a token without a freeze authority can't freeze
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        let instruction = Command::Freeze;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, true, &mut token_account),
+            (&owner_key, true, &mut owner_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert!(!get_account(&token_account_account.data).is_frozen());
+    }
+
+    #[test]
+    fn test_batch_transfer() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account4_key = new_pubkey(5);
+        let mut token_account4_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(6);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(7);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account4_key, true, &mut token_account4_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // create new token
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // one amount short
+        let instruction = Command::BatchTransfer(vec![10, 20]);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // the total exceeds the source's balance
+        let instruction = Command::BatchTransfer(vec![10, 2000, 30]);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+        assert_eq!(get_account(&token_account3_account.data).amount, 0);
+        assert_eq!(get_account(&token_account4_account.data).amount, 0);
+
+        // distribute
+        let instruction = Command::BatchTransfer(vec![10, 20, 30]);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account4_key, false, &mut token_account4_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 940);
+        assert_eq!(get_account(&token_account2_account.data).amount, 10);
+        assert_eq!(get_account(&token_account3_account.data).amount, 20);
+        assert_eq!(get_account(&token_account4_account.data).amount, 30);
+    }
+
+    #[test]
+    fn test_zero_amount() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::Approve(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // zero transfer
+        let instruction = Command::Transfer(0);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // zero approval leaves the allowance in place
+        let instruction = Command::Approve(0);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 100);
+    }
+
+    #[test]
+    fn test_new_token_funded_destination() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // destination already holds tokens
+        State::Account(TokenAccount {
+            amount: 500,
+            ..get_account(&token_account_account.data)
+        })
+        .serialize(&mut token_account_account.data)
+        .unwrap();
+
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 500);
+        assert_eq!(
+            Ok(State::Unallocated),
+            State::deserialize(&token_account.data)
+        );
+    }
+
+    #[test]
+    fn test_delegate_allowance() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(3);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve
+        let instruction = Command::Approve(75);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&delegate_account_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            Some((token_account_key, 75, 75))
+        );
+
+        // not delegates
+        assert_eq!(
+            State::deserialize(&token_account_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            None
+        );
+        assert_eq!(
+            State::deserialize(&token_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_transfer_all() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token accounts, a delegate of the second and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 42,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // whole balance
+        let instruction = Command::Transfer(u64::MAX);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 0);
+        assert_eq!(get_account(&token_account2_account.data).amount, 42);
+
+        // nothing left to move
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // whole remaining allowance
+        let instruction = Command::Approve(12);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::Transfer(u64::MAX);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 0);
+        assert_eq!(get_account(&token_account_account.data).amount, 12);
+        assert_eq!(get_account(&token_account2_account.data).amount, 30);
+    }
+
+    #[test]
+    fn test_new_token_account_idempotent() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let owner2_key = new_pubkey(4);
+        let mut owner2_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(6);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // fresh account
+        let instruction = Command::NewTokenAccountIdempotent;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account_account.data);
+        assert_eq!(account.owner, owner_key);
+        assert_eq!(account.token, token_key);
+
+        // fund it
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // already exists and matches, left untouched
+        let instruction = Command::NewTokenAccountIdempotent;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+
+        // plain NewTokenAccount still refuses it
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // already exists with another owner
+        let instruction = Command::NewTokenAccountIdempotent;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner2_key, false, &mut owner2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // already exists for another token
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // not an account at all
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let account = get_account(&token_account_account.data);
+        assert_eq!(account.owner, owner_key);
+        assert_eq!(account.amount, 1000);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let token2_key = new_pubkey(6);
+        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // token accounts for different tokens
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token2_key, false, &mut token2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // the code the entrypoint hands the runtime is the error's discriminant
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let error = State::process(&program_id, &mut account_infos, &instruction_data).unwrap_err();
+        assert_eq!(error, TokenError::TokenMismatch.into());
+        assert_eq!(u64::from(error), TokenError::TokenMismatch as u64);
+
+        // every code is distinct and none reads as success
+        let mut codes = vec![];
+        while let Some(error) = TokenError::from_u32(codes.len() as u32) {
+            codes.push(u64::from(ProgramError::from(error)));
+        }
+        assert_eq!(codes.len(), TokenError::DelegateExpired as usize + 1);
+        codes.push(u64::from(ProgramError::InvalidArgument));
+        assert!(!codes.contains(&0));
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), TokenError::DelegateExpired as usize + 2);
+    }
+
+    #[test]
+    fn test_transfer_with_memo() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // round trip
+        let mut memo = [0u8; 32];
+        memo[..9].copy_from_slice(b"order-123");
+        let instruction = Command::TransferWithMemo { amount: 100, memo };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        assert_eq!(
+            instruction_data.len(),
+            HEADER_LEN + size_of::<u64>() + memo.len()
+        );
+        assert_eq!(Ok(instruction), Command::deserialize(&instruction_data));
+
+        // create token accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer with memo
+        let instruction = Command::TransferWithMemo { amount: 100, memo };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // fails like Transfer
+        let instruction = Command::TransferWithMemo { amount: 1000, memo };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+    }
+
+    #[test]
+    fn test_mint_to_max_supply() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts and a token capped at 1500
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            max_supply: 1500,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // mint exactly up to the cap
+        let instruction = Command::MintTo(500);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 500);
+
+        // one more unit is rejected and nothing changes
+        let instruction = Command::MintTo(1);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::SupplyCapExceeded.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account2_account.data).amount, 500);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 1500);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+    }
+
+    #[test]
+    fn test_set_owner_zero_key() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let zero_key = Pubkey::default();
+        let mut zero_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create a funded and an empty token account
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // funded account can't be handed to the zero key
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&zero_key, false, &mut zero_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).owner, owner_key);
+
+        // empty account can
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&zero_key, false, &mut zero_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).owner, zero_key);
+    }
+
+    #[test]
+    fn test_transfer_authority() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(10, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let authority_key = new_pubkey(5);
+        let mut authority_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let beneficiary_key = new_pubkey(7);
+        let mut beneficiary_account = Account::default();
+
+        // create token accounts and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // only the owner can set the transfer authority
+        let instruction = Command::SetTransferAuthority;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&authority_key, false, &mut authority_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            get_account(&token_account_account.data).transfer_authority,
+            Some(authority_key)
+        );
+
+        // transfer authority must sign
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, false, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // transfer signed by the transfer authority alone
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+
+        // the owner can still transfer
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 800);
+
+        // transfer authority can't change the owner
+        let instruction = Command::SetOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).owner, owner_key);
+
+        // transfer authority can't close the account, though it can empty it otherwise
+        let instruction = Command::TransferAndCloseIfEmpty { amount: 800 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 800);
+        assert_eq!(token_account_account.lamports, 10);
+        assert_eq!(beneficiary_account.lamports, 0);
+        let instruction = Command::TransferAndCloseIfEmpty { amount: 100 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 700);
+        assert_eq!(token_account_account.lamports, 10);
+
+        // cleared, the former authority can no longer transfer
+        let instruction = Command::SetTransferAuthority;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            get_account(&token_account_account.data).transfer_authority,
+            None
+        );
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&authority_key, true, &mut authority_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::NoOwner.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_init_mint() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // token must sign
+        let instruction = Command::InitMint { decimals: 2 };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&token_key, false, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // zero supply mint
+        let mut accounts = vec![(&token_key, true, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&token_account.data),
+            Ok(State::Token(Token {
+                decimals: 2,
+                mint_authority: token_key,
+                ..Token::default()
+            }))
+        );
+
+        // already allocated
+        let mut accounts = vec![(&token_key, true, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // minting later sets the supply
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::MintTo(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 100);
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert_eq!(token.supply, 100);
+            assert_eq!(token.total_minted, 100);
+            token.check_supply().unwrap();
+        } else {
+            panic!("not a token");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_unaligned() {
+        // decoding copies field by field, so any byte offset into the buffer works
+        let account = TokenAccount {
+            token: new_pubkey(1),
+            owner: new_pubkey(2),
+            amount: 0x0102_0304_0506_0708,
+            delegate: Some(TokenAccountDelegate {
+                source: new_pubkey(3),
+                original_amount: 0x090a_0b0c_0d0e_0f10,
+                expiry_slot: 0x1112_1314_1516_1718,
+            }),
+            transfer_authority: Some(new_pubkey(4)),
+            ..TokenAccount::default()
+        };
+        let token = Token {
+            supply: 1000,
+            decimals: 2,
+            total_minted: 1000,
+            mint_authority: new_pubkey(5),
+            ..Token::default()
+        };
+        let command = Command::Transfer(0x0102_0304_0506_0708);
+        for offset in 1..size_of::<u64>() {
+            let mut data = vec![0u8; offset + State::ACCOUNT_LEN];
+            State::Account(account)
+                .serialize(&mut data[offset..])
+                .unwrap();
+            assert_eq!(
+                State::deserialize(&data[offset..]),
+                Ok(State::Account(account))
+            );
+
+            let mut data = vec![0u8; offset + State::TOKEN_LEN];
+            State::Token(token).serialize(&mut data[offset..]).unwrap();
+            assert_eq!(State::deserialize(&data[offset..]), Ok(State::Token(token)));
+
+            let mut data = vec![0u8; offset + command.serialized_len()];
+            command.serialize(&mut data[offset..]).unwrap();
+            assert_eq!(Command::deserialize(&data[offset..]), Ok(command.clone()));
+        }
+    }
+
+    #[test]
+    fn test_account_state() {
+        for state in [
+            AccountState::Uninitialized,
+            AccountState::Initialized,
+            AccountState::Frozen,
+        ]
+        .iter()
+        {
+            let account = TokenAccount {
+                owner: new_pubkey(1),
+                state: *state,
+                ..TokenAccount::default()
+            };
+            let mut data = vec![0u8; State::ACCOUNT_LEN];
+            State::Account(account).serialize(&mut data).unwrap();
+            assert_eq!(data[HEADER_LEN + TokenAccount::STATE_OFFSET], *state as u8);
+            assert_eq!(State::deserialize(&data), Ok(State::Account(account)));
+        }
+        let mut data = vec![0u8; State::ACCOUNT_LEN];
+        State::Account(TokenAccount::default())
+            .serialize(&mut data)
+            .unwrap();
+        data[HEADER_LEN + TokenAccount::STATE_OFFSET] = 3;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&data)
+        );
+
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // an uninitialized account can still be initialized
+        State::Account(TokenAccount {
+            owner: new_pubkey(5),
+            ..TokenAccount::default()
+        })
+        .serialize(&mut token_account_account.data)
+        .unwrap();
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&token_account_account.data);
+        assert_eq!(account.owner, owner_key);
+        assert_eq!(account.state, AccountState::Initialized);
+
+        // but only once
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_missing_account() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create token account and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // transfer without its destination or token, logged as missing account 2
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::NotEnoughAccountKeys),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+    }
+
+    #[test]
+    fn test_associated_token_account() {
+        let program_id = new_pubkey(1);
+        let owner_key = new_pubkey(2);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(3);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_source_key = new_pubkey(4);
+        let mut delegate_source_account = Account::default();
+        let associated_key = associated_address(&owner_key, &token_key, &program_id);
+        let mut associated_account = Account::new(0, size_of::<State>(), &program_id);
+        let other_key = new_pubkey(5);
+        let mut other_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // derived from the owner and the token
+        let seed = "03010101010101010101010101010101";
+        assert_eq!(
+            Ok(associated_key),
+            Pubkey::create_with_seed(&owner_key, seed, &program_id)
+        );
+        assert_ne!(
+            associated_key,
+            associated_address(&owner_key, &new_pubkey(6), &program_id)
+        );
+        assert_ne!(
+            associated_key,
+            associated_address(&new_pubkey(6), &token_key, &program_id)
+        );
+
+        // mismatched key
+        let instruction = Command::NewAssociatedTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&other_key, true, &mut other_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(
+            State::deserialize(&other_account.data),
+            Ok(State::Unallocated)
+        );
+
+        // owner must sign
+        let mut accounts = vec![
+            (&associated_key, false, &mut associated_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // can't be a delegate
+        let mut accounts = vec![
+            (&associated_key, false, &mut associated_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&delegate_source_key, false, &mut delegate_source_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+
+        // create at the associated address
+        let mut accounts = vec![
+            (&associated_key, false, &mut associated_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let account = get_account(&associated_account.data);
+        assert_eq!(account.token, token_key);
+        assert_eq!(account.owner, owner_key);
+        assert_eq!(account.delegate, None);
+    }
+
+    #[test]
+    fn test_validate_account() {
+        let token = Token {
+            supply: 1000,
+            ..Token::default()
+        };
+        let account = TokenAccount {
+            amount: 1000,
+            ..TokenAccount::default()
+        };
+        State::Account(account).validate_account(&token).unwrap();
+        State::Token(token).validate_account(&token).unwrap();
+
+        // balance exceeding the supply
+        let account = TokenAccount {
+            amount: 1001,
+            ..account
+        };
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::Account(account).validate_account(&token)
+        );
+
+        // an allowance may exceed it
+        let account = TokenAccount {
+            delegate: Some(TokenAccountDelegate::default()),
+            ..account
+        };
+        State::Account(account).validate_account(&token).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "strict")]
+    fn test_strict_validation() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // a token whose supply understates the balance of one of its accounts
+        State::Token(Token {
+            supply: 1000,
+            total_minted: 1000,
+            ..Token::default()
+        })
+        .serialize(&mut token_account.data)
+        .unwrap();
+        for (data, amount) in [
+            (&mut token_account_account.data, 2000),
+            (&mut token_account2_account.data, 0),
+        ]
+        .iter_mut()
+        {
+            State::Account(TokenAccount {
+                token: token_key,
+                owner: owner_key,
+                amount: *amount,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            })
+            .serialize(data)
+            .unwrap();
+        }
+
+        // the transfer itself succeeds, but the result fails validation
+        let instruction = Command::Transfer(100);
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+    }
+
+    #[test]
+    fn test_approve_and_transfer() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let delegate_account_key = new_pubkey(4);
+        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // approve 100 and spend 40 of it
+        let instruction = Command::ApproveAndTransfer {
+            approve: 100,
+            transfer: 40,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            State::deserialize(&delegate_account_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            Some((token_account_key, 60, 100))
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 960);
+        assert_eq!(get_account(&token_account2_account.data).amount, 40);
+
+        // a transfer beyond the new allowance fails, the runtime discards the new allowance
+        let instruction = Command::ApproveAndTransfer {
+            approve: 50,
+            transfer: 80,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let saved_delegate = delegate_account_account.clone();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::InsufficientFunds.into()),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 960);
+        assert_eq!(get_account(&token_account2_account.data).amount, 40);
+        delegate_account_account = saved_delegate;
+
+        // owner must sign
+        let instruction = Command::ApproveAndTransfer {
+            approve: 100,
+            transfer: 40,
+        };
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, false, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::MissingRequiredSignature),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
     }
 
     #[test]
-    fn test_new_token() {
+    fn test_pause() {
         let program_id = new_pubkey(1);
-        let mut instruction_data = vec![0u8; size_of::<Command>()];
         let token_account_key = new_pubkey(2);
         let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
         let token_account2_key = new_pubkey(3);
@@ -509,28 +13027,178 @@ mod tests {
         let mut owner_account = Account::default();
         let token_key = new_pubkey(6);
         let mut token_account = Account::new(0, size_of::<State>(), &program_id);
-        let token2_key = new_pubkey(7);
-        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
 
-        // token account not created
+        // create accounts, delegate and token
+        let instruction = Command::NewTokenAccount;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&delegate_account_key, true, &mut delegate_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         let instruction = Command::NewToken(Token {
             supply: 1000,
             decimals: 2,
+            ..Token::default()
         });
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // only the mint authority can pause
+        let instruction_data = Command::SetPause(true).serialize_to_vec().unwrap();
+        let mut accounts = vec![(&token_key, false, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(ProgramError::InvalidArgument),
+            Err(ProgramError::MissingRequiredSignature),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+        let mut accounts = vec![(&token_key, true, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        if let State::Token(token) = State::deserialize(&token_account.data).unwrap() {
+            assert!(token.paused);
+        } else {
+            panic!("not a token");
+        }
 
-        // create token account
+        // transfers and approvals fail while paused
+        let transfer_data = Command::Transfer(100).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::MintPaused.into()),
+            State::process(&program_id, &mut account_infos, &transfer_data)
+        );
+        let approve_data = Command::Approve(100).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::MintPaused.into()),
+            State::process(&program_id, &mut account_infos, &approve_data)
+        );
+        let approve_data = Command::ApproveIfUnused(100).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::MintPaused.into()),
+            State::process(&program_id, &mut account_infos, &approve_data)
+        );
+        let approve_data = Command::ApproveWithExpiry {
+            amount: 100,
+            expiry_slot: 20,
+        }
+        .serialize_to_vec()
+        .unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(TokenError::MintPaused.into()),
+            State::process(&program_id, &mut account_infos, &approve_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // unpause and both succeed again
+        let instruction_data = Command::SetPause(false).serialize_to_vec().unwrap();
+        let mut accounts = vec![(&token_key, true, &mut token_account)];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &transfer_data).unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &approve_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
+        assert_eq!(
+            State::deserialize(&delegate_account_account.data)
+                .unwrap()
+                .delegate_allowance(),
+            Some((token_account_key, 100, 100))
+        );
+
+        // bad paused byte
+        let mut data = vec![0u8; size_of::<State>()];
+        State::Token(Token::default()).serialize(&mut data).unwrap();
+        data[HEADER_LEN + Token::PAUSED_OFFSET] = 2;
+        assert_eq!(
+            Err(ProgramError::InvalidAccountData),
+            State::deserialize(&data)
+        );
+    }
+
+    #[test]
+    fn test_transfer_return_data() {
+        // off-chain the return data is dropped
+        set_return_data(&[]);
+        set_return_data(&[1; 16]);
+
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(5);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts and token
         let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&token_account_key, true, &mut token_account_account),
             (&owner_key, false, &mut owner_account),
@@ -538,13 +13206,19 @@ mod tests {
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create new token
+        let mut accounts = vec![
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         let instruction = Command::NewToken(Token {
             supply: 1000,
             decimals: 2,
+            ..Token::default()
         });
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
@@ -552,149 +13226,380 @@ mod tests {
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
 
-        // create another token account
+        // transfer goes through the stub
+        let instruction_data = Command::Transfer(100).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // the data it was given holds both new balances
+        let mut accounts = vec![
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let account_infos = create_is_signer_account_infos(&mut accounts);
+        let data = State::transfer_return_data(&account_infos[0], &account_infos[1]).unwrap();
+        assert_eq!(data[..8], 900u64.to_le_bytes());
+        assert_eq!(data[8..], 100u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        let token = Token {
+            transfer_fee_basis_points: 100,
+            ..Token::default()
+        };
+        assert_eq!(token.transfer_fee(10_000), Ok(100));
+        assert_eq!(token.transfer_fee(99), Ok(0));
+        let token = Token {
+            rounding: RoundingMode::Up as u8,
+            ..token
+        };
+        assert_eq!(token.transfer_fee(99), Ok(1));
+        let token = Token {
+            transfer_fee_basis_points: Token::MAX_TRANSFER_FEE_BASIS_POINTS + 1,
+            ..token
+        };
+        assert_eq!(token.transfer_fee(1), Err(ProgramError::InvalidArgument));
+
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let collector_account_key = new_pubkey(4);
+        let mut collector_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts and token
         let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        for (key, account) in [
+            (&token_account_key, &mut token_account_account),
+            (&token_account2_key, &mut token_account2_account),
+            (&collector_account_key, &mut collector_account_account),
+        ]
+        .iter_mut()
+        {
+            let mut accounts = vec![
+                (*key, true, &mut **account),
+                (&owner_key, false, &mut owner_account),
+                (&token_key, false, &mut token_account),
+            ];
+            let mut account_infos = create_is_signer_account_infos(&mut accounts);
+            State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        }
+        for (transfer_fee_basis_points, expected) in [
+            (
+                Token::MAX_TRANSFER_FEE_BASIS_POINTS + 1,
+                Err(ProgramError::InvalidArgument),
+            ),
+            (100, Ok(())),
+        ]
+        .iter()
+        {
+            let instruction = Command::NewToken(Token {
+                supply: 20_000,
+                decimals: 2,
+                transfer_fee_basis_points: *transfer_fee_basis_points,
+                fee_collector: collector_account_key,
+                ..Token::default()
+            });
+            let instruction_data = instruction.serialize_to_vec().unwrap();
+            let mut accounts = vec![
+                (&token_key, true, &mut token_account),
+                (&token_account_key, false, &mut token_account_account),
+            ];
+            let mut account_infos = create_is_signer_account_infos(&mut accounts);
+            assert_eq!(
+                *expected,
+                State::process(&program_id, &mut account_infos, &instruction_data)
+            );
+        }
+
+        // missing or wrong fee collector
+        let instruction_data = Command::Transfer(10_000).serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token_account2_key, true, &mut token_account2_account),
-            (&owner_key, false, &mut owner_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (
+                &collector_account_key,
+                false,
+                &mut collector_account_account,
+            ),
+            (&token_key, false, &mut token_account),
+            (&token_account2_key, false, &mut token_account2_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 20_000);
+
+        // 1% of 10000 goes to the collector
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
             (&token_key, false, &mut token_account),
+            (
+                &collector_account_key,
+                false,
+                &mut collector_account_account,
+            ),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 10_000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 9_900);
+        assert_eq!(get_account(&collector_account_account.data).amount, 100);
 
-        // token mismatch
-        let instruction = Command::NewToken(Token {
-            supply: 1000,
-            decimals: 2,
-        });
-        instruction.serialize(&mut instruction_data).unwrap();
+        // the collector pays the fee to itself
+        let instruction_data = Command::Transfer(1_000).serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token2_key, true, &mut token2_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (
+                &collector_account_key,
+                false,
+                &mut collector_account_account,
+            ),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let collector_account_info = account_infos[2].clone();
+        account_infos.push(collector_account_info);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 9_000);
+        assert_eq!(get_account(&collector_account_account.data).amount, 1_100);
+
+        // commands without a fee collector key refuse the token
+        let instruction_data = Command::BatchTransfer(vec![100])
+            .serialize_to_vec()
+            .unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
             (&token_account2_key, false, &mut token_account2_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::TokenMismatch.into()),
+            Err(ProgramError::InvalidArgument),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+    }
 
-        // create delegate account
+    #[test]
+    fn test_immutable_owner() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
+        let mut owner_account = Account::default();
+        let new_owner_key = new_pubkey(5);
+        let mut new_owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // an immutable-owner source and an ordinary destination
+        let instruction = Command::NewTokenAccountWithImmutableOwner;
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert!(get_account(&token_account_account.data).owner_immutable);
         let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&delegate_account_key, true, &mut delegate_account_account),
+            (&token_account2_key, true, &mut token_account2_account),
             (&owner_key, false, &mut owner_account),
             (&token_key, false, &mut token_account),
-            (&token_account_key, false, &mut token_account_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // account is a delegate token
+        assert!(!get_account(&token_account2_account.data).owner_immutable);
         let instruction = Command::NewToken(Token {
             supply: 1000,
             decimals: 2,
+            ..Token::default()
         });
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // neither SetOwner nor RotateOwner can change the owner
+        for instruction in [Command::SetOwner, Command::RotateOwner].iter() {
+            let instruction_data = instruction.serialize_to_vec().unwrap();
+            let mut accounts = vec![
+                (&owner_key, true, &mut owner_account),
+                (&token_account_key, false, &mut token_account_account),
+                (&new_owner_key, false, &mut new_owner_account),
+            ];
+            let mut account_infos = create_is_signer_account_infos(&mut accounts);
+            assert_eq!(
+                Err(TokenError::OwnerImmutable.into()),
+                State::process(&program_id, &mut account_infos, &instruction_data)
+            );
+            assert_eq!(get_account(&token_account_account.data).owner, owner_key);
+        }
+
+        // an ordinary account still can
+        let instruction_data = Command::SetOwner.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token_key, true, &mut token_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&new_owner_key, false, &mut new_owner_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         assert_eq!(
-            Err(ProgramError::InvalidArgument),
-            State::process(&program_id, &mut account_infos, &instruction_data)
+            get_account(&token_account2_account.data).owner,
+            new_owner_key
         );
 
-        // create twice
+        // transfers out are unaffected
+        let instruction_data = Command::Transfer(100).serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token_key, true, &mut token_account),
+            (&owner_key, true, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(ProgramError::InvalidArgument),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 900);
+        assert_eq!(get_account(&token_account2_account.data).amount, 100);
     }
 
     #[test]
-    fn test_new_token_account() {
+    fn test_non_transferable() {
         let program_id = new_pubkey(1);
-        let mut instruction_data = vec![0u8; size_of::<Command>()];
         let token_account_key = new_pubkey(2);
         let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
-        let owner_key = new_pubkey(3);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(4);
         let mut owner_account = Account::default();
-        let token_key = new_pubkey(4);
+        let token_key = new_pubkey(5);
         let mut token_account = Account::new(0, size_of::<State>(), &program_id);
 
-        // missing signer
+        // create accounts and a non-transferable token minted to the first
         let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = instruction.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token_account_key, false, &mut token_account_account),
+            (&token_account_key, true, &mut token_account_account),
             (&owner_key, false, &mut owner_account),
             (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(ProgramError::MissingRequiredSignature),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
-
-        // create token account
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         let mut accounts = vec![
-            (&token_account_key, true, &mut token_account_account),
+            (&token_account2_key, true, &mut token_account2_account),
             (&owner_key, false, &mut owner_account),
             (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction = Command::NewToken(Token {
+            supply: 1,
+            decimals: 0,
+            non_transferable: true,
+            ..Token::default()
+        });
+        let instruction_data = instruction.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
 
-        // create twice
+        // the balance can't move
+        let instruction_data = Command::Transfer(1).serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&token_account_key, true, &mut token_account_account),
-            (&owner_key, false, &mut owner_account),
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
             (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(ProgramError::InvalidArgument),
+            Err(TokenError::NonTransferable.into()),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+
+        // but is readable
+        assert_eq!(
+            State::deserialize(&token_account_account.data)
+                .unwrap()
+                .amount(),
+            Some(1)
+        );
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+
+        // and can be burned
+        let instruction_data = Command::Burn(1).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&owner_key, true, &mut owner_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).amount, 0);
     }
 
     #[test]
-    fn test_transfer() {
+    fn test_delegate_expiry() {
         let program_id = new_pubkey(1);
-        let mut instruction_data = vec![0u8; size_of::<Command>()];
         let token_account_key = new_pubkey(2);
         let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
         let token_account2_key = new_pubkey(3);
         let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
-        let token_account3_key = new_pubkey(3);
-        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
         let delegate_account_key = new_pubkey(4);
         let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
-        let mismatch_account_key = new_pubkey(5);
-        let mut mismatch_account_account = Account::new(0, size_of::<State>(), &program_id);
-        let mismatch_delegate_account_key = new_pubkey(5);
-        let mut mismatch_delegate_account_account =
-            Account::new(0, size_of::<State>(), &program_id);
-        let owner_key = new_pubkey(6);
+        let owner_key = new_pubkey(5);
         let mut owner_account = Account::default();
-        let owner2_key = new_pubkey(7);
-        let mut owner2_account = Account::default();
-        let token_key = new_pubkey(8);
+        let token_key = new_pubkey(6);
         let mut token_account = Account::new(0, size_of::<State>(), &program_id);
-        let token2_key = new_pubkey(9);
-        let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+        let clock_key = sysvar::clock::id();
+        let mut clock_account = Account::new(0, size_of::<u64>(), &program_id);
+        let not_clock_key = new_pubkey(7);
+        let mut not_clock_account = Account::new(0, size_of::<u64>(), &program_id);
 
-        // create token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        // create accounts, delegate and token
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&token_account_key, true, &mut token_account_account),
             (&owner_key, false, &mut owner_account),
@@ -702,10 +13607,6 @@ mod tests {
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create another token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
         let mut accounts = vec![
             (&token_account2_key, true, &mut token_account2_account),
             (&owner_key, false, &mut owner_account),
@@ -713,32 +13614,6 @@ mod tests {
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create another token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&token_account3_key, true, &mut token_account3_account),
-            (&owner_key, false, &mut owner_account),
-            (&token_key, false, &mut token_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create mismatch token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&mismatch_account_key, true, &mut mismatch_account_account),
-            (&owner_key, false, &mut owner_account),
-            (&token2_key, false, &mut token2_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create delegate account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
         let mut accounts = vec![
             (&delegate_account_key, true, &mut delegate_account_account),
             (&owner_key, false, &mut owner_account),
@@ -747,29 +13622,13 @@ mod tests {
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create mismatch delegate account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (
-                &mismatch_delegate_account_key,
-                true,
-                &mut mismatch_delegate_account_account,
-            ),
-            (&owner_key, false, &mut owner_account),
-            (&token2_key, false, &mut token2_account),
-            (&token_account_key, false, &mut token_account_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create new token
-        let instruction = Command::NewToken(Token {
+        let instruction_data = Command::NewToken(Token {
             supply: 1000,
             decimals: 2,
-        });
-        instruction.serialize(&mut instruction_data).unwrap();
+            ..Token::default()
+        })
+        .serialize_to_vec()
+        .unwrap();
         let mut accounts = vec![
             (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
@@ -777,239 +13636,302 @@ mod tests {
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
 
-        // missing signer
-        let instruction = Command::Transfer(1000);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // approve 100 until slot 20
+        let instruction_data = Command::ApproveWithExpiry {
+            amount: 100,
+            expiry_slot: 20,
+        }
+        .serialize_to_vec()
+        .unwrap();
         let mut accounts = vec![
-            (&owner_key, false, &mut owner_account),
+            (&owner_key, true, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         assert_eq!(
-            Err(ProgramError::MissingRequiredSignature),
-            State::process(&program_id, &mut account_infos, &instruction_data)
+            get_account(&delegate_account_account.data)
+                .delegate
+                .unwrap()
+                .expiry_slot,
+            20
         );
 
-        // destination is delegate
-        let instruction = Command::Transfer(1000);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // spending at slot 20 succeeds, the expiry slot itself is still valid
+        clock_account.data.copy_from_slice(&20u64.to_le_bytes());
+        let instruction_data = Command::Transfer(40).serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
             (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&clock_key, false, &mut clock_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(ProgramError::InvalidArgument),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 60);
+        assert_eq!(get_account(&token_account_account.data).amount, 960);
+        assert_eq!(get_account(&token_account2_account.data).amount, 40);
 
-        // mismatch token
-        let instruction = Command::Transfer(1000);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // past the expiry slot nothing moves
+        clock_account.data.copy_from_slice(&21u64.to_le_bytes());
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
             (&token_account2_key, false, &mut token_account2_account),
-            (&mismatch_account_key, false, &mut mismatch_account_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&clock_key, false, &mut clock_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::TokenMismatch.into()),
+            Err(TokenError::DelegateExpired.into()),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+        assert_eq!(get_account(&delegate_account_account.data).amount, 60);
+        assert_eq!(get_account(&token_account_account.data).amount, 960);
+        assert_eq!(get_account(&token_account2_account.data).amount, 40);
 
-        // missing owner
-        let instruction = Command::Transfer(1000);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // the clock must be the clock sysvar
         let mut accounts = vec![
-            (&owner2_key, true, &mut owner2_account),
-            (&token_account_key, false, &mut token_account_account),
+            (&owner_key, true, &mut owner_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
             (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&not_clock_key, false, &mut not_clock_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::NoOwner.into()),
+            Err(ProgramError::InvalidArgument),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
 
-        // transfer
-        let instruction = Command::Transfer(1000);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // a plain approval never expires and needs no clock
+        let instruction_data = Command::Approve(100).serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // insufficient funds
-        let instruction = Command::Transfer(1);
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = Command::Transfer(40).serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
+            (&delegate_account_key, false, &mut delegate_account_account),
             (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&delegate_account_account.data).amount, 60);
+        assert_eq!(get_account(&token_account2_account.data).amount, 80);
+    }
+
+    #[test]
+    fn test_newaccount_undersized() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, State::ACCOUNT_LEN - 1, &program_id);
+        let owner_key = new_pubkey(3);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(4);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // one byte short fails before anything is written
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::InsufficientFunds.into()),
+            Err(ProgramError::AccountDataTooSmall),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+        assert_eq!(
+            token_account_account.data,
+            vec![0u8; State::ACCOUNT_LEN - 1]
+        );
 
-        // transfer half back
-        let instruction = Command::Transfer(500);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // exactly `ACCOUNT_LEN` is enough
+        let mut token_account_account = Account::new(0, State::ACCOUNT_LEN, &program_id);
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&token_account_key, false, &mut token_account_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account_account.data).owner, owner_key);
+    }
 
-        // transfer rest
-        let instruction = Command::Transfer(500);
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&token_account_key, false, &mut token_account_account),
-        ];
+    #[test]
+    fn test_get_account_info() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_key = new_pubkey(3);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+
+        State::Account(TokenAccount {
+            token: token_key,
+            owner: new_pubkey(4),
+            amount: 123,
+            ..TokenAccount::default()
+        })
+        .serialize(&mut token_account_account.data)
+        .unwrap();
+        let original = token_account_account.data.clone();
+
+        // logs without writing
+        let instruction_data = Command::GetAccountInfo.serialize_to_vec().unwrap();
+        let mut accounts = vec![(&token_account_key, false, &mut token_account_account)];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(token_account_account.data, original);
 
-        // insufficient funds
-        let instruction = Command::Transfer(1);
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&token_account_key, false, &mut token_account_account),
-        ];
+        // not an account
+        State::Token(Token::default())
+            .serialize(&mut token_account.data)
+            .unwrap();
+        let mut accounts = vec![(&token_key, false, &mut token_account)];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::InsufficientFunds.into()),
+            Err(TokenError::NotAnAccount.into()),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+    }
 
-        // approve delegate
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+    #[test]
+    fn test_close_and_transfer() {
+        let program_id = new_pubkey(1);
+        let token_account_key = new_pubkey(2);
+        let mut token_account_account = Account::new(10, size_of::<State>(), &program_id);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(10, size_of::<State>(), &program_id);
+        let beneficiary_key = new_pubkey(4);
+        let mut beneficiary_account = Account::new(3, 0, &program_id);
+        let owner_key = new_pubkey(5);
+        let mut owner_account = Account::default();
+        let token_key = new_pubkey(6);
+        let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let unallocated_key = new_pubkey(7);
+        let mut unallocated_account = Account::new(0, size_of::<State>(), &program_id);
+
+        // create accounts and token
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_account_key, true, &mut token_account_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // not a delegate of source account
-        let instruction = Command::Transfer(100);
-        instruction.serialize(&mut instruction_data).unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&token_account3_key, false, &mut token_account3_account),
+            (&token_account2_key, true, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(TokenError::NotDelegate.into()),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
-
-        // transfer via delegate
-        let instruction = Command::Transfer(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction_data = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        })
+        .serialize_to_vec()
+        .unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
 
-        // insufficient funds approved via delegate
-        let instruction = Command::Transfer(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // owner must sign, nothing moves or closes
+        let instruction_data = Command::CloseAndTransfer.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
+            (&owner_key, false, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::InsufficientFunds.into()),
+            Err(ProgramError::MissingRequiredSignature),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(get_account(&token_account2_account.data).amount, 0);
+        assert_eq!(token_account_account.lamports, 10);
 
-        // transfer rest
-        let instruction = Command::Transfer(900);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // a failed transfer, here to an unallocated destination, leaves the source open
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
-            (&token_account2_key, false, &mut token_account2_account),
+            (&unallocated_key, false, &mut unallocated_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(
+            Err(ProgramError::InvalidArgument),
+            State::process(&program_id, &mut account_infos, &instruction_data)
+        );
+        assert_eq!(get_account(&token_account_account.data).amount, 1000);
+        assert_eq!(token_account_account.lamports, 10);
+        assert_eq!(beneficiary_account.lamports, 3);
 
-        // approve delegate
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // the whole balance moves and the source is closed
         let mut accounts = vec![
             (&owner_key, true, &mut owner_account),
             (&token_account_key, false, &mut token_account_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // insufficient funds in source account via delegate
-        let instruction = Command::Transfer(100);
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
             (&token_account2_key, false, &mut token_account2_account),
-            (&token_account_key, false, &mut token_account_account),
+            (&token_key, false, &mut token_account),
+            (&beneficiary_key, false, &mut beneficiary_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(get_account(&token_account2_account.data).amount, 1000);
         assert_eq!(
-            Err(TokenError::InsufficientFunds.into()),
-            State::process(&program_id, &mut account_infos, &instruction_data)
+            State::deserialize(&token_account_account.data).unwrap(),
+            State::Unallocated
         );
+        assert!(token_account_account.data.iter().all(|byte| *byte == 0));
+        assert_eq!(token_account_account.lamports, 0);
+        assert_eq!(beneficiary_account.lamports, 13);
     }
 
     #[test]
-    fn test_approve() {
+    fn test_get_authorities() {
         let program_id = new_pubkey(1);
-        let mut instruction_data = vec![0u8; size_of::<Command>()];
         let token_account_key = new_pubkey(2);
         let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
         let token_account2_key = new_pubkey(3);
         let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
-        let delegate_account_key = new_pubkey(4);
-        let mut delegate_account_account = Account::new(0, size_of::<State>(), &program_id);
-        let mismatch_delegate_account_key = new_pubkey(5);
-        let mut mismatch_delegate_account_account =
-            Account::new(0, size_of::<State>(), &program_id);
-        let owner_key = new_pubkey(6);
+        let owner_key = new_pubkey(4);
         let mut owner_account = Account::default();
-        let owner2_key = new_pubkey(7);
-        let mut owner2_account = Account::default();
-        let token_key = new_pubkey(8);
+        let token_key = new_pubkey(5);
         let mut token_account = Account::new(0, size_of::<State>(), &program_id);
-        let token2_key = new_pubkey(9);
+        let token2_key = new_pubkey(6);
         let mut token2_account = Account::new(0, size_of::<State>(), &program_id);
+        let mint_authority_key = new_pubkey(7);
+        let mut mint_authority_account = Account::default();
+        let freeze_key = new_pubkey(8);
+        let result_key = new_pubkey(9);
+        let mut result_account = Account::new(0, 65, &program_id);
 
-        // create token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        // a token with both authorities, and one with neither set
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
         let mut accounts = vec![
             (&token_account_key, true, &mut token_account_account),
             (&owner_key, false, &mut owner_account),
@@ -1017,112 +13939,70 @@ mod tests {
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create another token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
         let mut accounts = vec![
             (&token_account2_key, true, &mut token_account2_account),
             (&owner_key, false, &mut owner_account),
-            (&token_key, false, &mut token_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create delegate account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&delegate_account_key, true, &mut delegate_account_account),
-            (&owner_key, false, &mut owner_account),
-            (&token_key, false, &mut token_account),
-            (&token_account_key, false, &mut token_account_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create mismatch delegate account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (
-                &mismatch_delegate_account_key,
-                true,
-                &mut mismatch_delegate_account_account,
-            ),
-            (&owner_key, false, &mut owner_account),
             (&token2_key, false, &mut token2_account),
-            (&token_account_key, false, &mut token_account_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // create new token
-        let instruction = Command::NewToken(Token {
+        let instruction_data = Command::NewToken(Token {
             supply: 1000,
             decimals: 2,
-        });
-        instruction.serialize(&mut instruction_data).unwrap();
+            freeze_authority: Some(freeze_key),
+            ..Token::default()
+        })
+        .serialize_to_vec()
+        .unwrap();
         let mut accounts = vec![
             (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
+            (&mint_authority_key, true, &mut mint_authority_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // token mismatch
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        let instruction_data = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            ..Token::default()
+        })
+        .serialize_to_vec()
+        .unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
-            (
-                &mismatch_delegate_account_key,
-                false,
-                &mut mismatch_delegate_account_account,
-            ),
+            (&token2_key, true, &mut token2_account),
+            (&token_account2_key, false, &mut token_account2_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(TokenError::TokenMismatch.into()),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
 
-        // missing signer
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // both authorities set
+        let instruction_data = Command::GetAuthorities.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&owner_key, false, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut result_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(ProgramError::MissingRequiredSignature),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[..32], *mint_authority_key.as_ref());
+        assert_eq!(result_account.data[32], 1);
+        assert_eq!(result_account.data[33..], *freeze_key.as_ref());
 
-        // missing signer
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // no freeze authority, which overwrites the last result with zeros, and the token is
+        // its own mint authority
         let mut accounts = vec![
-            (&owner2_key, true, &mut owner2_account),
-            (&token_account_key, false, &mut token_account_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token2_key, false, &mut token2_account),
+            (&result_key, false, &mut result_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(TokenError::NoOwner.into()),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(result_account.data[..32], *token2_key.as_ref());
+        assert_eq!(result_account.data[32], 0);
+        assert!(result_account.data[33..].iter().all(|byte| *byte == 0));
 
-        // destination is delegate
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // not a token
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
             (&token_account_key, false, &mut token_account_account),
+            (&result_key, false, &mut result_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
@@ -1130,119 +14010,149 @@ mod tests {
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
 
-        // not a delegate
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&token_account_key, false, &mut token_account_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(TokenError::NotDelegate.into()),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
-
-        // not a delegate of source
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
+        // result account too small
+        let mut small_result_account = Account::new(0, 64, &program_id);
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account2_key, false, &mut token_account2_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
+            (&token_key, false, &mut token_account),
+            (&result_key, false, &mut small_result_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::NotDelegate.into()),
+            Err(ProgramError::InvalidAccountData),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
-
-        // approve delegate
-        let instruction = Command::Approve(100);
-        instruction.serialize(&mut instruction_data).unwrap();
-        let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
-            (&delegate_account_key, false, &mut delegate_account_account),
-        ];
-        let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
     }
 
     #[test]
-    fn test_set_owner() {
+    fn test_freeze_owner() {
         let program_id = new_pubkey(1);
-        let mut instruction_data = vec![0u8; size_of::<Command>()];
         let token_account_key = new_pubkey(2);
         let mut token_account_account = Account::new(0, size_of::<State>(), &program_id);
-        let owner_key = new_pubkey(3);
+        let token_account2_key = new_pubkey(3);
+        let mut token_account2_account = Account::new(0, size_of::<State>(), &program_id);
+        let token_account3_key = new_pubkey(4);
+        let mut token_account3_account = Account::new(0, size_of::<State>(), &program_id);
+        let owner_key = new_pubkey(5);
         let mut owner_account = Account::default();
-        let owner2_key = new_pubkey(4);
+        let owner2_key = new_pubkey(6);
         let mut owner2_account = Account::default();
-        let owner3_key = new_pubkey(5);
-        let mut owner3_account = Account::default();
-        let token_key = new_pubkey(6);
+        let token_key = new_pubkey(7);
         let mut token_account = Account::new(0, size_of::<State>(), &program_id);
+        let freeze_key = new_pubkey(8);
+        let mut freeze_account = Account::default();
+        let result_key = new_pubkey(9);
+        let mut result_account = Account::new(0, size_of::<u64>(), &program_id);
 
-        // invalid token account
-        let instruction = Command::SetOwner;
-        instruction.serialize(&mut instruction_data).unwrap();
+        // two accounts of the owner and one of another owner
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
         let mut accounts = vec![
+            (&token_account_key, true, &mut token_account_account),
             (&owner_key, false, &mut owner_account),
-            (&token_account_key, false, &mut token_account_account),
-            (&owner2_key, false, &mut owner2_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
-        assert_eq!(
-            Err(ProgramError::InvalidArgument),
-            State::process(&program_id, &mut account_infos, &instruction_data)
-        );
-
-        // create token account
-        let instruction = Command::NewTokenAccount;
-        instruction.serialize(&mut instruction_data).unwrap();
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
         let mut accounts = vec![
-            (&token_account_key, true, &mut token_account_account),
+            (&token_account2_key, true, &mut token_account2_account),
             (&owner_key, false, &mut owner_account),
             (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
-
-        // missing owner
-        let instruction = Command::SetOwner;
-        instruction.serialize(&mut instruction_data).unwrap();
         let mut accounts = vec![
+            (&token_account3_key, true, &mut token_account3_account),
             (&owner2_key, false, &mut owner2_account),
+            (&token_key, false, &mut token_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction_data = Command::NewToken(Token {
+            supply: 1000,
+            decimals: 2,
+            freeze_authority: Some(freeze_key),
+            ..Token::default()
+        })
+        .serialize_to_vec()
+        .unwrap();
+        let mut accounts = vec![
+            (&token_key, true, &mut token_account),
             (&token_account_key, false, &mut token_account_account),
-            (&owner3_key, false, &mut owner3_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+
+        // freeze authority didn't sign
+        let instruction_data = Command::FreezeOwner(owner_key).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&freeze_key, false, &mut freeze_account),
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         assert_eq!(
-            Err(TokenError::NoOwner.into()),
+            Err(ProgramError::MissingRequiredSignature),
             State::process(&program_id, &mut account_infos, &instruction_data)
         );
+        assert_eq!(
+            get_account(&token_account_account.data).state,
+            AccountState::Initialized
+        );
 
-        // owner did not sign
+        // freezes the owner's two accounts and skips the third
         let mut accounts = vec![
-            (&owner_key, false, &mut owner_account),
-            (&token_account_key, true, &mut token_account_account),
-            (&owner2_key, false, &mut owner2_account),
+            (&token_key, false, &mut token_account),
+            (&freeze_key, true, &mut freeze_account),
+            (&result_key, false, &mut result_account),
+            (&token_account_key, false, &mut token_account_account),
+            (&token_account2_key, false, &mut token_account2_account),
+            (&token_account3_key, false, &mut token_account3_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(read_u64(&result_account.data, 0), Ok(2));
         assert_eq!(
-            Err(ProgramError::MissingRequiredSignature),
-            State::process(&program_id, &mut account_infos, &instruction_data)
+            get_account(&token_account_account.data).state,
+            AccountState::Frozen
+        );
+        assert_eq!(
+            get_account(&token_account2_account.data).state,
+            AccountState::Frozen
+        );
+        assert_eq!(
+            get_account(&token_account3_account.data).state,
+            AccountState::Initialized
         );
 
-        // set owner
+        // an account passed twice is counted once, an already frozen one not at all
+        let token_account4_key = new_pubkey(10);
+        let mut token_account4_account = Account::new(0, size_of::<State>(), &program_id);
+        let instruction_data = Command::NewTokenAccount.serialize_to_vec().unwrap();
         let mut accounts = vec![
-            (&owner_key, true, &mut owner_account),
-            (&token_account_key, true, &mut token_account_account),
-            (&owner2_key, false, &mut owner2_account),
+            (&token_account4_key, true, &mut token_account4_account),
+            (&owner_key, false, &mut owner_account),
+            (&token_key, false, &mut token_account),
         ];
         let mut account_infos = create_is_signer_account_infos(&mut accounts);
         State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        let instruction_data = Command::FreezeOwner(owner_key).serialize_to_vec().unwrap();
+        let mut accounts = vec![
+            (&token_key, false, &mut token_account),
+            (&freeze_key, true, &mut freeze_account),
+            (&result_key, false, &mut result_account),
+            (&token_account4_key, false, &mut token_account4_account),
+            (&token_account_key, false, &mut token_account_account),
+        ];
+        let mut account_infos = create_is_signer_account_infos(&mut accounts);
+        let token_account4_info = account_infos[3].clone();
+        account_infos.push(token_account4_info);
+        State::process(&program_id, &mut account_infos, &instruction_data).unwrap();
+        assert_eq!(read_u64(&result_account.data, 0), Ok(1));
+        assert_eq!(
+            get_account(&token_account4_account.data).state,
+            AccountState::Frozen
+        );
     }
 }